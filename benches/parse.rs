@@ -0,0 +1,67 @@
+// Compares the deb822 tokenizer with the line-by-line regex approach that parse_packages used to take.
+// Both sides extract Package, Version, MD5sum and the Depends alternatives from data/installed-packages.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use regex::Regex;
+
+use rpkg::deb822;
+
+const KEYVAL_REGEX : &str = r"(?P<key>(\w|-)+): (?P<value>.+)";
+const PKGNAME_AND_VERSION_REGEX : &str = r"(?P<pkg>(\w|\.|\+|-)+)( \((?P<op>(<|=|>)(<|=|>)?) (?P<ver>.*)\))?";
+
+fn parse_with_regex(text: &str, kv_regexp: &Regex, pkgver_regexp: &Regex) -> usize {
+    let mut count = 0;
+    for line in text.lines() {
+        if let Some(caps) = kv_regexp.captures(line) {
+            let (key, value) = (caps.name("key").unwrap().as_str(), caps.name("value").unwrap().as_str());
+            match key {
+                "Package" | "Version" | "MD5sum" => { black_box(value.to_string()); count += 1; }
+                "Depends" => {
+                    for dep in value.split(',') {
+                        for alt in dep.split('|') {
+                            if let Some(caps) = pkgver_regexp.captures(alt) {
+                                black_box(caps.name("pkg").unwrap().as_str().to_string());
+                                black_box(caps.name("ver").map(|v| v.as_str().to_string()));
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+    count
+}
+
+fn parse_with_deb822(text: &str) -> usize {
+    let mut count = 0;
+    for para in deb822::paragraphs(text) {
+        for field in para.fields() {
+            match field.name {
+                "Package" | "Version" | "MD5sum" => { black_box(field.value); count += 1; }
+                "Depends" => {
+                    for alts in deb822::parse_relations(field.value) {
+                        count += black_box(alts).len();
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+    count
+}
+
+fn bench_parsers(c: &mut Criterion) {
+    let text = std::fs::read_to_string("data/installed-packages").unwrap();
+    let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
+    let pkgver_regexp = Regex::new(PKGNAME_AND_VERSION_REGEX).unwrap();
+
+    let mut group = c.benchmark_group("installed-packages");
+    group.sample_size(20);
+    group.bench_function("regex", |b| b.iter(|| parse_with_regex(black_box(&text), &kv_regexp, &pkgver_regexp)));
+    group.bench_function("deb822", |b| b.iter(|| parse_with_deb822(black_box(&text))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsers);
+criterion_main!(benches);
@@ -0,0 +1,185 @@
+//! Typed package checksums (MD5, SHA256, SHA512) and a hasher that computes all of them in one pass.
+
+use std::fmt;
+use std::str::FromStr;
+
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Hash algorithms, ordered from weakest to strongest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    Sha512
+}
+
+pub const ALL_ALGORITHMS : [HashAlgorithm; 3] = [HashAlgorithm::Md5, HashAlgorithm::Sha256, HashAlgorithm::Sha512];
+
+impl HashAlgorithm {
+    /// Number of bytes in a digest.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64
+        }
+    }
+
+    /// The name of the field that carries this checksum in a Packages stanza.
+    pub fn field_name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "MD5sum",
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512"
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "md5"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Sha512 => write!(f, "sha512")
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "md5" | "md5sum" => Ok(HashAlgorithm::Md5),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(format!("unknown hash algorithm {}", s))
+        }
+    }
+}
+
+/// A digest together with the algorithm that produced it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    algorithm : HashAlgorithm,
+    bytes : Vec<u8>
+}
+
+impl Checksum {
+    /// Parses a hex digest; fails if it isn't hex or has the wrong length for algorithm.
+    pub fn from_hex(algorithm: HashAlgorithm, hex: &str) -> Result<Checksum, String> {
+        let hex = hex.trim();
+        if hex.len() != 2 * algorithm.digest_len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("bad {} checksum {:?}", algorithm, hex));
+        }
+        let bytes = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).unwrap()).collect();
+        Ok(Checksum { algorithm, bytes })
+    }
+
+    pub fn from_bytes(algorithm: HashAlgorithm, bytes: &[u8]) -> Result<Checksum, String> {
+        if bytes.len() != algorithm.digest_len() {
+            return Err(format!("bad {} checksum length {}", algorithm, bytes.len()));
+        }
+        Ok(Checksum { algorithm, bytes: bytes.to_vec() })
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.bytes {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self)
+    }
+}
+
+/// All the checksums known for one file, at most one per algorithm.
+#[derive(Clone, Default)]
+pub struct Checksums {
+    digests : Vec<Checksum>
+}
+
+impl Checksums {
+    pub fn new() -> Checksums {
+        Checksums { digests: vec![] }
+    }
+
+    /// Adds c, replacing any existing checksum with the same algorithm.
+    pub fn insert(&mut self, c: Checksum) {
+        self.digests.retain(|d| d.algorithm != c.algorithm);
+        self.digests.push(c);
+        self.digests.sort_by_key(|d| d.algorithm);
+    }
+
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&Checksum> {
+        self.digests.iter().find(|d| d.algorithm == algorithm)
+    }
+
+    pub fn strongest(&self) -> Option<&Checksum> {
+        self.digests.last()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    /// Iterates from the weakest to the strongest checksum.
+    pub fn iter(&self) -> std::slice::Iter<'_, Checksum> {
+        self.digests.iter()
+    }
+}
+
+/// Computes MD5, SHA256 and SHA512 of a byte stream at once, e.g. while it is downloaded.
+pub struct MultiHasher {
+    md5 : Md5,
+    sha256 : Sha256,
+    sha512 : Sha512,
+    size : u64
+}
+
+impl Default for MultiHasher {
+    fn default() -> Self {
+        MultiHasher::new()
+    }
+}
+
+impl MultiHasher {
+    pub fn new() -> MultiHasher {
+        MultiHasher { md5: Md5::new(), sha256: Sha256::new(), sha512: Sha512::new(), size: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.md5.update(data);
+        self.sha256.update(data);
+        self.sha512.update(data);
+        self.size += data.len() as u64;
+    }
+
+    /// Number of bytes hashed so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn finish(self) -> Checksums {
+        let mut sums = Checksums::new();
+        sums.insert(Checksum { algorithm: HashAlgorithm::Md5, bytes: self.md5.finalize().to_vec() });
+        sums.insert(Checksum { algorithm: HashAlgorithm::Sha256, bytes: self.sha256.finalize().to_vec() });
+        sums.insert(Checksum { algorithm: HashAlgorithm::Sha512, bytes: self.sha512.finalize().to_vec() });
+        sums
+    }
+}
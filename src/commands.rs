@@ -0,0 +1,422 @@
+//! The command registry: every command's name, aliases, arguments, flags and help text, and the parser
+//! that checks a command line against them.
+
+/// What a positional argument is, so that the REPL knows how to complete it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    Package,
+    File,
+    Word,
+}
+
+pub struct ArgSpec {
+    /// as shown in syntax lines, e.g. <pkg> or refuse|warn
+    pub name : &'static str,
+    pub kind : Arg,
+    pub optional : bool,
+    /// the last argument may take any number of words
+    pub repeats : bool,
+    /// the last argument takes the rest of the line as typed, for commands that parse it themselves
+    pub raw : bool,
+}
+
+pub struct FlagSpec {
+    /// including the dashes, e.g. --force
+    pub name : &'static str,
+    /// the flag's value, e.g. json|junit, or None for a switch
+    pub value : Option<&'static str>,
+    pub help : &'static str,
+}
+
+pub struct CommandSpec {
+    pub name : &'static str,
+    pub aliases : &'static [&'static str],
+    pub args : &'static [ArgSpec],
+    pub flags : &'static [FlagSpec],
+    pub help : &'static str,
+}
+
+impl CommandSpec {
+    /// The arguments and flags, e.g. "<pkg> [<version>] [--force]".
+    pub fn syntax(&self) -> String {
+        let args = self.args.iter().map(|a| {
+            let name = if a.repeats { format!("{}...", a.name) } else { a.name.to_string() };
+            if a.optional { format!("[{}]", name) } else { name }
+        });
+        let flags = self.flags.iter().map(|f| match f.value {
+            None => format!("[{}]", f.name),
+            Some(v) => format!("[{} {}]", f.name, v)
+        });
+        args.chain(flags).collect::<Vec<String>>().join(" ")
+    }
+
+    /// Checks tokens (the words after the command name) against the spec.
+    pub fn parse(&self, tokens: &[String]) -> Result<Invocation, String> {
+        let mut inv = Invocation { args: vec![], flags: vec![] };
+        let mut tokens = tokens.iter().cloned();
+        while let Some(token) = tokens.next() {
+            if !token.starts_with("--") || token.len() == 2 {
+                inv.args.push(token);
+                continue;
+            }
+            inv.flags.push(self.flag(&token, &mut tokens)?);
+        }
+        self.check_count(&inv)?;
+        Ok(inv)
+    }
+
+    /// Checks the rest of a command line after the command name against a spec whose last argument is
+    /// raw: flags are taken out, and the words from the last argument on are kept as typed, quotes and all.
+    pub fn parse_line(&self, line: &str) -> Result<Invocation, String> {
+        let mut inv = Invocation { args: vec![], flags: vec![] };
+        let mut raw : Vec<String> = vec![];
+        let mut words = raw_words(line)?.into_iter();
+        while let Some(word) = words.next() {
+            if word.starts_with("--") && word.len() > 2 {
+                inv.flags.push(self.flag(&word, &mut words)?);
+            } else if inv.args.len() + 1 < self.args.len() {
+                inv.args.push(word);
+            } else {
+                raw.push(word);
+            }
+        }
+        if !raw.is_empty() {
+            inv.args.push(raw.join(" "));
+        }
+        self.check_count(&inv)?;
+        Ok(inv)
+    }
+
+    // one of the spec's flags, with its value from the token or else the next one
+    fn flag(&self, token: &str, tokens: &mut impl Iterator<Item = String>) -> Result<(&'static str, Option<String>), String> {
+        let (name, inline_value) = match token.split_once('=') {
+            Some((n, v)) => (n, Some(v.to_string())),
+            None => (token, None)
+        };
+        let flag = self.flags.iter().find(|f| f.name == name).ok_or_else(|| format!("unknown flag {}", name))?;
+        let value = match (flag.value, inline_value) {
+            (None, None) => None,
+            (None, Some(_)) => return Err(format!("{} takes no value", flag.name)),
+            (Some(_), Some(v)) => Some(v),
+            (Some(v), None) => Some(tokens.next().ok_or_else(|| format!("{} needs a value ({})", flag.name, v))?)
+        };
+        Ok((flag.name, value))
+    }
+
+    fn check_count(&self, inv: &Invocation) -> Result<(), String> {
+        let required = self.args.iter().filter(|a| !a.optional).count();
+        let unbounded = self.args.last().is_some_and(|a| a.repeats);
+        if inv.args.len() < required || (inv.args.len() > self.args.len() && !unbounded) {
+            let expected = if required == self.args.len() { required.to_string() } else { format!("{} to {}", required, self.args.len()) };
+            return Err(format!("expected {} arguments, got {}", expected, inv.args.len()));
+        }
+        Ok(())
+    }
+
+    /// Whether the last argument takes the rest of the line as typed; see parse_line.
+    pub fn raw(&self) -> bool {
+        self.args.last().is_some_and(|a| a.raw)
+    }
+}
+
+/// A parsed command line: positional arguments in order, and the flags that were given.
+pub struct Invocation {
+    args : Vec<String>,
+    flags : Vec<(&'static str, Option<String>)>,
+}
+
+impl Invocation {
+    /// A required argument; parse() has checked that it is there.
+    pub fn arg(&self, i: usize) -> &str {
+        &self.args[i]
+    }
+
+    pub fn opt(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(|a| a.as_str())
+    }
+
+    /// The words from argument i on, joined by spaces, for a repeated last argument.
+    pub fn rest(&self, i: usize) -> String {
+        self.args[i..].join(" ")
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|(n, _)| *n == name)
+    }
+
+    /// The value of the last occurrence of a flag that takes one.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags.iter().rev().find(|(n, _)| *n == name).and_then(|(_, v)| v.as_deref())
+    }
+}
+
+/// Splits a command line into words. Words are separated by any amount of whitespace; single or double
+/// quotes keep spaces in a word, and a backslash escapes the next character outside single quotes.
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote : Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => current.push(c),
+            (_, '\\') => {
+                current.push(chars.next().ok_or("trailing backslash")?);
+                in_token = true;
+            }
+            (Some(_), c) => current.push(c),
+            (None, '"') | (None, '\'') => { quote = Some(c); in_token = true }
+            (None, c) if c.is_whitespace() => {
+                if in_token { tokens.push(std::mem::take(&mut current)); }
+                in_token = false;
+            }
+            (None, c) => { current.push(c); in_token = true }
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("unclosed {}", q));
+    }
+    if in_token { tokens.push(current); }
+    Ok(tokens)
+}
+
+// Splits a command line at whitespace outside double quotes, keeping the quotes in the words.
+fn raw_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        if c.is_whitespace() && !quoted {
+            if !current.is_empty() { words.push(std::mem::take(&mut current)); }
+            continue;
+        }
+        if c == '"' { quoted = !quoted; }
+        current.push(c);
+    }
+    if quoted {
+        return Err("unclosed \"".to_string());
+    }
+    if !current.is_empty() { words.push(current); }
+    Ok(words)
+}
+
+use Arg::*;
+
+const fn arg(name: &'static str, kind: Arg) -> ArgSpec {
+    ArgSpec { name, kind, optional: false, repeats: false, raw: false }
+}
+
+const fn opt(name: &'static str, kind: Arg) -> ArgSpec {
+    ArgSpec { name, kind, optional: true, repeats: false, raw: false }
+}
+
+const fn line(name: &'static str, kind: Arg) -> ArgSpec {
+    ArgSpec { name, kind, optional: false, repeats: true, raw: true }
+}
+
+const FORCE : FlagSpec = FlagSpec { name: "--force", value: None, help: "ask the server even if the verification cache has an answer" };
+const JSON : FlagSpec = FlagSpec { name: "--json", value: None, help: "print JSON objects, as after set-output json" };
+const PKG : &[ArgSpec] = &[arg("<pkg>", Package)];
+
+const fn command(name: &'static str, aliases: &'static [&'static str], args: &'static [ArgSpec], flags: &'static [FlagSpec], help: &'static str) -> CommandSpec {
+    CommandSpec { name, aliases, args, flags, help }
+}
+
+pub const COMMANDS : &[CommandSpec] = &[
+    command("help", &[], &[opt("<command>", Word)], &[], "list the commands, or describe one"),
+    command("quit", &[], &[], &[
+        FlagSpec { name: "--execute", value: None, help: "run pending requests first" },
+        FlagSpec { name: "--discard", value: None, help: "drop pending requests" },
+    ], "quit; refuses while requests are pending unless told what to do with them"),
+
+    command("load-defaults", &["ld"], &[], &[JSON], "load the configured sources and installed file; missing built-in default files are skipped with a warning"),
+    command("load-packages", &["lp"], &[arg("<pkgfile-name>", File)], &[JSON], "load available packages from a Packages file"),
+    command("load-installed", &["li"], &[arg("<pkgfile-name>", File)], &[JSON], "load installed packages from a status file"),
+    command("write-installed", &[], &[arg("<status-file>", File)], &[JSON], "write the installed packages to a status file, as load-installed reads"),
+    command("load-csv", &["lc"], &[arg("<csvfile-name>", File)], &[JSON], "load package versions and checksums from a CSV file"),
+    command("load-release", &["lr"], &[arg("<release-file>", File)], &[JSON], "load a Release or InRelease file to check indexes against"),
+    command("set-release-check", &[], &[arg("refuse|warn", Word)], &[], "what load-packages does with an index that doesn't match its Release entry"),
+    command("set-keyring", &[], &[arg("<keyring-file>", File)], &[JSON], "verify Release signatures with the keys in a file"),
+    command("set-allow-unverified", &[], &[arg("true|false", Word)], &[], "load indexes that can't be authenticated, with a warning"),
+    command("save-cache", &[], &[arg("<cache-file>", File)], &[JSON], "write the loaded packages to a binary cache"),
+    command("load-cache", &[], &[arg("<cache-file>", File)], &[JSON], "read a binary cache, re-parsing its sources if they changed"),
+    command("add-source", &[], &[arg("<base-url>", Word), arg("<suite>", Word), arg("<component>", Word), arg("<arch>", Word)], &[],
+        "add a mirror index for update to download"),
+    command("set-lists-dir", &[], &[arg("<directory>", File)], &[], "set where update stores indexes"),
+    command("update", &[], &[], &[JSON], "download and load the indexes of every source"),
+
+    command("search", &[], &[line("<pattern>", Word)], &[JSON],
+        "list packages matching a pattern: ~n name, ~d description, ~s section, ~p priority (regexes), ~i installed, \
+        ~U upgradable, ~D<pkg> depends on, ~V<op><version>; join terms with spaces (and) or | (or), negate with !, group with ()"),
+    command("info", &[], PKG, &[JSON], "show a package's versions, checksums and dependencies"),
+    command("deps", &[], PKG, &[JSON], "show a package's dependencies"),
+    command("deps-available", &[], PKG, &[JSON], "show which of a package's dependencies are satisfied"),
+    command("transitive-dep-solution", &[], PKG, &[JSON], "list everything a package depends on, transitively"),
+    command("how-to-install", &[], PKG, &[JSON], "list the packages to install to install a package"),
+
+    command("install", &[], PKG, &[JSON], "simulate installing a package and what how-to-install says it needs"),
+    command("remove", &[], PKG, &[JSON], "simulate removing a package and the installed packages that depend on it"),
+    command("rollback", &[], &[], &[
+        FlagSpec { name: "--all", value: None, help: "roll back every transaction since the last commit-to" },
+        JSON,
+    ], "undo the last simulated install or remove"),
+    command("commit-to", &[], &[arg("<status-file>", File)], &[JSON], "write the installed packages to a status file and keep the transactions so far"),
+    command("transactions", &[], &[], &[JSON], "list the simulated installs and removes"),
+
+    command("set-output", &[], &[arg("text|json", Word)], &[], "print query and request results as text or as JSON objects, one per line"),
+    command("set-server", &[], &[arg("<server>", Word)], &[], "verify against this server only"),
+    command("add-server", &[], &[arg("<server>", Word)], &[], "add a verification server"),
+    command("remove-server", &[], &[arg("<server>", Word)], &[], "remove a verification server"),
+    command("list-servers", &[], &[], &[JSON], "list the verification servers and the mode"),
+    command("set-server-mode", &[], &[arg("failover|quorum", Word), opt("<n>", Word)], &[], "use the servers in failover order, or compare the answers of n of them"),
+    command("set-hash", &[], &[arg("md5|sha256|sha512|strongest", Word)], &[], "which checksum enq-verify asks for"),
+    command("set-timeout", &[], &[arg("<milliseconds>", Word)], &[], "limit each attempt at a request"),
+    command("set-retries", &[], &[arg("<count>", Word)], &[], "how many times a failed request is sent again"),
+    command("set-backoff", &[], &[arg("<milliseconds>", Word)], &[], "delay before the first retry"),
+    command("set-max-connections", &[], &[arg("<count>", Word)], &[], "cap the number of concurrent requests"),
+    command("set-ca-bundle", &[], &[arg("<pem-file>|none", File)], &[], "trust the CA certificates in a file"),
+    command("set-client-cert", &[], &[arg("<cert-pem>|none", File), opt("<key-pem>", File)], &[], "present a client certificate for mutual TLS"),
+    command("set-proxy", &[], &[arg("<proxy-url>|none|env", Word)], &[], "send requests through a proxy"),
+
+    command("enq-verify", &[], &[arg("<pkg>", Package), opt("<version>", Word)], &[FORCE, JSON], "queue a request to verify a package's checksum"),
+    command("execute", &[], &[], &[JSON], "run the queued requests and wait for them"),
+    command("submit", &[], &[], &[JSON], "start the queued requests without waiting"),
+    command("poll", &[], &[], &[JSON], "make progress on submitted requests without blocking"),
+    command("wait", &[], &[], &[JSON], "wait for the submitted requests"),
+    command("cancel", &[], &[], &[JSON], "drop queued and submitted requests"),
+    command("verify-installed", &[], &[], &[FORCE, JSON], "verify every installed package"),
+    command("verify-plan", &[], PKG, &[FORCE, JSON], "verify a package and everything how-to-install would install with it"),
+    command("verify-all", &[], &[], &[FORCE, JSON], "verify every available package"),
+    command("verify-report", &[], &[arg("<file>", File)], &[
+        FlagSpec { name: "--format", value: Some("json|junit"), help: "report format (default json)" },
+        JSON,
+    ], "write the results of the requests so far"),
+    command("verify-history", &[], PKG, &[JSON], "show the cached verification answers for a package"),
+    command("set-verify-cache", &[], &[arg("<file>", File)], &[], "set the verification cache file"),
+    command("set-verify-ttl", &[], &[arg("<seconds>", Word)], &[], "how long a cached answer is used"),
+
+    command("set-mirror", &[], &[arg("<base-url>", Word)], &[], "set the mirror that fetch downloads from"),
+    command("set-cache-dir", &[], &[arg("<directory>", File)], &[], "set where fetched .debs go"),
+    command("fetch", &[], &[arg("<pkg>", Package), opt("<version>", Word)], &[JSON], "download a package's .deb and check it"),
+    command("enq-fetch", &[], &[arg("<pkg>", Package), opt("<version>", Word)], &[JSON], "queue a download of a package's .deb"),
+    command("output-md5s", &["output-checksums"], &[arg("<output-file>", File)], &[], "write the available versions and checksums to a CSV file"),
+    command("test-version-compare", &[], &[arg("<version1>", Word), arg("<version2>", Word)], &[JSON], "compare two Debian version numbers"),
+];
+
+/// Looks a command up by name or alias.
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name == name || c.aliases.contains(&name))
+}
+
+/// Names and aliases of every command, e.g. for completion.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+}
+
+/// Prints every command with its syntax and help, or everything about one command.
+pub fn print_help(name: Option<&str>) {
+    let spec = match name {
+        None => {
+            for c in COMMANDS {
+                println!("{}", format!("{} {}", c.name, c.syntax()).trim_end());
+                println!("    {}", c.help);
+            }
+            return;
+        }
+        Some(n) => match find(n) {
+            None => { error!("no command {}; help lists them", n); return }
+            Some(s) => s
+        }
+    };
+    print!("{}", describe(spec));
+}
+
+// everything about one command, a line each: syntax, aliases, help, and each flag with what it does
+fn describe(spec: &CommandSpec) -> String {
+    let mut lines = vec![format!("syntax: {}", format!("{} {}", spec.name, spec.syntax()).trim_end())];
+    if !spec.aliases.is_empty() {
+        lines.push(format!("aliases: {}", spec.aliases.join(", ")));
+    }
+    lines.push(spec.help.to_string());
+    for f in spec.flags {
+        lines.push(match f.value {
+            None => format!("  {}: {}", f.name, f.help),
+            Some(v) => format!("  {} {}: {}", f.name, v, f.help)
+        });
+    }
+    lines.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    fn parse(line: &str) -> Result<Invocation, String> {
+        let tokens = words(line);
+        find(&tokens[0]).unwrap().parse(&tokens[1..])
+    }
+
+    #[test]
+    fn tokenize_splits_at_whitespace_outside_quotes() {
+        assert_eq!(words("  info   alpha "), ["info", "alpha"]);
+        assert_eq!(words("a \"b c\" 'd e'"), ["a", "b c", "d e"]);
+        assert_eq!(words("x\"y z\"w"), ["xy zw"]);
+        assert_eq!(words("a \"\" ''"), ["a", "", ""]);
+        assert_eq!(words("'say \"hi\"' \"it's\""), ["say \"hi\"", "it's"]);
+        assert_eq!(words(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn tokenize_escapes_with_backslash_except_in_single_quotes() {
+        assert_eq!(words("a\\ b"), ["a b"]);
+        assert_eq!(words("\"a \\\" b\""), ["a \" b"]);
+        assert_eq!(words("'a\\b'"), ["a\\b"]);
+        assert_eq!(words("\\'x"), ["'x"]);
+        assert_eq!(tokenize("a\\").unwrap_err(), "trailing backslash");
+        assert_eq!(tokenize("a \"b").unwrap_err(), "unclosed \"");
+        assert_eq!(tokenize("a 'b").unwrap_err(), "unclosed '");
+    }
+
+    #[test]
+    fn flag_values_follow_an_equals_sign_or_come_next() {
+        let inv = parse("verify-report out.xml --format=junit").unwrap();
+        assert_eq!((inv.arg(0), inv.value("--format")), ("out.xml", Some("junit")));
+        let inv = parse("verify-report --format junit out.xml").unwrap();
+        assert_eq!((inv.arg(0), inv.value("--format")), ("out.xml", Some("junit")));
+        // the last occurrence wins, and an empty value is still a value
+        let inv = parse("verify-report out.xml --format=json --format=").unwrap();
+        assert_eq!(inv.value("--format"), Some(""));
+        let inv = parse("verify-report out.xml --json").unwrap();
+        assert!(inv.flag("--json") && inv.value("--json").is_none());
+        // a lone -- is an argument rather than a flag
+        assert_eq!(parse("info --").unwrap().arg(0), "--");
+    }
+
+    #[test]
+    fn bad_flags_are_reported() {
+        assert_eq!(parse("verify-report out.xml --format").err().unwrap(), "--format needs a value (json|junit)");
+        assert_eq!(parse("verify-report out.xml --json=yes").err().unwrap(), "--json takes no value");
+        assert_eq!(parse("verify-report out.xml --fromat=junit").err().unwrap(), "unknown flag --fromat");
+        assert_eq!(parse("verify-report").err().unwrap(), "expected 1 arguments, got 0");
+    }
+
+    #[test]
+    fn help_describes_one_command() {
+        assert_eq!(describe(find("verify-report").unwrap()),
+                   "syntax: verify-report <file> [--format json|junit] [--json]\n\
+                    write the results of the requests so far\n\
+                    \x20 --format json|junit: report format (default json)\n\
+                    \x20 --json: print JSON objects, as after set-output json\n");
+        assert_eq!(describe(find("lp").unwrap()),
+                   "syntax: load-packages <pkgfile-name> [--json]\n\
+                    aliases: lp\n\
+                    load available packages from a Packages file\n\
+                    \x20 --json: print JSON objects, as after set-output json\n");
+    }
+}
@@ -0,0 +1,200 @@
+use std::borrow::Cow;
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::commands::{self, Arg};
+use crate::packages::Packages;
+
+/// Completes command names and aliases, flags, package names and file paths; hints at a command's arguments
+/// and shows unknown commands in red.
+pub struct RpkgHelper {
+    // sorted, so that the names with a given prefix are a range
+    package_names : Vec<String>,
+    // the names_generation of the Packages that package_names came from
+    generation : Option<u64>,
+    files : FilenameCompleter,
+}
+
+impl RpkgHelper {
+    pub fn new() -> RpkgHelper {
+        RpkgHelper { package_names: vec![], generation: None, files: FilenameCompleter::new() }
+    }
+
+    /// Picks up the package names after a command that may have loaded packages.
+    pub fn update_packages(&mut self, state: &Packages) {
+        if self.generation == Some(state.names_generation()) { return; }
+        self.generation = Some(state.names_generation());
+        self.package_names = state.get_package_names().into_iter().map(String::from).collect();
+        self.package_names.sort_unstable();
+    }
+
+    fn packages_with_prefix(&self, prefix: &str) -> Vec<Pair> {
+        let start = self.package_names.partition_point(|n| n.as_str() < prefix);
+        self.package_names[start..].iter()
+            .take_while(|n| n.starts_with(prefix))
+            .map(|n| pair(n))
+            .collect()
+    }
+}
+
+// a completed word is followed by a space, ready for the next argument
+fn pair(s: &str) -> Pair {
+    Pair { display: s.to_string(), replacement: format!("{} ", s) }
+}
+
+// a command word that nothing starts with, or that is complete (followed by a space) and isn't a command
+fn is_unknown(word: &str, complete: bool) -> bool {
+    if complete { commands::find(word).is_none() } else { !commands::names().any(|n| n.starts_with(word)) }
+}
+
+impl Completer for RpkgHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let mut words = before[..start].split_whitespace();
+        let cmd = match words.next() {
+            None => {
+                let mut names : Vec<Pair> = commands::names().filter(|n| n.starts_with(word)).map(pair).collect();
+                names.sort_by(|a, b| a.display.cmp(&b.display));
+                return Ok((start, names));
+            }
+            Some(c) => c
+        };
+        let spec = match commands::find(cmd) {
+            None => return Ok((start, vec![])),
+            Some(s) => s
+        };
+        if word.starts_with("--") {
+            let flags = spec.flags.iter().filter(|f| f.name.starts_with(word)).map(|f| pair(f.name)).collect();
+            return Ok((start, flags));
+        }
+        // the value of a flag isn't a positional argument
+        let mut position = 0;
+        while let Some(w) = words.next() {
+            match spec.flags.iter().find(|f| f.name == w) {
+                Some(f) if f.value.is_some() => { words.next(); }
+                Some(_) => {}
+                None => position += 1
+            }
+        }
+        let arg = spec.args.get(position).or_else(|| spec.args.last().filter(|a| a.repeats));
+        match arg.map(|a| a.kind) {
+            Some(Arg::Package) => Ok((start, self.packages_with_prefix(word))),
+            Some(Arg::File) => self.files.complete_path(line, pos),
+            _ => Ok((start, vec![]))
+        }
+    }
+}
+
+impl Hinter for RpkgHelper {
+    type Hint = String;
+
+    // the rest of the only command that starts with what has been typed, then the command's arguments
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() || line.is_empty() { return None; }
+        if let Some(cmd) = line.strip_suffix(' ') {
+            if cmd.contains(' ') { return None; }
+            return commands::find(cmd).map(|spec| spec.syntax()).filter(|s| !s.is_empty());
+        }
+        if line.contains(' ') { return None; }
+        let mut matching = commands::COMMANDS.iter().filter(|c| c.name.starts_with(line));
+        match (matching.next(), matching.next()) {
+            (Some(spec), None) => Some(format!("{} {}", &spec.name[line.len()..], spec.syntax()).trim_end().to_string()),
+            _ => None
+        }
+    }
+}
+
+impl Highlighter for RpkgHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let end = line.find(' ').unwrap_or(line.len());
+        let word = &line[..end];
+        if word.is_empty() || !is_unknown(word, end < line.len()) {
+            return Cow::Borrowed(line);
+        }
+        Cow::Owned(format!("\x1b[31m{}\x1b[0m{}", word, &line[end..]))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Validator for RpkgHelper {}
+
+impl Helper for RpkgHelper {}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::history::History;
+
+    use super::*;
+
+    fn completions(helper: &RpkgHelper, line: &str) -> (usize, Vec<String>) {
+        let history = History::new();
+        let (start, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    fn hint(helper: &RpkgHelper, line: &str) -> Option<String> {
+        let history = History::new();
+        helper.hint(line, line.len(), &Context::new(&history))
+    }
+
+    fn load_installed(state: &mut Packages, dir: &std::path::Path, name: &str, packages: &[&str]) {
+        let path = dir.join(name);
+        let stanzas : Vec<String> = packages.iter().map(|p| format!("Package: {}\nStatus: install ok installed\nVersion: 1.0\n", p)).collect();
+        std::fs::write(&path, stanzas.join("\n")).unwrap();
+        state.parse_installed(path.to_str().unwrap());
+    }
+
+    #[test]
+    fn completes_commands_flags_and_package_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = Packages::new();
+        load_installed(&mut state, dir.path(), "status", &["alpha", "alphabet", "beta"]);
+        let mut helper = RpkgHelper::new();
+        helper.update_packages(&state);
+
+        assert_eq!(completions(&helper, "dep"), (0, vec![String::from("deps "), String::from("deps-available ")]));
+        assert_eq!(completions(&helper, "info --j"), (5, vec![String::from("--json ")]));
+        assert_eq!(completions(&helper, "info alp"), (5, vec![String::from("alpha "), String::from("alphabet ")]));
+        assert_eq!(completions(&helper, "info --json b"), (12, vec![String::from("beta ")]));
+        assert_eq!(completions(&helper, "nosuch a"), (7, vec![]));
+    }
+
+    #[test]
+    fn picks_up_names_loaded_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = Packages::new();
+        load_installed(&mut state, dir.path(), "status", &["alpha"]);
+        let mut helper = RpkgHelper::new();
+        helper.update_packages(&state);
+        assert_eq!(completions(&helper, "info om").1, Vec::<String>::new());
+
+        load_installed(&mut state, dir.path(), "status2", &["omega"]);
+        helper.update_packages(&state);
+        assert_eq!(completions(&helper, "info om").1, [String::from("omega ")]);
+    }
+
+    #[test]
+    fn hints_at_the_rest_of_a_command_and_its_arguments() {
+        let helper = RpkgHelper::new();
+        assert_eq!(hint(&helper, "load-inst"), Some(String::from("alled <pkgfile-name> [--json]")));
+        assert_eq!(hint(&helper, "deps "), Some(String::from("<pkg> [--json]")));
+        // several commands start with "load"
+        assert_eq!(hint(&helper, "load"), None);
+        assert_eq!(hint(&helper, "deps alpha"), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! A small, allocation-free tokenizer for deb822 control data (Packages, status and Release files).
+//! Paragraphs, fields and relationship alternatives are all returned as slices of the input text.
+
+use crate::debversion::VersionRelation;
+
+/// One paragraph (stanza) of a deb822 document, i.e. a run of non-blank lines.
+pub struct Paragraph<'a> {
+    text : &'a str
+}
+
+/// One field of a paragraph. For multi-line fields, value includes the continuation lines
+/// (with their leading whitespace), separated by newlines.
+pub struct Field<'a> {
+    pub name : &'a str,
+    pub value : &'a str
+}
+
+/// Iterator over the paragraphs of a document; see paragraphs().
+pub struct Paragraphs<'a> {
+    rest : &'a str
+}
+
+/// Iterator over the fields of a paragraph; see Paragraph::fields().
+pub struct Fields<'a> {
+    rest : &'a str
+}
+
+/// One alternative of a relationship field, e.g. `libc6 (>= 2.33)`.
+pub struct Relation<'a> {
+    pub name : &'a str,
+    pub version : Option<(VersionRelation, &'a str)>
+}
+
+pub fn paragraphs(text: &str) -> Paragraphs<'_> {
+    Paragraphs { rest: text }
+}
+
+// splits off the first line, without its terminating newline
+fn split_line(s: &str) -> (&str, &str) {
+    match s.find('\n') {
+        None => (s, ""),
+        Some(i) => (&s[..i], &s[i+1..])
+    }
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn is_continuation(line: &str) -> bool {
+    line.starts_with(' ') || line.starts_with('\t')
+}
+
+impl<'a> Iterator for Paragraphs<'a> {
+    type Item = Paragraph<'a>;
+
+    fn next(&mut self) -> Option<Paragraph<'a>> {
+        // skip separating blank lines
+        loop {
+            if self.rest.is_empty() { return None; }
+            let (line, after) = split_line(self.rest);
+            if !is_blank(line) { break; }
+            self.rest = after;
+        }
+        let start = self.rest;
+        let mut cursor = start;
+        while !cursor.is_empty() {
+            let (line, after) = split_line(cursor);
+            if is_blank(line) { break; }
+            cursor = after;
+        }
+        self.rest = cursor;
+        let len = start.len() - cursor.len();
+        Some(Paragraph { text: start[..len].trim_end() })
+    }
+}
+
+impl<'a> Paragraph<'a> {
+    pub fn fields(&self) -> Fields<'a> {
+        Fields { rest: self.text }
+    }
+
+    /// Returns the value of the first field called name (compared case-insensitively, as deb822 requires).
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.fields().find(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value)
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Field<'a>;
+
+    fn next(&mut self) -> Option<Field<'a>> {
+        loop {
+            if self.rest.is_empty() { return None; }
+            let start = self.rest;
+            let (line, after) = split_line(start);
+            self.rest = after;
+            // stray continuation lines, comments and lines without a colon aren't fields
+            if is_continuation(line) || line.starts_with('#') { continue; }
+            let colon = match line.find(':') {
+                None => continue,
+                Some(c) => c
+            };
+            let mut end = line.len();
+            while !self.rest.is_empty() {
+                let (cont, after) = split_line(self.rest);
+                if !is_continuation(cont) { break; }
+                end = start.len() - self.rest.len() + cont.len();
+                self.rest = after;
+            }
+            return Some(Field {
+                name : line[..colon].trim(),
+                value : start[colon+1..end].trim()
+            });
+        }
+    }
+}
+
+fn parse_relation_op(op: &str) -> Option<VersionRelation> {
+    match op {
+        "<<" => Some(VersionRelation::StrictlyLess),
+        "<=" | "<" => Some(VersionRelation::LessOrEqual), // bare < and > are deprecated spellings of <= and >=
+        "=" => Some(VersionRelation::Equal),
+        ">=" | ">" => Some(VersionRelation::GreaterOrEqual),
+        ">>" => Some(VersionRelation::StrictlyGreater),
+        _ => None
+    }
+}
+
+/// Parses one alternative like `libfoo:any (>= 1.2) [amd64]`. Architecture qualifiers,
+/// architecture restrictions and build profiles are dropped.
+pub fn parse_relation(s: &str) -> Option<Relation<'_>> {
+    let s = s.trim();
+    let name_end = s.find(|c:char| c.is_whitespace() || c == '(' || c == '[' || c == '<').unwrap_or(s.len());
+    let name = match s[..name_end].find(':') {
+        None => &s[..name_end],
+        Some(c) => &s[..c]
+    };
+    if name.is_empty() { return None; }
+
+    let rest = s[name_end..].trim_start();
+    let mut version = None;
+    if let Some(inner) = rest.strip_prefix('(') {
+        let inner = &inner[..inner.find(')')?];
+        let inner = inner.trim_start();
+        let op_end = inner.find(|c:char| c != '<' && c != '=' && c != '>').unwrap_or(inner.len());
+        version = Some((parse_relation_op(&inner[..op_end])?, inner[op_end..].trim()));
+    }
+    Some(Relation { name, version })
+}
+
+/// Parses a relationship field such as Depends into a list of dependencies, each a list of alternatives.
+pub fn parse_relations(value: &str) -> Vec<Vec<Relation<'_>>> {
+    value.split(',')
+        .map(|dep| dep.split('|').filter_map(parse_relation).collect::<Vec<Relation>>())
+        .filter(|alts| !alts.is_empty())
+        .collect()
+}
@@ -3,6 +3,7 @@ use std::string::ParseError;
 use std::cmp::Ordering::{Less, Equal, Greater};
 use std::str::FromStr;
 
+#[derive(Clone,Copy,PartialEq,Eq)]
 pub enum VersionRelation {
     StrictlyLess, // <<
     LessOrEqual, // <=
@@ -39,7 +40,7 @@ impl FromStr for VersionRelation {
 }
 
 
-#[derive(PartialEq,Eq)]
+#[derive(Clone,PartialEq,Eq)]
 pub struct DebianVersionNum {
     epoch : String,
     upstream : String,
@@ -1 +1,3 @@
+pub mod checksums;
+pub mod deb822;
 pub mod debversion;
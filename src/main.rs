@@ -1,116 +1,204 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::io::{self, BufRead, IsTerminal};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use rpkg::debversion;
+use crate::completion::RpkgHelper;
 use crate::packages::Packages;
+use crate::packages::config::Config;
+use crate::packages::serve::ServeOptions;
+
+// Failures reported by the command being run, so that it can return a status; a script's exit code comes from them.
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn note_failure() {
+    FAILURES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Prints "Error: ..." (an "error" object in JSON mode) and marks the current command as failed.
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        $crate::note_failure();
+        if $crate::packages::output::json() {
+            $crate::packages::output::error(&format!($($arg)*));
+        } else {
+            println!("Error: {}", format_args!($($arg)*));
+        }
+    }};
+}
 
 mod packages;
+mod commands;
+mod completion;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Failed,
+    Quit,
+}
 
-fn check_syntax(n: usize, cmd_fragments:&Vec<&str>, arg: &str) -> bool {
-    let cmd : &str = &cmd_fragments.get(0).unwrap();
-    if cmd_fragments.len() != n {
-        println!("syntax: {} {}", cmd, arg);
-        return false
+/// Runs one command line. The status is Failed if the command line couldn't be parsed or the command reported an error.
+fn process_command(state: &mut Packages, cmdline: &str) -> Status {
+    FAILURES.store(0, Ordering::SeqCst);
+    // a command that parses its own arguments, like search, gets the rest of the line as typed
+    let line = cmdline.trim_start();
+    let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (cmd, spec, parsed) = match commands::find(first).filter(|s| s.raw()) {
+        Some(spec) => (first.to_string(), spec, spec.parse_line(rest)),
+        None => {
+            let tokens = match commands::tokenize(cmdline) {
+                Ok(t) => t,
+                Err(e) => { error!("{}", e); return Status::Failed }
+            };
+            let cmd = match tokens.first() {
+                None => return Status::Ok,
+                Some(c) => c.clone()
+            };
+            let spec = match commands::find(&cmd) {
+                Some(s) => s,
+                None => {
+                    packages::output::failure(&format!("couldn't understand cmd {:?}; help lists the commands", cmd));
+                    return Status::Failed
+                }
+            };
+            (cmd, spec, spec.parse(&tokens[1..]))
+        }
+    };
+    let args = match parsed {
+        Ok(a) => a,
+        Err(e) => {
+            packages::output::failure(&format!("syntax: {} {} ({})", cmd, spec.syntax(), e));
+            return Status::Failed
+        }
+    };
+    // --json switches to JSON output for this command only
+    if !args.flag("--json") {
+        return run(state, spec.name, &args);
     }
-    return true
+    let was_json = packages::output::json();
+    packages::output::set_json(true);
+    let status = run(state, spec.name, &args);
+    packages::output::set_json(was_json);
+    status
 }
 
-fn process_command(state: &mut Packages, cmdline: &str) -> bool {
-    let cmd_fragments: Vec<&str> = cmdline.split(" ").collect();
-    if cmdline.is_empty() { return false }
-    let cmd : &str = &cmd_fragments.get(0).unwrap();
-    match cmd {
-        "quit" => { 
-            return true 
+// runs the command called name, whose arguments have been checked against its spec
+fn run(state: &mut Packages, name: &str, args: &commands::Invocation) -> Status {
+    match name {
+        "help" => commands::print_help(args.opt(0)),
+        "quit" => {
+            let (execute, discard) = (args.flag("--execute"), args.flag("--discard"));
+            if execute && discard {
+                error!("quit takes --execute or --discard, not both");
+                return Status::Failed;
+            }
+            let pending = state.pending_requests();
+            if pending > 0 && !execute && !discard {
+                error!("{} requests are pending; quit --execute runs them first, quit --discard drops them", pending);
+                return Status::Failed;
+            }
+            if execute {
+                state.execute();
+            } else if pending > 0 {
+                state.cancel();
+            }
+            return Status::Quit
         },
-        "load-csv" | "lc" => {
-            if !check_syntax(2, &cmd_fragments, "<csvfile-name>") { return false; }
-            let arg = cmd_fragments.get(1).unwrap();
-            state.parse_csv(arg)
-        }
+        "load-csv" => state.parse_csv(args.arg(0)),
         // parsers.rs
-        "load-packages" | "lp" => {
-            if !check_syntax(2, &cmd_fragments, "<pkgfile-name>") { return false; }
-            let arg = cmd_fragments.get(1).unwrap();
-            state.parse_packages(arg)
-        }
-        "load-installed" | "li" => {
-            if !check_syntax(2, &cmd_fragments, "<pkgfile-name>") { return false; }
-            let arg = cmd_fragments.get(1).unwrap();
-            state.parse_installed(arg)
-        }
-        // convenience function, also depends on parsers.rs
-        "load-defaults" | "ld" => {
-            state.parse_packages("data/mirror.csclub.uwaterloo.ca_debian_dists_sid_main_binary-amd64_Packages");
-            state.parse_installed("data/installed-packages")
-        }
-
-        "info" => {
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
-            let pkg = cmd_fragments.get(1).unwrap();
-            state.print_info(pkg)
-        }
-        "deps" => {
-            // test: deps 0ad
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
-            let pkg = cmd_fragments.get(1).unwrap();
-            state.print_deps(pkg)
-        }
-
-        // deps-available.rs
-        "deps-available" => {
-            // test: deps-available 3depict
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
-            let pkg = cmd_fragments.get(1).unwrap();
-            state.deps_available(pkg)
-        }
-
-        // solvers.rs, and deps-available.rs for how-to-install
-        "transitive-dep-solution" => {
-            // test: transitive-dep-solution 0ad
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
-            let pkg = cmd_fragments.get(1).unwrap();
-            state.print_transitive_dep_solution(pkg)
-        }
-        "how-to-install" => {
-            if !check_syntax(2, &cmd_fragments, "<pkg>") { return false; }
-            let pkg = cmd_fragments.get(1).unwrap();
-            state.print_how_to_install(pkg)
-        }
-
-        "set-server" => {
-            if !check_syntax(2, &cmd_fragments, "<server>") { return false; }
-            let server = cmd_fragments.get(1).unwrap();
-            state.set_server(server)
-        }
-        "execute" => {
-            state.execute();
-        }
-        "enq-verify" => {
-            let cmd : &str = &cmd_fragments.get(0).unwrap();
-            if cmd_fragments.len() < 2 || cmd_fragments.len() > 3 {
-                println!("syntax: {} <pkg> [<version>]", cmd);
-                return false
-            }
-            let pkg = cmd_fragments.get(1).unwrap();
-            if cmd_fragments.len() == 2 {
-                state.enq_verify(pkg);
+        "load-packages" => state.parse_packages(args.arg(0)),
+        "load-installed" => state.parse_installed(args.arg(0)),
+        // status_file.rs
+        "write-installed" => state.write_installed(args.arg(0)),
+        "load-release" => state.parse_release(args.arg(0)),
+        "set-release-check" => state.set_release_check(args.arg(0)),
+        "set-keyring" => state.set_keyring(args.arg(0)),
+        "set-allow-unverified" => state.set_allow_unverified(args.arg(0)),
+        // convenience function, also depends on parsers.rs; config.rs says what to load
+        "load-defaults" => state.load_defaults(),
+        "save-cache" => state.save_cache(args.arg(0)),
+        "load-cache" => state.load_cache(args.arg(0)),
+
+        "info" => state.print_info(args.arg(0)),
+        // test: deps 0ad
+        "deps" => state.print_deps(args.arg(0)),
+        // search.rs; test: search ~d"access control"
+        "search" => state.search(&args.rest(0)),
+        // deps-available.rs; test: deps-available 3depict
+        "deps-available" => state.deps_available(args.arg(0)),
+        // solvers.rs, and deps-available.rs for how-to-install; test: transitive-dep-solution 0ad
+        "transitive-dep-solution" => state.print_transitive_dep_solution(args.arg(0)),
+        "how-to-install" => state.print_how_to_install(args.arg(0)),
+        // transactions.rs: simulated installs and removes
+        "install" => state.install(args.arg(0)),
+        "remove" => state.remove(args.arg(0)),
+        "rollback" => state.rollback(args.flag("--all")),
+        "commit-to" => state.commit_to(args.arg(0)),
+        "transactions" => state.print_transactions(),
+
+        "set-output" => state.set_output(args.arg(0)),
+        "set-server" => state.set_server(args.arg(0)),
+        "add-server" => state.add_server(args.arg(0)),
+        "remove-server" => state.remove_server(args.arg(0)),
+        "list-servers" => state.list_servers(),
+        "set-server-mode" => state.set_server_mode(args.arg(0), args.opt(1)),
+        "set-hash" => state.set_hash(args.arg(0)),
+        "set-timeout" => state.set_timeout(args.arg(0)),
+        "set-retries" => state.set_retries(args.arg(0)),
+        "set-backoff" => state.set_backoff(args.arg(0)),
+        "set-max-connections" => state.set_max_connections(args.arg(0)),
+        "execute" => state.execute(),
+        "submit" => state.submit(),
+        "poll" => {
+            let left = state.poll();
+            if packages::output::json() {
+                packages::output::emit("poll", &serde_json::json!({ "in_progress": left }));
             } else {
-                let version = cmd_fragments.get(2).unwrap();
-                state.enq_verify_with_version(pkg, version);
+                println!("{} requests in progress", left);
             }
         }
+        "wait" => state.wait(),
+        "cancel" => state.cancel(),
+        "verify-installed" => state.verify_installed(args.flag("--force")),
+        "verify-plan" => state.verify_plan(args.arg(0), args.flag("--force")),
+        "verify-all" => state.verify_all(args.flag("--force")),
+        "verify-history" => state.print_verify_history(args.arg(0)),
+        "set-verify-cache" => state.set_verify_cache(args.arg(0)),
+        "set-verify-ttl" => state.set_verify_ttl(args.arg(0)),
+        "verify-report" => state.write_verify_report(args.arg(0), args.value("--format").unwrap_or("json")),
+        "enq-verify" => match args.opt(1) {
+            None => state.enq_verify(args.arg(0), args.flag("--force")),
+            Some(version) => state.enq_verify_with_version(args.arg(0), version, args.flag("--force"))
+        },
 
-        "output-md5s" => {
-            if !check_syntax(2, &cmd_fragments, "<output-file>") { return false; }
-            let fname = cmd_fragments.get(1).unwrap();
-            state.output_md5s(fname);
-        }
+        "set-ca-bundle" => state.set_ca_bundle(args.arg(0)),
+        "set-client-cert" => state.set_client_cert(args.arg(0), args.opt(1)),
+        "set-proxy" => state.set_proxy(args.arg(0)),
+        "add-source" => state.add_source(args.arg(0), args.arg(1), args.arg(2), args.arg(3)),
+        "set-lists-dir" => state.set_lists_dir(args.arg(0)),
+        "update" => state.update(),
+        "set-mirror" => state.set_mirror(args.arg(0)),
+        "set-cache-dir" => state.set_cache_dir(args.arg(0)),
+        "fetch" => state.fetch(args.arg(0), args.opt(1)),
+        "enq-fetch" => state.enq_fetch(args.arg(0), args.opt(1)),
+
+        "output-md5s" => state.output_md5s(args.arg(0)),
         "test-version-compare" => {
-            if !check_syntax(3, &cmd_fragments, "<version1> <version2>") { return false; }
-            let v1 = cmd_fragments.get(1).unwrap().parse::<debversion::DebianVersionNum>().unwrap();
-            let v2 = cmd_fragments.get(2).unwrap().parse::<debversion::DebianVersionNum>().unwrap();
-            println!("{} and {}: {:?}", v1, v2, v1.cmp(&v2));
+            let parse = |v: &str| v.parse::<debversion::DebianVersionNum>();
+            match (parse(args.arg(0)), parse(args.arg(1))) {
+                (Ok(v1), Ok(v2)) if packages::output::json() => packages::output::emit("version-compare",
+                    &serde_json::json!({ "version1": v1.to_string(), "version2": v2.to_string(), "ordering": format!("{:?}", v1.cmp(&v2)).to_lowercase() })),
+                (Ok(v1), Ok(v2)) => println!("{} and {}: {:?}", v1, v2, v1.cmp(&v2)),
+                _ => error!("could not parse {} and {} as versions", args.arg(0), args.arg(1))
+            }
             // 1:0.4.5+cvs20030824-9 vs 1:0.4.5+cvs20030824-10
             // a vs b
             // a vs a
@@ -119,37 +207,217 @@ fn process_command(state: &mut Packages, cmdline: &str) -> bool {
             // 2-a vs 1-b
             // a vs ~a
         }
-        _ => {
-            println!("couldn't understand cmd {:?}", cmd)
-        }
+        name => unreachable!("command {} is in the registry but not handled", name)
     }
-    return false;
+    if FAILURES.load(Ordering::SeqCst) > 0 { Status::Failed } else { Status::Ok }
 }
 
-fn main() {
+// rpkg serve [--bind <addr>] [--port <n>] [--max-connections <n>] [--delay-ms <ms>] [--fail-every <n>] [--fail-status <code>] [--keyring <file>] [--allow-unverified] <file>...
+fn serve(args: &[String]) {
+    let usage = "syntax: rpkg serve [--bind <addr>] [--port <n>] [--max-connections <n>] [--delay-ms <ms>] [--fail-every <n>] [--fail-status <code>] [--keyring <file>] [--allow-unverified] <release-packages-or-csv-file>...";
+    let mut options = ServeOptions::default();
     let mut state : Packages = Packages::new();
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            files.push(arg.as_str());
+            continue;
+        }
+        if arg == "--allow-unverified" {
+            state.set_allow_unverified("true");
+            continue;
+        }
+        let value = match args.next() {
+            None => { println!("{}", usage); return }
+            Some(v) => v
+        };
+        let ok = match arg.as_str() {
+            "--bind" => value.parse().map(|a| options.bind = a).is_ok(),
+            "--port" => value.parse().map(|p| options.port = p).is_ok(),
+            "--max-connections" => value.parse().ok().filter(|&n| n > 0).map(|n| options.max_connections = n).is_some(),
+            "--delay-ms" => value.parse().map(|ms| options.delay = Duration::from_millis(ms)).is_ok(),
+            "--fail-every" => value.parse().map(|n| options.fail_every = n).is_ok(),
+            "--fail-status" => value.parse().map(|c| options.fail_status = c).is_ok(),
+            "--keyring" => { state.set_keyring(value); FAILURES.load(Ordering::SeqCst) == 0 }
+            _ => false
+        };
+        if !ok {
+            error!("bad option {} {}", arg, value);
+            println!("{}", usage);
+            return;
+        }
+    }
+    if files.is_empty() {
+        println!("{}", usage);
+        return;
+    }
+
+    for file in files {
+        // CSV files come from output-md5s, Release files authenticate the Packages files after them, and anything
+        // else is a Packages file
+        let name = Path::new(file).file_name().and_then(|n| n.to_str()).unwrap_or(file);
+        if file.trim_end_matches(".gz").trim_end_matches(".xz").ends_with(".csv") {
+            state.parse_csv(file);
+        } else if name.ends_with("Release") {
+            state.parse_release(file);
+        } else {
+            state.parse_packages(file);
+        }
+    }
+    // don't serve a partial table
+    if FAILURES.load(Ordering::SeqCst) > 0 {
+        return;
+    }
+    state.serve(options);
+}
+
+const USAGE : &str = "usage: rpkg [--config <file>] [--keyring <file>] [--allow-unverified] [--keep-going] [-c <command>]...
+       rpkg [--config <file>] [--keyring <file>] [--allow-unverified] [--keep-going] <script-file>
+       rpkg serve ...
+With no arguments, commands are read from the terminal, or from standard input if it isn't a terminal.
+Without --config, settings come from ~/.config/rpkg/config.toml or /etc/rpkg/config.toml, if there is one.
+--keyring verifies Release signatures against the keys in a file; indexes that can't be verified are refused
+unless --allow-unverified is given.";
 
-    // bonus (0 points): implement command completion!
-    let mut rl = Editor::<()>::new();
-    if rl.load_history("history.txt").is_err() {}
+// Runs commands until one fails (unless keep_going) or quits. Requests still pending when the input runs out are
+// dropped, and count as a failure: a script says what to do with them by ending with quit --execute or
+// quit --discard. Returns the exit code: 0 if every command succeeded, 1 otherwise.
+fn run_commands<I: Iterator<Item = String>>(state: &mut Packages, commands: I, keep_going: bool) -> i32 {
+    let mut failed = false;
+    for line in commands {
+        // blank lines and # comments are allowed in scripts
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        match process_command(state, line) {
+            Status::Ok => {}
+            Status::Failed => {
+                failed = true;
+                if !keep_going { break }
+            }
+            Status::Quit => break
+        }
+    }
+    let pending = state.pending_requests();
+    if pending > 0 {
+        if !failed {
+            error!("{} requests are still pending at the end of the input; end with quit --execute to run them or quit --discard to drop them", pending);
+            failed = true;
+        }
+        state.cancel();
+    }
+    if failed { 1 } else { 0 }
+}
+
+// Asks what to do with pending requests when interactive input ends; another end of input drops them.
+fn ask_about_pending(state: &mut Packages, rl: &mut Editor<RpkgHelper>) {
+    loop {
+        let pending = state.pending_requests();
+        if pending == 0 {
+            return;
+        }
+        match rl.readline(&format!("{} requests are pending; execute or discard them? [e/d] ", pending)).as_deref().map(str::trim) {
+            Ok("e") | Ok("execute") => { state.execute(); return }
+            Ok("d") | Ok("discard") | Err(_) => { state.cancel(); return }
+            Ok(_) => {}
+        }
+    }
+}
+
+fn repl(state: &mut Packages, history: &str) {
+    let mut rl = Editor::<RpkgHelper>::new();
+    rl.set_helper(Some(RpkgHelper::new()));
+    if rl.load_history(history).is_err() {}
     loop {
         let readline = rl.readline("$ ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
-                if process_command(&mut state, &line) { break }
+                if process_command(state, &line) == Status::Quit { break }
+                rl.helper_mut().unwrap().update_packages(state);
             },
-            Err(ReadlineError::Interrupted) => {
-                break
-            },
-            Err(ReadlineError::Eof) => {
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                ask_about_pending(state, &mut rl);
                 break
             },
             Err(err) => {
-                println!("Error: {:?}", err);
+                error!("{:?}", err);
                 break
             }
         }
     }
-    rl.save_history("history.txt").unwrap();
+    if let Some(dir) = Path::new(history).parent().filter(|d| !d.as_os_str().is_empty()) {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = rl.save_history(history) {
+        error!("could not save history to {}: {}", history, e);
+    }
+}
+
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("serve") {
+        serve(&args[2..]);
+        process::exit(if FAILURES.load(Ordering::SeqCst) > 0 { 1 } else { 0 });
+    }
+
+    let (mut commands, mut script, mut keep_going, mut config) = (vec![], None, false, None);
+    let (mut keyring, mut allow_unverified) = (None, false);
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-c" => match rest.next() {
+                Some(command) => commands.push(command.clone()),
+                None => { println!("{}", USAGE); process::exit(2) }
+            },
+            "--config" => match rest.next() {
+                Some(path) => config = Some(path.clone()),
+                None => { println!("{}", USAGE); process::exit(2) }
+            },
+            "--keyring" => match rest.next() {
+                Some(path) => keyring = Some(path.clone()),
+                None => { println!("{}", USAGE); process::exit(2) }
+            },
+            "--allow-unverified" => allow_unverified = true,
+            "--keep-going" => keep_going = true,
+            "-h" | "--help" => { println!("{}", USAGE); return }
+            a if a.starts_with('-') || script.is_some() => { println!("{}", USAGE); process::exit(2) }
+            a => script = Some(a.to_string())
+        }
+    }
+    if !commands.is_empty() && script.is_some() {
+        println!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let config = match Config::load(config.as_deref()) {
+        Ok(c) => c,
+        Err(e) => { error!("{}", e); process::exit(2) }
+    };
+    let history = config.history();
+    let mut state : Packages = Packages::new();
+    state.apply_config(config);
+    if let Some(path) = keyring {
+        state.set_keyring(&path);
+    }
+    if allow_unverified {
+        state.set_allow_unverified("true");
+    }
+    if FAILURES.load(Ordering::SeqCst) > 0 {
+        process::exit(2);
+    }
+    let code = if !commands.is_empty() {
+        run_commands(&mut state, commands.into_iter(), keep_going)
+    } else if let Some(script) = script {
+        match fs::read_to_string(&script) {
+            Ok(text) => run_commands(&mut state, text.lines().map(String::from), keep_going),
+            Err(e) => { error!("could not read {}: {}", script, e); 2 }
+        }
+    } else if !io::stdin().is_terminal() {
+        run_commands(&mut state, io::stdin().lock().lines().map_while(Result::ok), keep_going)
+    } else {
+        repl(&mut state, &history);
+        0
+    };
+    process::exit(code);
 }
@@ -1,101 +1,887 @@
-use curl::easy::{Easy2, Handler, WriteError};
+use curl::easy::{Easy2, Handler, TimeCondition, WriteError};
 use curl::multi::{Easy2Handle, Multi};
-use std::time::Duration;
-use std::str;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use rpkg::checksums::{Checksum, Checksums, HashAlgorithm, MultiHasher};
+use rpkg::debversion::DebianVersionNum;
 
 use crate::Packages;
+use crate::packages::output;
+use crate::packages::report::{Outcome, VerifyResult};
+use crate::packages::transport::{server_url, TransportSettings};
+use crate::packages::verify_cache::{now_secs, VerifyRecord};
 
-struct Collector(Box<String>);
+#[derive(Default)]
+struct Collector {
+    body : String,
+    // downloads go straight to a file and are hashed on the way, instead of collecting in body
+    download : Option<Box<(File, MultiHasher)>>,
+    // the server's Last-Modified time, in seconds since the epoch, if it sent one
+    filetime : Option<i64>,
+}
 impl Handler for Collector {
     fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
-        (*self.0).push_str(str::from_utf8(&data.to_vec()).unwrap());
+        match &mut self.download {
+            None => self.body.push_str(&String::from_utf8_lossy(data)),
+            Some(download) => {
+                let (file, hasher) = &mut **download;
+                hasher.update(data);
+                // a short count makes curl abort the transfer with a write error
+                if file.write_all(data).is_err() { return Ok(0); }
+            }
+        }
         Ok(data.len())
     }
 }
 
 const DEFAULT_SERVER : &str = "ece459.patricklam.ca:4590";
-impl Drop for Packages {
-    fn drop(&mut self) {
-        self.execute()
+/// What we asked the server for, kept with the curl handle so that results are reported against the
+/// request itself rather than whatever the response URL turns out to be.
+struct PendingRequest {
+    package : String,
+    version : String,
+    kind : RequestKind,
+    // (server, URL) pairs to try in failover order, and the one being tried
+    targets : Vec<(String, String)>,
+    target : usize,
+    // number of times the request has been sent so far, and how many times it has gone through all targets
+    attempts : u32,
+    rounds : u32,
+    // in quorum mode, the request's group (one request per server) and the group's size
+    group : Option<(usize, usize)>,
+    enqueued : Instant,
+}
+
+impl PendingRequest {
+    fn new(package: &str, version: &str, kind: RequestKind, targets: Vec<(String, String)>, group: Option<(usize, usize)>) -> PendingRequest {
+        PendingRequest { package: package.to_string(), version: version.to_string(), kind, targets, target: 0, attempts: 0, rounds: 1, group,
+            enqueued: Instant::now() }
+    }
+
+    // what the request is about, for status lines
+    fn describe(&self) -> String {
+        match self.kind {
+            RequestKind::Index { .. } => format!("index {}", self.url()),
+            _ => format!("package {} version {}", self.package, self.version)
+        }
+    }
+
+    fn server(&self) -> &str {
+        &self.targets[self.target].0
     }
+
+    fn url(&self) -> &str {
+        &self.targets[self.target].1
+    }
+}
+
+/// How enq-verify uses the list of servers.
+#[derive(Clone, Copy)]
+pub enum ServerMode {
+    /// ask the first server, moving down the list on errors and timeouts
+    Failover,
+    /// ask the first n servers (all of them if None) and compare their answers
+    Quorum(Option<usize>),
+}
+
+enum RequestKind {
+    /// ask the server for a checksum and compare it with the local one of exactly this version, if we have one
+    Verify { algorithm: HashAlgorithm, expected: Option<Checksum> },
+    /// download a .deb to path, checking it against the size and checksums from the Packages file
+    Fetch { path: PathBuf, size: Option<u64>, expected: Checksums },
+    /// download an index file (Release, Packages) to path, unless it hasn't changed since modified
+    Index { path: PathBuf, modified: Option<SystemTime> },
+}
+
+// the outcome of one attempt at a request
+enum Attempt {
+    Response(u32, Collector),
+    /// a transport error, which is worth retrying
+    Failed(String),
+    /// curl's multi interface itself failed, which trying again won't fix
+    Aborted(String),
 }
 
 pub struct AsyncState {
-    server : String,
-    easys : Vec<Easy2Handle<Collector>>,
+    servers : Vec<String>,
+    mode : ServerMode,
+    next_group : usize,
+    // None means the strongest checksum known locally for each package
+    hash : Option<HashAlgorithm>,
+    requests : Vec<PendingRequest>,
     multi : Multi,
+    // per-attempt limit, including connecting
+    timeout : Duration,
+    // how many times a request that failed with a 5xx or transport error is sent again
+    retries : u32,
+    // delay before the first retry; it doubles for each retry after that
+    backoff : Duration,
+    max_connections : usize,
+    // everything execute() has finished, for verify-report
+    results : Vec<VerifyResult>,
+    // base URL that Filename fields are relative to
+    pub(crate) mirror : String,
+    // where fetched .debs go
+    pub(crate) cache_dir : String,
+    pub(crate) transport : TransportSettings,
+    session : Session,
 }
 
+const DEFAULT_TIMEOUT : Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES : u32 = 3;
+const DEFAULT_BACKOFF : Duration = Duration::from_millis(250);
+const MAX_BACKOFF : Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONNECTIONS : usize = 16;
+const DEFAULT_MIRROR : &str = "http://deb.debian.org/debian";
+pub(crate) const DEFAULT_CACHE_DIR : &str = "cache";
+
 impl AsyncState {
     pub fn new() -> AsyncState {
         AsyncState {
-            server : String::from(DEFAULT_SERVER),
-            easys : Vec::new(),
+            servers : vec![String::from(DEFAULT_SERVER)],
+            mode : ServerMode::Failover,
+            next_group : 0,
+            hash : None,
+            requests : Vec::new(),
             multi : Multi::new(),
+            timeout : DEFAULT_TIMEOUT,
+            retries : DEFAULT_RETRIES,
+            backoff : DEFAULT_BACKOFF,
+            max_connections : DEFAULT_MAX_CONNECTIONS,
+            results : Vec::new(),
+            mirror : String::from(DEFAULT_MIRROR),
+            cache_dir : String::from(DEFAULT_CACHE_DIR),
+            transport : TransportSettings::default(),
+            session : Session::default(),
         }
     }
+
+    fn backoff_for(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.checked_mul(factor).map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+    }
+
+    fn start(&self, req: &PendingRequest, token: usize) -> io::Result<Easy2Handle<Collector>> {
+        let mut collector = Collector::default();
+        match &req.kind {
+            RequestKind::Fetch { path, .. } | RequestKind::Index { path, .. } =>
+                collector.download = Some(Box::new((File::create(partial_path(path))?, MultiHasher::new()))),
+            RequestKind::Verify { .. } => {}
+        }
+        let mut easy = Easy2::new(collector);
+        if let RequestKind::Index { modified, .. } = &req.kind {
+            easy.fetch_filetime(true).unwrap();
+            if let Some(secs) = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+                easy.time_condition(TimeCondition::IfModifiedSince).unwrap();
+                easy.time_value(secs.as_secs() as i64).unwrap();
+            }
+        }
+        easy.url(req.url()).unwrap();
+        easy.verbose(false).unwrap();
+        easy.follow_location(true).unwrap();
+        easy.timeout(self.timeout).unwrap();
+        easy.connect_timeout(self.timeout).unwrap();
+        self.transport.apply(&mut easy).map_err(io::Error::other)?;
+        let mut handle = self.multi.add2(easy).unwrap();
+        handle.set_token(token).unwrap();
+        Ok(handle)
+    }
+}
+
+// downloads are written next to their final path and only renamed into place once they check out
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+fn parse_setting<T: std::str::FromStr>(value: &str, what: &str) -> Option<T> {
+    match value.parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => { error!("bad {} {}", what, value); None }
+    }
 }
 
 impl Packages {
+    /// Replaces the list of servers with new_server.
     pub fn set_server(&mut self, new_server:&str) {
-        self.async_state.server = String::from(new_server);
+        self.async_state.servers = vec![String::from(new_server)];
+    }
+
+    /// Adds a server at the end of the list, i.e. last in failover order.
+    pub fn add_server(&mut self, server:&str) {
+        if self.async_state.servers.iter().any(|s| s == server) {
+            error!("{} is already a server", server);
+            return;
+        }
+        self.async_state.servers.push(String::from(server));
+    }
+
+    pub fn remove_server(&mut self, server:&str) {
+        let servers = &mut self.async_state.servers;
+        match servers.iter().position(|s| s == server) {
+            None => error!("{} is not a server", server),
+            Some(_) if servers.len() == 1 => error!("can't remove the last server; use set-server to replace it"),
+            Some(i) => { servers.remove(i); }
+        }
+    }
+
+    pub fn list_servers(&self) {
+        if output::json() {
+            let (mode, quorum) = match self.async_state.mode {
+                ServerMode::Failover => ("failover", None),
+                ServerMode::Quorum(n) => ("quorum", Some(n.unwrap_or(self.async_state.servers.len())))
+            };
+            output::emit("servers", &json!({ "mode": mode, "quorum": quorum, "servers": self.async_state.servers }));
+            return;
+        }
+        match self.async_state.mode {
+            ServerMode::Failover => println!("mode: failover"),
+            ServerMode::Quorum(None) => println!("mode: quorum of all servers"),
+            ServerMode::Quorum(Some(n)) => println!("mode: quorum of {} servers", n)
+        }
+        for (i, server) in self.async_state.servers.iter().enumerate() {
+            println!("{}. {}", i + 1, server);
+        }
+    }
+
+    /// failover tries the servers in order until one answers; quorum asks the first n servers (all if n is
+    /// None) and flags packages whose answers disagree with each other or with the local checksum.
+    pub fn set_server_mode(&mut self, mode:&str, n:Option<&str>) {
+        self.async_state.mode = match (mode, n) {
+            ("failover", None) => ServerMode::Failover,
+            ("quorum", None) => ServerMode::Quorum(None),
+            ("quorum", Some(n)) => match parse_setting::<usize>(n, "quorum size") {
+                None => return,
+                Some(0) => { error!("a quorum needs at least one server"); return }
+                Some(n) => ServerMode::Quorum(Some(n))
+            },
+            _ => { error!("expected failover or quorum [<n>]"); return }
+        };
+    }
+
+    /// Selects the checksum that enq-verify requests: md5 (from the v1 endpoint), sha256 or sha512 (from the v2
+    /// endpoint), or strongest, which picks the strongest checksum known locally for each package.
+    pub fn set_hash(&mut self, hash:&str) {
+        if hash == "strongest" {
+            self.async_state.hash = None;
+            return;
+        }
+        match hash.parse::<HashAlgorithm>() {
+            Ok(a) => self.async_state.hash = Some(a),
+            Err(e) => error!("{}", e)
+        }
+    }
+
+    /// Sets the per-attempt timeout, in milliseconds.
+    pub fn set_timeout(&mut self, ms:&str) {
+        if let Some(ms) = parse_setting::<u64>(ms, "timeout") {
+            self.async_state.timeout = Duration::from_millis(ms);
+        }
+    }
+
+    /// Sets how many times a request that got a 5xx response or a transport error is retried.
+    pub fn set_retries(&mut self, n:&str) {
+        if let Some(n) = parse_setting::<u32>(n, "retry count") {
+            self.async_state.retries = n;
+        }
+    }
+
+    /// Sets the delay before the first retry, in milliseconds. Each further retry waits twice as long.
+    pub fn set_backoff(&mut self, ms:&str) {
+        if let Some(ms) = parse_setting::<u64>(ms, "backoff") {
+            self.async_state.backoff = Duration::from_millis(ms);
+        }
+    }
+
+    /// Sets how many requests execute() keeps in flight at once.
+    pub fn set_max_connections(&mut self, n:&str) {
+        match parse_setting::<usize>(n, "connection limit") {
+            Some(0) => error!("need at least one connection"),
+            Some(n) => self.async_state.max_connections = n,
+            None => ()
+        }
+    }
+
+    pub(crate) fn verify_results(&self) -> &[VerifyResult] {
+        &self.async_state.results
     }
 
     /// Retrieves the version number of pkg and calls enq_verify_with_version with that version number.
-    pub fn enq_verify(&mut self, pkg:&str) {
+    pub fn enq_verify(&mut self, pkg:&str, force:bool) {
         let version = self.get_available_debver(pkg);
         match version {
-            None => { println!("Error: package {} not defined.", pkg); return },
+            None => { error!("package {} not defined.", pkg); return },
             Some(v) => { 
                 let vs = &v.to_string();
-                self.enq_verify_with_version(pkg, vs); 
+                self.enq_verify_with_version(pkg, vs, force); 
             }
         };
     }
 
+    // Local checksums describe the available version only; any other version has none.
+    fn get_checksums_for_version(&self, pkg:&str, version:&str) -> Option<&Checksums> {
+        let available = self.get_available_debver(pkg)?;
+        let requested = version.parse::<DebianVersionNum>().ok()?;
+        if requested.cmp(available) != Ordering::Equal {
+            return None;
+        }
+        self.get_checksums(pkg)
+    }
+
     /// Enqueues a request for the provided version/package information. Stores any needed state to async_state so that execute() can handle the results and print out needed output.
-    pub fn enq_verify_with_version(&mut self, pkg:&str, version:&str) {
-        let url = format!("http://{}/rest/v1/checksums/{}/{}", self.async_state.server, pkg, urlencoding::encode(version));
-        println!("queueing request {}", url);
+    /// Unless force is set, a fresh enough answer from the same server in the verification cache is used instead.
+    pub fn enq_verify_with_version(&mut self, pkg:&str, version:&str, force:bool) {
+        for url in self.queue_verify(pkg, version, force) {
+            output::status(&format!("queueing request {}", url));
+        }
+    }
 
-        // add easy handles to multi
-        let mut easy = Easy2::new(Collector(Box::new(String::new())));
-        easy.url(&url).unwrap();
-        easy.verbose(false).unwrap();
-        let handle = self.async_state.multi.add2(easy).unwrap();
-        self.async_state.easys.push(handle);
+    // enq_verify_with_version without the output, for bulk verification. Returns the URLs of the requests, none
+    // if the verification cache answered; that result is reported (and recorded for verify-report) right away.
+    // The cache isn't used in quorum mode, which needs fresh answers from several servers.
+    pub(crate) fn queue_verify(&mut self, pkg:&str, version:&str, force:bool) -> Vec<String> {
+        let local = self.get_checksums_for_version(pkg, version);
+        let algorithm = match self.async_state.hash {
+            Some(a) => a,
+            None => local.and_then(|sums| sums.strongest()).map_or(HashAlgorithm::Md5, |sum| sum.algorithm())
+        };
+        let expected = local.and_then(|sums| sums.get(algorithm)).cloned();
+        // md5 stays on the original endpoint; other algorithms come from v2, which names the algorithm
+        let path = match algorithm {
+            HashAlgorithm::Md5 => format!("/rest/v1/checksums/{}/{}", pkg, urlencoding::encode(version)),
+            _ => format!("/rest/v2/checksums/{}/{}/{}", pkg, urlencoding::encode(version), algorithm)
+        };
+        let targets : Vec<(String, String)> = self.async_state.servers.iter()
+            .map(|server| (server.clone(), server_url(server, &path)))
+            .collect();
+
+        let state = &mut self.async_state;
+        match state.mode {
+            ServerMode::Failover => {
+                if !force {
+                    let cache = &mut self.verify_cache;
+                    let hit = targets.iter().find_map(|(server, url)| cache.lookup(pkg, version, &algorithm.to_string(), server).map(|r| (r.clone(), url)));
+                    if let Some((record, url)) = hit {
+                        let result = cached_result(&record, url.clone(), algorithm, expected);
+                        result.print();
+                        state.results.push(result);
+                        return vec![];
+                    }
+                }
+                let url = targets[0].1.clone();
+                state.requests.push(PendingRequest::new(pkg, version, RequestKind::Verify { algorithm, expected }, targets, None));
+                vec![url]
+            }
+            ServerMode::Quorum(n) => {
+                let n = n.unwrap_or(targets.len()).min(targets.len());
+                let group = (state.next_group, n);
+                state.next_group += 1;
+                targets.into_iter().take(n).map(|target| {
+                    let url = target.1.clone();
+                    let kind = RequestKind::Verify { algorithm, expected: expected.clone() };
+                    state.requests.push(PendingRequest::new(pkg, version, kind, vec![target], Some(group)));
+                    url
+                }).collect()
+            }
+        }
+    }
+
+    // queues a download of an index or Release file from url to path, sending If-Modified-Since with path's
+    // modification time if it exists; execute() runs it
+    pub(crate) fn queue_index(&mut self, url:&str, path:PathBuf) {
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let targets = vec![(String::new(), url.to_string())];
+        self.async_state.requests.push(PendingRequest::new(url, "", RequestKind::Index { path, modified }, targets, None));
+    }
+
+    pub(crate) fn queue_fetch(&mut self, pkg:&str, version:&str, url:&str, path:PathBuf, size:Option<u64>, expected:Checksums) {
+        let targets = vec![(self.async_state.mirror.clone(), url.to_string())];
+        self.async_state.requests.push(PendingRequest::new(pkg, version, RequestKind::Fetch { path, size, expected }, targets, None));
     }
 
-    /// Asks curl to perform all enqueued requests. For requests that succeed with response code 200, compares received MD5sum with local MD5sum (perhaps stored earlier). For requests that fail with 400+, prints error message.
+    /// Asks curl to perform all enqueued requests and waits for them: submit() followed by wait().
     pub fn execute(&mut self) {
+        self.run_requests(false)
+    }
+
+    // execute(), optionally prefixing each status line with a [done/total] progress counter
+    pub(crate) fn run_requests(&mut self, progress: bool) {
+        self.submit_requests(progress);
+        self.wait();
+    }
 
-        // execute all easy handles. wait until done or 30 secs of no events
-        let multi = &self.async_state.multi;
-        while multi.perform().unwrap() > 0
-        {
-            multi.wait(&mut [], Duration::from_secs(30)).unwrap();
-        }
-
-        // check each easy handle
-        let easys = &mut self.async_state.easys;
-        for mut eh in easys.drain(..)
-        {
-            // get package and version associated with handle
-            let mut url : Vec<&str> = eh.effective_url().unwrap().unwrap().split('/').collect();
-            let (ver, pkg) = (url.pop().unwrap().to_string(), url.pop().unwrap().to_string());
-
-            // check response code. If OK, get md5 and compare.
-            let mut handler_after = multi.remove2(eh).unwrap();
-            let response_code = handler_after.response_code().unwrap();
-            if response_code == 200
-            {
-                let md5 = &handler_after.get_ref().0;
-                let same_md5sum = (**md5).eq(self.md5sums.get(self.package_name_to_num.get(&pkg).unwrap()).unwrap());
-                println!("verifying {}, matches: {:?}", pkg, same_md5sum);
+    /// Hands the enqueued requests to curl without waiting for them; poll() and wait() make progress on them.
+    pub fn submit(&mut self) {
+        let n = self.submit_requests(false);
+        let in_progress = self.async_state.session.pending();
+        if output::json() {
+            output::emit("submitted", &json!({ "submitted": n, "in_progress": in_progress }));
+        } else {
+            println!("submitted {} requests ({} in progress)", n, in_progress);
+        }
+    }
+
+    fn submit_requests(&mut self, progress: bool) -> usize {
+        let state = &mut self.async_state;
+        let session = &mut state.session;
+        if session.pending() == 0 {
+            session.first_result = state.results.len();
+        }
+        let n = state.requests.len();
+        session.waiting.extend(state.requests.drain(..));
+        session.total += n;
+        session.progress |= progress;
+        n
+    }
+
+    /// Does whatever work is ready on the submitted requests without blocking, and returns how many are left.
+    pub fn poll(&mut self) -> usize {
+        self.async_state.poll_once();
+        self.finish_session_if_idle();
+        self.async_state.session.pending()
+    }
+
+    /// Blocks until every submitted request has finished.
+    ///
+    /// Requests run at most max_connections at a time. Requests that fail with a 5xx response or a transport
+    /// error (including timeouts) go to the next server in failover order; once every server has failed, they
+    /// are retried with exponential backoff. Every request ends with one status line: for 200, the received
+    /// checksum compared with the local checksum; otherwise the error. In quorum mode, each group of requests
+    /// for the same package also ends with a line comparing the servers' answers.
+    pub fn wait(&mut self) {
+        while self.poll() > 0 {
+            self.async_state.wait_for_activity();
+        }
+    }
+
+    /// Drops every enqueued and submitted request, including those in flight, and removes partial downloads.
+    pub fn cancel(&mut self) {
+        let state = &mut self.async_state;
+        let queued = state.requests.len();
+        let session = std::mem::take(&mut state.session);
+        let in_flight = session.pending();
+        let dropped = state.requests.drain(..)
+            .chain(session.waiting)
+            .chain(session.retrying.into_iter().map(|(_, req)| req))
+            .chain(session.active.into_values().map(|(req, _, _)| req));
+        for req in dropped {
+            if let RequestKind::Fetch { path, .. } | RequestKind::Index { path, .. } = &req.kind {
+                let _ = fs::remove_file(partial_path(path));
             }
-            else if response_code >= 400 {
-                println!("got error {} on request for package {} version {}", response_code, pkg, ver);
+        }
+        // what finished before the cancel still counts
+        if session.total > 0 {
+            self.verify_cache.record(&self.async_state.results[session.first_result..]);
+        }
+        if output::json() {
+            output::emit("cancelled", &json!({ "cancelled": queued + in_flight, "enqueued": queued, "submitted": in_flight }));
+        } else {
+            println!("cancelled {} requests ({} enqueued, {} submitted)", queued + in_flight, queued, in_flight);
+        }
+    }
+
+    /// Enqueued requests plus submitted requests that haven't finished.
+    pub fn pending_requests(&self) -> usize {
+        self.async_state.requests.len() + self.async_state.session.pending()
+    }
+
+    // once everything submitted has finished, remember the answers in the verification cache and start afresh
+    fn finish_session_if_idle(&mut self) {
+        let session = &self.async_state.session;
+        if session.pending() > 0 || session.total == 0 {
+            return;
+        }
+        let first = session.first_result;
+        self.async_state.session = Session::default();
+        self.verify_cache.record(&self.async_state.results[first..]);
+    }
+}
+
+/// Requests that have been submitted to curl and haven't finished yet.
+#[derive(Default)]
+struct Session {
+    waiting : VecDeque<PendingRequest>,
+    // requests waiting out their backoff, and when they can go again
+    retrying : Vec<(Instant, PendingRequest)>,
+    active : HashMap<usize, (PendingRequest, Instant, Easy2Handle<Collector>)>,
+    next_token : usize,
+    // quorum requests that have finished, by group, until the whole group has
+    groups : HashMap<usize, Vec<VerifyResult>>,
+    // requests submitted and finished since the session started, for the progress counter
+    total : usize,
+    done : usize,
+    progress : bool,
+    // index in AsyncState::results of the session's first result
+    first_result : usize,
+}
+
+impl Session {
+    fn pending(&self) -> usize {
+        self.waiting.len() + self.retrying.len() + self.active.len()
+    }
+}
+
+impl AsyncState {
+    // starts what can be started, then reports the requests that curl has finished
+    fn poll_once(&mut self) {
+        // requests whose backoff has expired go ahead of the ones that haven't been tried yet
+        let now = Instant::now();
+        let (ready, later) : (Vec<_>, Vec<_>) = std::mem::take(&mut self.session.retrying).into_iter().partition(|(at, _)| *at <= now);
+        self.session.retrying = later;
+        for (_, req) in ready.into_iter().rev() {
+            self.session.waiting.push_front(req);
+        }
+        while self.session.active.len() < self.max_connections {
+            let mut req = match self.session.waiting.pop_front() {
+                None => break,
+                Some(r) => r
+            };
+            req.attempts += 1;
+            let token = self.session.next_token;
+            self.session.next_token += 1;
+            match self.start(&req, token) {
+                Ok(handle) => { self.session.active.insert(token, (req, Instant::now(), handle)); }
+                Err(e) => {
+                    self.session.done += 1;
+                    error!("could not start request for {}: {}", req.describe(), e);
+                }
+            }
+        }
+        if self.session.active.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.multi.perform() {
+            self.abort_active(&e);
+            return;
+        }
+        let mut finished = vec![];
+        self.multi.messages(|msg| {
+            if let (Ok(token), Some(result)) = (msg.token(), msg.result()) {
+                finished.push((token, result));
+            }
+        });
+        for (token, result) in finished {
+            let (req, started, handle) = self.session.active.remove(&token).unwrap();
+            let latency = started.elapsed().as_millis() as u64;
+            let mut easy = self.multi.remove2(handle).unwrap();
+            let attempt = match result {
+                Ok(()) => {
+                    let filetime = easy.filetime().ok().flatten();
+                    let mut collector = std::mem::take(easy.get_mut());
+                    collector.filetime = filetime;
+                    Attempt::Response(easy.response_code().unwrap(), collector)
+                }
+                Err(e) => Attempt::Failed(e.description().to_string())
+            };
+            self.finish_attempt(req, attempt, latency);
+        }
+    }
+
+    // ends every request in flight with the multi handle's error
+    fn abort_active(&mut self, e: &curl::MultiError) {
+        for (_, (req, started, handle)) in std::mem::take(&mut self.session.active) {
+            let _ = self.multi.remove2(handle);
+            let latency = started.elapsed().as_millis() as u64;
+            self.finish_attempt(req, Attempt::Aborted(format!("curl multi error: {}", e.description())), latency);
+        }
+    }
+
+    // moves req on to the next server or a retry if the attempt failed in a way that might not last;
+    // otherwise reports its final result
+    fn finish_attempt(&mut self, mut req: PendingRequest, attempt: Attempt, latency: u64) {
+        let retryable = match &attempt {
+            Attempt::Response(code, _) => *code >= 500,
+            Attempt::Failed(_) => true,
+            Attempt::Aborted(_) => false
+        };
+        let reason = match &attempt {
+            Attempt::Response(code, _) => format!("got error {}", code),
+            Attempt::Failed(e) | Attempt::Aborted(e) => e.clone()
+        };
+        if retryable && req.target + 1 < req.targets.len() {
+            output::status(&format!("trying next server for {} ({} from {})", req.describe(), reason, req.server()));
+            req.target += 1;
+            self.session.waiting.push_front(req);
+            return;
+        }
+        if retryable && req.rounds <= self.retries {
+            let delay = self.backoff_for(req.rounds);
+            output::status(&format!("retrying request for {} in {} ms ({})", req.describe(), delay.as_millis(), reason));
+            req.rounds += 1;
+            req.target = 0;
+            self.session.retrying.push((Instant::now() + delay, req));
+            return;
+        }
+        self.session.done += 1;
+        if self.session.progress && !output::json() {
+            print!("[{}/{}] ", self.session.done, self.session.total);
+        }
+        match req.kind {
+            RequestKind::Fetch { .. } => return finish_fetch(req, attempt),
+            RequestKind::Index { .. } => return finish_index(req, attempt),
+            RequestKind::Verify { .. } => {}
+        }
+        let group = req.group;
+        let result = finish(req, attempt, latency);
+        result.print();
+        if result.failed() {
+            crate::note_failure();
+        }
+        if let Some((id, size)) = group {
+            let members = self.session.groups.entry(id).or_default();
+            members.push(result.clone());
+            if members.len() == size {
+                report_quorum(&self.session.groups.remove(&id).unwrap());
             }
         }
+        self.results.push(result);
+    }
+
+    // blocks until a socket is ready, a retry is due or a second has passed
+    fn wait_for_activity(&mut self) {
+        let next_retry = self.session.retrying.iter().map(|(at, _)| at.saturating_duration_since(Instant::now())).min();
+        if self.session.active.is_empty() {
+            if let Some(delay) = next_retry {
+                std::thread::sleep(delay);
+            }
+            return;
+        }
+        let wait = next_retry.unwrap_or(self.timeout).min(Duration::from_secs(1));
+        if let Err(e) = self.multi.wait(&mut [], wait) {
+            self.abort_active(&e);
+        }
+    }
+}
+
+// Compares the answers of the servers in a quorum group with each other and with the local checksum.
+fn report_quorum(results: &[VerifyResult]) {
+    let (package, version) = (&results[0].package, &results[0].version);
+    let answers : Vec<(&str, &str)> = results.iter()
+        .filter_map(|r| r.received.as_deref().filter(|_| r.http_status == Some(200)).map(|d| (r.server.as_str(), d)))
+        .collect();
+    let agree = !answers.is_empty() && answers.iter().all(|(_, d)| d.eq_ignore_ascii_case(answers[0].1));
+    let matches = match &results[0].expected {
+        Some(expected) if agree => Some(expected.eq_ignore_ascii_case(answers[0].1)),
+        _ => None
+    };
+    if !agree || matches == Some(false) {
+        crate::note_failure();
+    }
+    if output::json() {
+        let answers : Vec<serde_json::Value> = answers.iter().map(|(s, d)| json!({ "server": s, "checksum": d })).collect();
+        output::emit("quorum", &json!({
+            "package": package, "version": version, "servers": results.len(), "answers": answers, "agree": agree, "matches": matches,
+        }));
+        return;
+    }
+    if answers.is_empty() {
+        println!("quorum for package {} version {}: no server answered", package, version);
+        return;
+    }
+    if !agree {
+        let listed : Vec<String> = answers.iter().map(|(s, d)| format!("{} says {}", s, d)).collect();
+        println!("Warning: servers disagree on package {} version {}: {}", package, version, listed.join(", "));
+        return;
+    }
+    let agreed = format!("{}/{} servers agree", answers.len(), results.len());
+    match matches {
+        None => println!("quorum for package {} version {}: {}, no local checksum", package, version, agreed),
+        Some(true) => println!("quorum for package {} version {}: {}, matches: true", package, version, agreed),
+        Some(false) => println!("Warning: quorum for package {} version {}: {}, matches: false", package, version, agreed)
+    }
+}
+
+// the result of a request that the verification cache answered, compared with today's local checksum
+fn cached_result(record: &VerifyRecord, url: String, algorithm: HashAlgorithm, expected: Option<Checksum>) -> VerifyResult {
+    let mut result = VerifyResult {
+        package : record.package.clone(),
+        version : record.version.clone(),
+        url,
+        server : record.server.clone(),
+        algorithm : algorithm.to_string(),
+        outcome : Outcome::NoLocalChecksum,
+        http_status : Some(200),
+        expected : expected.as_ref().map(|c| c.to_string()),
+        received : Some(record.received.clone()),
+        matches : None,
+        error : None,
+        latency_ms : 0,
+        total_ms : 0,
+        retries : 0,
+        cached_at : Some(record.timestamp),
+        received_at : record.timestamp,
+    };
+    if let (Ok(r), Some(expected)) = (Checksum::from_hex(algorithm, &record.received), &expected) {
+        result.matches = Some(r == *expected);
+        result.outcome = if r == *expected { Outcome::Match } else { Outcome::Mismatch };
+    }
+    result
+}
+
+// the final result of a request, from its last attempt
+fn finish(req: PendingRequest, attempt: Attempt, latency: u64) -> VerifyResult {
+    let (url, server) = (req.url().to_string(), req.server().to_string());
+    let (algorithm, expected) = match req.kind {
+        RequestKind::Verify { algorithm, expected } => (algorithm, expected),
+        RequestKind::Fetch { .. } | RequestKind::Index { .. } => unreachable!()
+    };
+    let mut result = VerifyResult {
+        package : req.package,
+        version : req.version,
+        url,
+        server,
+        algorithm : algorithm.to_string(),
+        outcome : Outcome::HttpError,
+        http_status : None,
+        expected : expected.as_ref().map(|c| c.to_string()),
+        received : None,
+        matches : None,
+        error : None,
+        latency_ms : latency,
+        total_ms : req.enqueued.elapsed().as_millis() as u64,
+        retries : req.attempts.saturating_sub(1),
+        cached_at : None,
+        received_at : now_secs(),
+    };
+    match attempt {
+        Attempt::Response(code, collector) => {
+            let body = collector.body;
+            result.http_status = Some(code);
+            if code == 200 {
+                result.received = Some(body.trim().to_string());
+                result.outcome = match (Checksum::from_hex(algorithm, &body), &expected) {
+                    (Err(e), _) => { result.error = Some(e); Outcome::BadResponse }
+                    (_, None) => Outcome::NoLocalChecksum,
+                    (Ok(r), Some(expected)) => {
+                        result.matches = Some(r == *expected);
+                        if r == *expected { Outcome::Match } else { Outcome::Mismatch }
+                    }
+                };
+            }
+        }
+        Attempt::Failed(e) | Attempt::Aborted(e) => {
+            result.outcome = Outcome::TransportError;
+            result.error = Some(e);
+        }
+    }
+    result
+}
+
+// Checks a finished download against the Packages file and moves it into place, or deletes it.
+fn finish_fetch(req: PendingRequest, attempt: Attempt) {
+    let (path, size, expected) = match req.kind {
+        RequestKind::Fetch { path, size, expected } => (path, size, expected),
+        _ => unreachable!()
+    };
+    let partial = partial_path(&path);
+    let (code, collector) = match attempt {
+        Attempt::Failed(e) | Attempt::Aborted(e) => {
+            let _ = fs::remove_file(&partial);
+            output::failure(&format!("download of package {} version {} failed: {}", req.package, req.version, e));
+            return;
+        }
+        Attempt::Response(code, collector) => (code, collector)
+    };
+    let (file, hasher) = *collector.download.unwrap();
+    drop(file);
+    if code != 200 {
+        let _ = fs::remove_file(&partial);
+        output::failure(&format!("got error {} on download of package {} version {}", code, req.package, req.version));
+        return;
+    }
+    let (actual_size, actual) = (hasher.size(), hasher.finish());
+    if let Some(problem) = check_download(actual_size, &actual, size, &expected) {
+        let _ = fs::remove_file(&partial);
+        output::failure(&format!("rejected download of package {} version {}: {}", req.package, req.version, problem));
+        return;
+    }
+    if let Err(e) = fs::rename(&partial, &path) {
+        output::failure(&format!("could not move {} to {}: {}", partial.display(), path.display(), e));
+        return;
+    }
+    let checked : Vec<String> = expected.iter().map(|c| c.algorithm().to_string()).collect();
+    if output::json() {
+        output::emit("fetch", &json!({ "package": req.package, "version": req.version, "path": path, "size": actual_size, "checked": checked }));
+    } else {
+        println!("fetched {} version {} to {} ({} bytes, {} ok)", req.package, req.version, path.display(), actual_size, checked.join(", "));
+    }
+}
+
+fn finish_index(req: PendingRequest, attempt: Attempt) {
+    let path = match &req.kind {
+        RequestKind::Index { path, .. } => path,
+        _ => unreachable!()
+    };
+    let partial = partial_path(path);
+    let (code, collector) = match attempt {
+        Attempt::Response(code, collector) => (code, collector),
+        Attempt::Failed(e) | Attempt::Aborted(e) => {
+            let _ = fs::remove_file(&partial);
+            output::failure(&format!("download of {} failed: {}{}", req.url(), e, keeping(path)));
+            return;
+        }
+    };
+    let (file, hasher) = *collector.download.unwrap();
+    drop(file);
+    match code {
+        200 => {}
+        304 => {
+            let _ = fs::remove_file(&partial);
+            if output::json() {
+                output::emit("index", &json!({ "url": req.url(), "path": path, "modified": false }));
+            } else {
+                println!("{} not modified", req.url());
+            }
+            return;
+        }
+        _ => {
+            let _ = fs::remove_file(&partial);
+            output::failure(&format!("got error {} on download of {}{}", code, req.url(), keeping(path)));
+            return;
+        }
+    }
+    if let Err(e) = fs::rename(&partial, path) {
+        output::failure(&format!("could not move {} to {}: {}", partial.display(), path.display(), e));
+        return;
+    }
+    // the next update asks If-Modified-Since the server's own time for the file
+    if let Some(secs) = collector.filetime.filter(|t| *t >= 0) {
+        let mtime = UNIX_EPOCH + Duration::from_secs(secs as u64);
+        let _ = File::options().write(true).open(path).and_then(|f| f.set_modified(mtime));
+    }
+    if output::json() {
+        output::emit("index", &json!({ "url": req.url(), "path": path, "modified": true, "size": hasher.size() }));
+    } else {
+        println!("downloaded {} to {} ({} bytes)", req.url(), path.display(), hasher.size());
+    }
+}
+
+// what happens when an index couldn't be downloaded
+fn keeping(path: &Path) -> &'static str {
+    if path.exists() { "; keeping the previous copy" } else { "" }
+}
+
+/// Compares a file's size and checksums with what the Packages file says; returns what doesn't match.
+pub(crate) fn check_download(actual_size: u64, actual: &Checksums, size: Option<u64>, expected: &Checksums) -> Option<String> {
+    if let Some(size) = size {
+        if size != actual_size {
+            return Some(format!("size {} does not match {}", actual_size, size));
+        }
+    }
+    for sum in expected.iter() {
+        let got = actual.get(sum.algorithm()).unwrap();
+        if got != sum {
+            return Some(format!("{} {} does not match {}", sum.algorithm().field_name(), got, sum));
+        }
     }
+    None
 }
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::time::UNIX_EPOCH;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use rpkg::checksums::{Checksum, Checksums, HashAlgorithm};
+use rpkg::debversion::{self, DebianVersionNum, VersionRelation};
+
+use crate::Packages;
+use crate::packages::{Dependency, PackageDetails, PackageFile, RelVersionedPackageNum};
+use crate::packages::{compression, output};
+use crate::packages::release::{Release, ReleaseEntry};
+
+const CACHE_MAGIC : &[u8; 8] = b"RPKGCACH";
+const CACHE_FORMAT_VERSION : u32 = 8;
+
+/// Which loader produced a source file; used to re-parse it when a cache goes stale.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Packages,
+    Installed,
+    Csv,
+    Release,
+}
+
+/// A file that contributed to the current database, with its fingerprint from when it was parsed.
+#[derive(Clone)]
+pub struct SourceFile {
+    pub kind : SourceKind,
+    pub path : String,
+    fingerprint : Fingerprint,
+    /// whether the packages it defines were authenticated when it was parsed; a Release file and the status file
+    /// count as authenticated, since whether they are trusted only matters through the indexes they describe
+    authenticated : bool,
+}
+
+/// Modification time, size and content hash of a source file at the time it was parsed.
+#[derive(Clone)]
+pub struct Fingerprint {
+    mtime_secs : u64,
+    mtime_nanos : u32,
+    size : u64,
+    sha256 : [u8; 32],
+}
+
+impl Fingerprint {
+    pub(crate) fn of(path: &str) -> io::Result<Fingerprint> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 { break; }
+            hasher.update(&buf[..n]);
+        }
+        Ok(Fingerprint {
+            mtime_secs : mtime.as_secs(),
+            mtime_nanos : mtime.subsec_nanos(),
+            size : metadata.len(),
+            sha256 : hasher.finalize().into(),
+        })
+    }
+
+    /// Reads path into memory and fingerprints those bytes, so that whatever is parsed from them is what the
+    /// fingerprint describes.
+    pub(crate) fn read(path: &str) -> io::Result<(Fingerprint, Vec<u8>)> {
+        // the modification time comes first, so that a write during the read makes the fingerprint stale
+        let mtime = fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let bytes = fs::read(path)?;
+        let fingerprint = Fingerprint {
+            mtime_secs : mtime.as_secs(),
+            mtime_nanos : mtime.subsec_nanos(),
+            size : bytes.len() as u64,
+            sha256 : Sha256::digest(&bytes).into(),
+        };
+        Ok((fingerprint, bytes))
+    }
+
+    // Whether path still has this content. An unchanged modification time and size is taken as unchanged content,
+    // as make does; only a file that was touched without changing size gets hashed again.
+    fn still_matches(&self, path: &str) -> bool {
+        let metadata = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => return false
+        };
+        if metadata.len() != self.size {
+            return false;
+        }
+        let mtime = metadata.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        if mtime.as_secs() == self.mtime_secs && mtime.subsec_nanos() == self.mtime_nanos {
+            return true;
+        }
+        Fingerprint::of(path).map(|now| now.size == self.size && now.sha256 == self.sha256).unwrap_or(false)
+    }
+}
+
+impl SourceKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            SourceKind::Packages => 0,
+            SourceKind::Installed => 1,
+            SourceKind::Csv => 2,
+            SourceKind::Release => 3,
+        }
+    }
+
+    fn from_byte(b: u8) -> io::Result<SourceKind> {
+        match b {
+            0 => Ok(SourceKind::Packages),
+            1 => Ok(SourceKind::Installed),
+            2 => Ok(SourceKind::Csv),
+            3 => Ok(SourceKind::Release),
+            _ => Err(invalid_data("bad source kind")),
+        }
+    }
+}
+
+fn relation_to_byte(rel: &VersionRelation) -> u8 {
+    match rel {
+        VersionRelation::StrictlyLess => 1,
+        VersionRelation::LessOrEqual => 2,
+        VersionRelation::Equal => 3,
+        VersionRelation::GreaterOrEqual => 4,
+        VersionRelation::StrictlyGreater => 5,
+    }
+}
+
+fn relation_from_byte(b: u8) -> io::Result<VersionRelation> {
+    match b {
+        1 => Ok(VersionRelation::StrictlyLess),
+        2 => Ok(VersionRelation::LessOrEqual),
+        3 => Ok(VersionRelation::Equal),
+        4 => Ok(VersionRelation::GreaterOrEqual),
+        5 => Ok(VersionRelation::StrictlyGreater),
+        _ => Err(invalid_data("bad version relation")),
+    }
+}
+
+fn algorithm_to_byte(algorithm: HashAlgorithm) -> u8 {
+    match algorithm {
+        HashAlgorithm::Md5 => 1,
+        HashAlgorithm::Sha256 => 2,
+        HashAlgorithm::Sha512 => 3,
+    }
+}
+
+fn algorithm_from_byte(b: u8) -> io::Result<HashAlgorithm> {
+    match b {
+        1 => Ok(HashAlgorithm::Md5),
+        2 => Ok(HashAlgorithm::Sha256),
+        3 => Ok(HashAlgorithm::Sha512),
+        _ => Err(invalid_data("bad hash algorithm")),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// little-endian, length-prefixed encoding of the database
+struct CacheWriter<W: Write> {
+    out : W,
+}
+
+impl<W: Write> CacheWriter<W> {
+    fn u8(&mut self, v: u8) -> io::Result<()> { self.out.write_all(&[v]) }
+    fn u32(&mut self, v: u32) -> io::Result<()> { self.out.write_all(&v.to_le_bytes()) }
+    fn u64(&mut self, v: u64) -> io::Result<()> { self.out.write_all(&v.to_le_bytes()) }
+    fn i32(&mut self, v: i32) -> io::Result<()> { self.out.write_all(&v.to_le_bytes()) }
+    fn len(&mut self, v: usize) -> io::Result<()> { self.u32(v as u32) }
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.len(s.len())?;
+        self.out.write_all(s.as_bytes())
+    }
+    // a presence byte, then the string
+    fn opt_str(&mut self, s: Option<&str>) -> io::Result<()> {
+        match s {
+            None => self.u8(0),
+            Some(s) => { self.u8(1)?; self.str(s) }
+        }
+    }
+    fn strs(&mut self, v: &[String]) -> io::Result<()> {
+        self.len(v.len())?;
+        v.iter().try_for_each(|s| self.str(s))
+    }
+    fn checksums(&mut self, sums: &Checksums) -> io::Result<()> {
+        self.len(sums.iter().count())?;
+        for sum in sums.iter() {
+            self.u8(algorithm_to_byte(sum.algorithm()))?;
+            self.out.write_all(sum.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+struct CacheReader<'a> {
+    buf : &'a [u8],
+}
+
+impl<'a> CacheReader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("truncated cache file"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> io::Result<u8> { Ok(self.take(1)?[0]) }
+    fn u32(&mut self) -> io::Result<u32> { Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap())) }
+    fn u64(&mut self) -> io::Result<u64> { Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap())) }
+    fn i32(&mut self) -> io::Result<i32> { Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap())) }
+    fn len(&mut self) -> io::Result<usize> { Ok(self.u32()? as usize) }
+    fn str(&mut self) -> io::Result<&'a str> {
+        let n = self.len()?;
+        std::str::from_utf8(self.take(n)?).map_err(|_| invalid_data("bad utf-8 in cache file"))
+    }
+    fn opt_str(&mut self) -> io::Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.str()?.to_string()))
+        }
+    }
+    fn strs(&mut self) -> io::Result<Vec<String>> {
+        (0..self.len()?).map(|_| self.str().map(String::from)).collect()
+    }
+    fn checksums(&mut self) -> io::Result<Checksums> {
+        let mut sums = Checksums::new();
+        for _ in 0..self.len()? {
+            let algorithm = algorithm_from_byte(self.u8()?)?;
+            let bytes = self.take(algorithm.digest_len())?;
+            sums.insert(Checksum::from_bytes(algorithm, bytes).map_err(|e| invalid_data(&e))?);
+        }
+        Ok(sums)
+    }
+    // DebianVersionNum accepts any string, so check that this looks like a version before it gets compared
+    fn debver(&mut self) -> io::Result<DebianVersionNum> {
+        let s = self.str()?;
+        let (epoch, rest) = s.split_once(':').unwrap_or(("0", s));
+        if rest.is_empty() || !epoch.bytes().all(|b| b.is_ascii_digit()) || epoch.is_empty()
+            || !rest.bytes().all(|b| b.is_ascii_alphanumeric() || b".+~:-".contains(&b)) {
+            return Err(invalid_data(&format!("bad version {:?} in cache file", s)));
+        }
+        s.parse::<debversion::DebianVersionNum>().map_err(|e| invalid_data(&e.to_string()))
+    }
+}
+
+impl Packages {
+    /// Notes that path was loaded; fingerprint is taken before parsing it, so that a later edit makes the cache stale.
+    pub(crate) fn record_source(&mut self, kind: SourceKind, path: &str, fingerprint: Fingerprint, authenticated: bool) {
+        match self.sources.iter_mut().find(|s| s.kind == kind && s.path == path) {
+            Some(src) => { src.fingerprint = fingerprint; src.authenticated = authenticated; }
+            None => self.sources.push(SourceFile { kind, path: path.to_string(), fingerprint, authenticated }),
+        }
+    }
+
+    /// Writes the interned database (names, versions, relations, checksums, .deb file names, descriptive fields, and
+    /// the Release files that packages came from) to fname, along with the modification time, size and SHA256 of every
+    /// file it was loaded from, as they were when the file was parsed. A .gz or .xz extension compresses the cache.
+    pub fn save_cache(&self, fname: &str) {
+        match self.write_cache(fname) {
+            Ok(()) if output::json() => output::emit("save-cache", &json!({ "path": fname, "packages": self.package_name_to_num.len() })),
+            Ok(()) => println!("Cache written to {} ({} packages)", fname, self.package_name_to_num.len()),
+            Err(e) => error!("could not write cache {}: {}", fname, e),
+        }
+    }
+
+    /// Loads a cache written by save_cache. If any of the files that the cache was built from has changed
+    /// since it was parsed, the cache is discarded, the source files are parsed again, and the cache is rewritten.
+    /// A cache holding packages that weren't authenticated when they were parsed is refused, as those files would
+    /// be, unless unverified data is allowed.
+    pub fn load_cache(&mut self, fname: &str) {
+        let bytes = match compression::read(fname) {
+            Ok(b) => b,
+            Err(e) => { error!("could not read cache {}: {}", fname, e); return }
+        };
+        let mut rdr = CacheReader { buf: &bytes };
+        let sources = match read_header(&mut rdr) {
+            Ok(s) => s,
+            Err(e) => { error!("bad cache file {}: {}", fname, e); return }
+        };
+
+        let stale : Vec<&SourceFile> = sources.iter().filter(|src| !src.fingerprint.still_matches(&src.path)).collect();
+        if !stale.is_empty() {
+            for src in &stale {
+                output::status(&format!("cache {} is stale: {} changed", fname, src.path));
+            }
+            for src in &sources {
+                match src.kind {
+                    SourceKind::Packages => self.parse_packages(&src.path),
+                    SourceKind::Installed => self.parse_installed(&src.path),
+                    SourceKind::Csv => self.parse_csv(&src.path),
+                    SourceKind::Release => self.parse_release(&src.path),
+                }
+            }
+            self.save_cache(fname);
+            return;
+        }
+
+        for src in sources.iter().filter(|src| !src.authenticated) {
+            if self.check_unverified(&format!("cache {}", fname), &format!("{} was not authenticated", src.path)).is_err() {
+                return;
+            }
+        }
+        match read_cache_body(&mut rdr) {
+            Ok(body) => self.merge_cache_body(body),
+            Err(e) => { error!("bad cache file {}: {}", fname, e); return }
+        }
+        for src in sources {
+            self.record_source(src.kind, &src.path, src.fingerprint, src.authenticated);
+        }
+        self.print_loaded(fname, true, true);
+    }
+
+    fn write_cache(&self, fname: &str) -> io::Result<()> {
+        let mut w = CacheWriter { out: compression::create(fname)? };
+        w.out.write_all(CACHE_MAGIC)?;
+        w.u32(CACHE_FORMAT_VERSION)?;
+
+        w.len(self.sources.len())?;
+        for src in &self.sources {
+            let fp = &src.fingerprint;
+            w.u8(src.kind.to_byte())?;
+            w.u8(src.authenticated as u8)?;
+            w.str(&src.path)?;
+            w.u64(fp.mtime_secs)?;
+            w.u32(fp.mtime_nanos)?;
+            w.u64(fp.size)?;
+            w.out.write_all(&fp.sha256)?;
+        }
+
+        w.len(self.package_num_to_name.len())?;
+        for (num, name) in &self.package_num_to_name {
+            w.i32(*num)?;
+            w.str(name)?;
+        }
+        for debvers in [&self.available_debvers, &self.installed_debvers] {
+            w.len(debvers.len())?;
+            for (num, ver) in debvers {
+                w.i32(*num)?;
+                w.str(&ver.to_string())?;
+            }
+        }
+        w.len(self.checksums.len())?;
+        for (num, sums) in &self.checksums {
+            w.i32(*num)?;
+            w.checksums(sums)?;
+        }
+        w.len(self.package_files.len())?;
+        for (num, file) in &self.package_files {
+            w.i32(*num)?;
+            w.str(&file.filename)?;
+            // Size is optional; u64::MAX stands for a missing one
+            w.u64(file.size.unwrap_or(u64::MAX))?;
+        }
+        w.len(self.details.len())?;
+        for (num, details) in &self.details {
+            w.i32(*num)?;
+            for field in [&details.section, &details.priority, &details.description] {
+                w.opt_str(field.as_deref())?;
+            }
+        }
+        w.len(self.installed_stanzas.len())?;
+        for (num, stanzas) in &self.installed_stanzas {
+            w.i32(*num)?;
+            w.len(stanzas.len())?;
+            for text in stanzas {
+                w.str(text)?;
+            }
+        }
+        w.len(self.dependencies.len())?;
+        for (num, deps) in &self.dependencies {
+            w.i32(*num)?;
+            w.len(deps.len())?;
+            for dep in deps {
+                w.len(dep.len())?;
+                for alt in dep {
+                    w.i32(alt.package_num)?;
+                    match &alt.rel_version {
+                        None => w.u8(0)?,
+                        Some((rel, ver)) => { w.u8(relation_to_byte(rel))?; w.str(ver)?; }
+                    }
+                }
+            }
+        }
+        // the Release files that indexes were checked against, so that packages keep their Origin and Suite
+        w.len(self.releases.len())?;
+        for release in &self.releases {
+            w.str(&release.path)?;
+            for field in [&release.origin, &release.suite, &release.codename, &release.signed_by] {
+                w.opt_str(field.as_deref())?;
+            }
+            w.strs(&release.architectures)?;
+            w.strs(&release.components)?;
+            w.len(release.files.len())?;
+            for (name, entry) in &release.files {
+                w.str(name)?;
+                w.u64(entry.size)?;
+                w.checksums(&entry.checksums)?;
+            }
+        }
+        w.len(self.package_releases.len())?;
+        for (num, idx) in &self.package_releases {
+            w.i32(*num)?;
+            w.len(*idx)?;
+        }
+        w.out.finish()
+    }
+
+    // Adds a decoded cache body to the database. Package numbers in the cache are local to the cache; they are
+    // remapped through get_package_num_inserting, and release numbers through the releases as merged.
+    fn merge_cache_body(&mut self, body: CacheBody) {
+        let nums : HashMap<i32, i32> = body.names.iter().map(|(cached, name)| (*cached, self.get_package_num_inserting(name))).collect();
+        for (num, ver) in body.available { self.available_debvers.insert(nums[&num], ver); }
+        for (num, ver) in body.installed { self.installed_debvers.insert(nums[&num], ver); }
+        for (num, sums) in body.checksums { self.checksums.insert(nums[&num], sums); }
+        for (num, file) in body.package_files { self.package_files.insert(nums[&num], file); }
+        for (num, details) in body.details { self.details.insert(nums[&num], details); }
+        for (num, stanzas) in body.installed_stanzas { self.installed_stanzas.insert(nums[&num], stanzas); }
+        for (num, mut deps) in body.dependencies {
+            for alt in deps.iter_mut().flatten() {
+                alt.package_num = nums[&alt.package_num];
+            }
+            self.dependencies.insert(nums[&num], deps);
+        }
+        // a release already loaded from the same path is replaced
+        let release_idx : Vec<usize> = body.releases.into_iter().map(|release| {
+            match self.releases.iter().position(|r| r.path == release.path) {
+                Some(i) => { self.releases[i] = release; i }
+                None => { self.releases.push(release); self.releases.len() - 1 }
+            }
+        }).collect();
+        for (num, idx) in body.package_releases { self.package_releases.insert(nums[&num], release_idx[idx]); }
+    }
+}
+
+/// The tables of a cache file, decoded in full before any of it goes into the database, so that a truncated
+/// or corrupt cache leaves the database as it was. Package and release numbers are still the cache's own.
+struct CacheBody {
+    names : HashMap<i32, String>,
+    available : Vec<(i32, DebianVersionNum)>,
+    installed : Vec<(i32, DebianVersionNum)>,
+    checksums : Vec<(i32, Checksums)>,
+    package_files : Vec<(i32, PackageFile)>,
+    details : Vec<(i32, PackageDetails)>,
+    installed_stanzas : Vec<(i32, Vec<String>)>,
+    dependencies : Vec<(i32, Vec<Dependency>)>,
+    releases : Vec<Release>,
+    package_releases : Vec<(i32, usize)>,
+}
+
+// checks that every package and release number refers to an entry of the cache
+fn read_cache_body(rdr: &mut CacheReader) -> io::Result<CacheBody> {
+    let mut names = HashMap::new();
+    for _ in 0..rdr.len()? {
+        let cached_num = rdr.i32()?;
+        names.insert(cached_num, rdr.str()?.to_string());
+    }
+    let known = |n: i32| if names.contains_key(&n) { Ok(n) } else { Err(invalid_data("unknown package number")) };
+
+    let available = read_versions(rdr, &known)?;
+    let installed = read_versions(rdr, &known)?;
+    let mut checksums = vec![];
+    for _ in 0..rdr.len()? {
+        checksums.push((known(rdr.i32()?)?, rdr.checksums()?));
+    }
+    let mut package_files = vec![];
+    for _ in 0..rdr.len()? {
+        let num = known(rdr.i32()?)?;
+        let filename = rdr.str()?.to_string();
+        let size = Some(rdr.u64()?).filter(|s| *s != u64::MAX);
+        package_files.push((num, PackageFile { filename, size }));
+    }
+    let mut details = vec![];
+    for _ in 0..rdr.len()? {
+        let num = known(rdr.i32()?)?;
+        let (section, priority, description) = (rdr.opt_str()?, rdr.opt_str()?, rdr.opt_str()?);
+        details.push((num, PackageDetails { section, priority, description }));
+    }
+    let mut installed_stanzas = vec![];
+    for _ in 0..rdr.len()? {
+        installed_stanzas.push((known(rdr.i32()?)?, rdr.strs()?));
+    }
+    let mut dependencies = vec![];
+    for _ in 0..rdr.len()? {
+        let num = known(rdr.i32()?)?;
+        let n_deps = rdr.len()?;
+        let mut deps : Vec<Dependency> = Vec::with_capacity(n_deps);
+        for _ in 0..n_deps {
+            let n_alts = rdr.len()?;
+            let mut dep : Dependency = Vec::with_capacity(n_alts);
+            for _ in 0..n_alts {
+                let package_num = known(rdr.i32()?)?;
+                let rel_version = match rdr.u8()? {
+                    0 => None,
+                    b => Some((relation_from_byte(b)?, rdr.str()?.to_string())),
+                };
+                dep.push(RelVersionedPackageNum { package_num, rel_version });
+            }
+            deps.push(dep);
+        }
+        dependencies.push((num, deps));
+    }
+    let mut releases = vec![];
+    for _ in 0..rdr.len()? {
+        let path = rdr.str()?.to_string();
+        let (origin, suite, codename, signed_by) = (rdr.opt_str()?, rdr.opt_str()?, rdr.opt_str()?, rdr.opt_str()?);
+        let (architectures, components) = (rdr.strs()?, rdr.strs()?);
+        let mut files = HashMap::new();
+        for _ in 0..rdr.len()? {
+            let name = rdr.str()?.to_string();
+            let size = rdr.u64()?;
+            files.insert(name, ReleaseEntry { size, checksums: rdr.checksums()? });
+        }
+        releases.push(Release { path, origin, suite, codename, architectures, components, files, signed_by });
+    }
+    let mut package_releases = vec![];
+    for _ in 0..rdr.len()? {
+        let num = known(rdr.i32()?)?;
+        let idx = rdr.len()?;
+        if idx >= releases.len() {
+            return Err(invalid_data("unknown release number"));
+        }
+        package_releases.push((num, idx));
+    }
+    Ok(CacheBody { names, available, installed, checksums, package_files, details, installed_stanzas, dependencies, releases, package_releases })
+}
+
+fn read_versions(rdr: &mut CacheReader, known: &impl Fn(i32) -> io::Result<i32>) -> io::Result<Vec<(i32, DebianVersionNum)>> {
+    (0..rdr.len()?).map(|_| Ok((known(rdr.i32()?)?, rdr.debver()?))).collect()
+}
+
+fn read_header(rdr: &mut CacheReader) -> io::Result<Vec<SourceFile>> {
+    if rdr.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+        return Err(invalid_data("not an rpkg cache"));
+    }
+    let version = rdr.u32()?;
+    if version != CACHE_FORMAT_VERSION {
+        return Err(invalid_data(&format!("unsupported cache format version {}", version)));
+    }
+    let mut sources = vec![];
+    for _ in 0..rdr.len()? {
+        let kind = SourceKind::from_byte(rdr.u8()?)?;
+        let authenticated = rdr.u8()? != 0;
+        let path = rdr.str()?.to_string();
+        let fingerprint = Fingerprint {
+            mtime_secs : rdr.u64()?,
+            mtime_nanos : rdr.u32()?,
+            size : rdr.u64()?,
+            sha256 : rdr.take(32)?.try_into().unwrap(),
+        };
+        sources.push(SourceFile { kind, path, fingerprint, authenticated });
+    }
+    Ok(sources)
+}
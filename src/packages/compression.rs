@@ -0,0 +1,130 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+const GZIP_MAGIC : &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC : &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+/// Detects the compression of a file from its first few bytes, regardless of its name.
+pub fn detect(filename: &str) -> io::Result<Compression> {
+    let mut magic = [0u8; 6];
+    let mut file = File::open(filename)?;
+    let mut n = 0;
+    while n < magic.len() {
+        let read = file.read(&mut magic[n..])?;
+        if read == 0 { break; }
+        n += read;
+    }
+    Ok(detect_bytes(&magic[..n]))
+}
+
+/// Detects the compression of data that starts with a file's first bytes.
+pub fn detect_bytes(data: &[u8]) -> Compression {
+    if data.starts_with(XZ_MAGIC) {
+        Compression::Xz
+    } else if data.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else {
+        Compression::None
+    }
+}
+
+/// Decompresses the contents of a file that has already been read, if they are gzip or xz compressed.
+pub fn decompress(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let mut bytes = vec![];
+    match detect_bytes(data) {
+        Compression::None => return Ok(Cow::Borrowed(data)),
+        Compression::Gzip => MultiGzDecoder::new(data).read_to_end(&mut bytes)?,
+        Compression::Xz => XzDecoder::new_multi_decoder(data).read_to_end(&mut bytes)?,
+    };
+    Ok(Cow::Owned(bytes))
+}
+
+/// Opens filename for reading, decompressing it on the fly if it is gzip or xz compressed.
+pub fn open(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    let compression = detect(filename)?;
+    let file = File::open(filename)?;
+    Ok(match compression {
+        Compression::None => Box::new(BufReader::new(file)),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(file)))),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(BufReader::new(file)))),
+    })
+}
+
+/// Reads all of filename into memory, decompressing it if needed.
+pub fn read(filename: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    open(filename)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A file being written by create. Call finish when done: dropping it instead would write the gzip or xz
+/// trailer, and flush the buffer, without reporting errors.
+pub enum Writer {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Xz(XzEncoder<BufWriter<File>>),
+}
+
+impl Writer {
+    /// Writes whatever the compressor still holds, flushes the file, and reports any error doing so.
+    pub fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Writer::Plain(file) => file,
+            Writer::Gzip(encoder) => encoder.finish()?,
+            Writer::Xz(encoder) => encoder.finish()?,
+        };
+        file.into_inner().map(drop).map_err(|e| e.into_error())
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(file) => file.write(buf),
+            Writer::Gzip(encoder) => encoder.write(buf),
+            Writer::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(file) => file.flush(),
+            Writer::Gzip(encoder) => encoder.flush(),
+            Writer::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Creates filename for writing, compressing according to its extension (.gz or .xz).
+pub fn create(filename: &str) -> io::Result<Writer> {
+    let file = BufWriter::new(File::create(filename)?);
+    Ok(match Path::new(filename).extension().and_then(|e| e.to_str()) {
+        Some("gz") => Writer::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+        Some("xz") => Writer::Xz(XzEncoder::new(file, 6)),
+        _ => Writer::Plain(file),
+    })
+}
+
+/// Finds the file to load for filename: the file itself if it exists, otherwise filename.xz or filename.gz.
+pub fn resolve(filename: &str) -> String {
+    for candidate in [filename.to_string(), format!("{}.xz", filename), format!("{}.gz", filename)] {
+        if Path::new(&candidate).exists() {
+            return candidate;
+        }
+    }
+    filename.to_string()
+}
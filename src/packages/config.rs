@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::Packages;
+use crate::packages::{compression, output};
+
+const SYSTEM_CONFIG : &str = "/etc/rpkg/config.toml";
+
+// what load-defaults loads without a config file, or with one that doesn't say
+const DEFAULT_PACKAGES : &str = "data/mirror.csclub.uwaterloo.ca_debian_dists_sid_main_binary-amd64_Packages";
+const DEFAULT_INSTALLED : &str = "data/installed-packages";
+const DEFAULT_HISTORY : &str = "history.txt";
+
+/// The settings in a config file. Anything left out keeps its built-in default.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// named sources of available packages, loaded by load-defaults in name order
+    sources : Option<BTreeMap<String, Source>>,
+    /// dpkg status file
+    installed : Option<String>,
+    history : Option<String>,
+    lists_dir : Option<String>,
+    servers : Option<Servers>,
+    output : Option<Output>,
+    /// where the config was read from, for messages
+    #[serde(skip)]
+    path : Option<String>,
+}
+
+/// Either a local Packages file, or an index on a mirror that update downloads.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Source {
+    packages : Option<String>,
+    url : Option<String>,
+    suite : Option<String>,
+    component : Option<String>,
+    arch : Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Servers {
+    /// in failover order
+    list : Option<Vec<String>>,
+    /// failover or quorum
+    mode : Option<String>,
+    quorum : Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Output {
+    /// text or json
+    format : Option<String>,
+}
+
+// ~/ at the start of a path is the home directory
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string()
+    }
+}
+
+// $XDG_CONFIG_HOME/rpkg/config.toml, or ~/.config/rpkg/config.toml
+fn user_config() -> Option<String> {
+    let base = env::var("XDG_CONFIG_HOME").ok().filter(|d| !d.is_empty())
+        .or_else(|| env::var("HOME").ok().map(|home| format!("{}/.config", home)))?;
+    Some(format!("{}/rpkg/config.toml", base))
+}
+
+impl Config {
+    /// Reads the config file given with --config, or else the user's, or else the system's. Without any,
+    /// the built-in defaults apply.
+    pub fn load(explicit: Option<&str>) -> Result<Config, String> {
+        let path = match explicit {
+            Some(p) => p.to_string(),
+            None => match user_config().into_iter().chain(Some(SYSTEM_CONFIG.to_string())).find(|p| Path::new(p).is_file()) {
+                Some(p) => p,
+                None => return Ok(Config::default())
+            }
+        };
+        let text = fs::read_to_string(&path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let mut config : Config = toml::from_str(&text).map_err(|e| format!("bad config file {}: {}", path, e))?;
+        for (name, source) in config.sources.iter().flatten() {
+            let mirror = [&source.url, &source.suite, &source.component, &source.arch];
+            match (&source.packages, mirror.iter().filter(|f| f.is_some()).count()) {
+                (Some(_), 0) | (None, 4) => {}
+                _ => return Err(format!("bad config file {}: source {} needs either packages, or url, suite, component and arch", path, name))
+            }
+        }
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    /// Where the REPL keeps its history.
+    pub fn history(&self) -> String {
+        expand_home(self.history.as_deref().unwrap_or(DEFAULT_HISTORY))
+    }
+}
+
+impl Packages {
+    /// Applies the settings that take effect at startup: mirror sources, the lists directory, servers and output.
+    /// The sources and installed file are kept for load-defaults.
+    pub fn apply_config(&mut self, config: Config) {
+        if let Some(dir) = &config.lists_dir {
+            self.set_lists_dir(&expand_home(dir));
+        }
+        for source in config.sources.iter().flat_map(|s| s.values()) {
+            if let (Some(url), Some(suite), Some(component), Some(arch)) = (&source.url, &source.suite, &source.component, &source.arch) {
+                self.add_source(url, suite, component, arch);
+            }
+        }
+        if let Some(servers) = &config.servers {
+            match servers.list.as_deref() {
+                Some([]) => error!("{}: the server list is empty", config.path.as_deref().unwrap_or("config")),
+                Some([first, rest @ ..]) => {
+                    self.set_server(first);
+                    for server in rest {
+                        self.add_server(server);
+                    }
+                }
+                None => {}
+            }
+            if let Some(mode) = &servers.mode {
+                self.set_server_mode(mode, servers.quorum.map(|n| n.to_string()).as_deref());
+            }
+        }
+        if let Some(format) = config.output.as_ref().and_then(|o| o.format.as_deref()) {
+            self.set_output(format);
+        }
+        self.config = config;
+    }
+
+    /// Loads every source and the installed file that the config names, or the built-in defaults.
+    /// Mirror sources are loaded from the copies that update downloaded.
+    pub fn load_defaults(&mut self) {
+        let sources : Vec<(String, Option<String>, Option<[String; 4]>)> = match &self.config.sources {
+            None => vec![(String::from("default"), Some(DEFAULT_PACKAGES.to_string()), None)],
+            Some(sources) => sources.iter().map(|(name, s)| {
+                let mirror = match (&s.url, &s.suite, &s.component, &s.arch) {
+                    (Some(u), Some(s), Some(c), Some(a)) => Some([u.clone(), s.clone(), c.clone(), a.clone()]),
+                    _ => None
+                };
+                (name.clone(), s.packages.clone(), mirror)
+            }).collect()
+        };
+        for (name, packages, mirror) in sources {
+            match (packages, mirror) {
+                (Some(path), _) => {
+                    let path = compression::resolve(&expand_home(&path));
+                    if self.config.sources.is_some() || built_in_present(&path) {
+                        self.parse_packages(&path)
+                    }
+                }
+                (None, Some([url, suite, component, arch])) => self.load_downloaded_index(&name, &url, &suite, &component, &arch),
+                (None, None) => unreachable!()
+            }
+        }
+        let installed = compression::resolve(&expand_home(self.config.installed.as_deref().unwrap_or(DEFAULT_INSTALLED)));
+        if self.config.installed.is_some() || built_in_present(&installed) {
+            self.parse_installed(&installed)
+        }
+    }
+}
+
+// Whether a built-in default file is there; a missing one is skipped with a warning, since nothing asked
+// for it. Files that a config file names are still errors when missing.
+fn built_in_present(path: &str) -> bool {
+    let present = Path::new(path).exists();
+    if !present {
+        output::warning(&format!("skipping default file {}: it does not exist; name your files in a config file", path));
+    }
+    present
+}
@@ -1,19 +1,30 @@
+use serde_json::json;
+
 use rpkg::debversion::{self, DebianVersionNum};
 use crate::Packages;
 use crate::packages::Dependency;
+use crate::packages::output;
 
 impl Packages {
     /// Gets the dependencies of package_name, and prints out whether they are satisfied (and by which library/version) or not.
     pub fn deps_available(&self, package_name: &str) {
         if !self.package_exists(package_name) {
-            println!("no such package {}", package_name);
+            output::no_such_package(package_name);
             return;
         }
-        println!("Package {}:", package_name);
-        // some sort of for loop...
-
         let pkg_num = self.get_package_num(package_name);
         let deps = self.dependencies.get(pkg_num).unwrap();
+        if output::json() {
+            let dependencies : Vec<serde_json::Value> = deps.iter().map(|dep| {
+                let satisfied_by = self.dep_is_satisfied(dep)
+                    .map(|alt| json!({ "package": alt, "installed_version": self.get_installed_debver(alt).unwrap().to_string() }));
+                json!({ "alternatives": self.dep2json(dep), "satisfied_by": satisfied_by })
+            }).collect();
+            output::emit("deps-available", &json!({ "package": package_name, "dependencies": dependencies }));
+            return;
+        }
+        println!("Package {}:", package_name);
+        // some sort of for loop...
 
         // for all dependencies
         for dep in deps
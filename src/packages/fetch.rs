@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+
+use rpkg::checksums::Checksums;
+use rpkg::debversion::DebianVersionNum;
+
+use crate::Packages;
+use crate::packages::output;
+use crate::packages::async_fns::check_download;
+use crate::packages::release::hash_file;
+
+impl Packages {
+    /// Sets the base URL that the Filename fields of Packages files are relative to, e.g. http://deb.debian.org/debian.
+    pub fn set_mirror(&mut self, url: &str) {
+        self.async_state.mirror = url.trim_end_matches('/').to_string();
+    }
+
+    /// Sets the directory that fetched .debs are stored in. The verification cache is kept there too,
+    /// unless set-verify-cache moved it.
+    pub fn set_cache_dir(&mut self, dir: &str) {
+        self.async_state.cache_dir = dir.to_string();
+        self.verify_cache.set_dir(dir);
+    }
+
+    /// Enqueues a download of the .deb of pkg from the mirror into the cache directory; execute() runs it.
+    /// Only the available version has a Filename, so that is the only version that can be fetched.
+    /// The download is hashed as it arrives and rejected unless its size and checksums match the Packages file.
+    pub fn enq_fetch(&mut self, pkg: &str, version: Option<&str>) {
+        let available = match self.get_available_debver(pkg) {
+            None => { error!("package {} not defined.", pkg); return }
+            Some(v) => v
+        };
+        if let Some(version) = version {
+            if version.parse::<DebianVersionNum>().map_or(true, |v| v.cmp(available) != Ordering::Equal) {
+                error!("only the available version {} of {} can be fetched", available, pkg);
+                return;
+            }
+        }
+        let version = available.to_string();
+        let file = match self.package_files.get(self.get_package_num(pkg)) {
+            None => { error!("no Filename known for package {}", pkg); return }
+            Some(f) => f
+        };
+        let expected = self.get_checksums(pkg).cloned().unwrap_or_else(Checksums::new);
+        if expected.is_empty() {
+            error!("no checksums known for package {}; refusing to fetch it", pkg);
+            return;
+        }
+
+        let cache_dir = Path::new(&self.async_state.cache_dir);
+        if let Err(e) = fs::create_dir_all(cache_dir) {
+            error!("could not create {}: {}", cache_dir.display(), e);
+            return;
+        }
+        let basename = file.filename.rsplit('/').next().unwrap();
+        let path = cache_dir.join(basename);
+        // a good copy from an earlier fetch doesn't need downloading again
+        if let Ok((size, sums)) = hash_file(&path.to_string_lossy()) {
+            if check_download(size, &sums, file.size, &expected).is_none() {
+                output::status(&format!("package {} version {} already in cache at {}", pkg, version, path.display()));
+                return;
+            }
+        }
+        let url = format!("{}/{}", self.async_state.mirror, file.filename);
+        let size = file.size;
+        output::status(&format!("queueing download {}", url));
+        self.queue_fetch(pkg, &version, &url, path, size, expected);
+    }
+
+    /// Downloads the .deb of pkg right away, along with anything else enqueued.
+    pub fn fetch(&mut self, pkg: &str, version: Option<&str>) {
+        self.enq_fetch(pkg, version);
+        self.execute();
+    }
+}
@@ -3,27 +3,76 @@ use std::path::Path;
 use std::sync::atomic::{AtomicI32, Ordering};
 
 use itertools::Itertools;
+use serde::Serialize;
+use serde_json::json;
 
 use rpkg::debversion;
 use rpkg::debversion::{DebianVersionNum,VersionRelation};
+use rpkg::checksums::{self, Checksum, Checksums, HashAlgorithm};
 
 mod deps_available;
 mod solvers;
 mod parsers;
 mod async_fns;
+mod cache;
+pub mod compression;
+mod release;
+mod signatures;
+pub mod serve;
+mod report;
+mod verify_bulk;
+mod fetch;
+mod verify_cache;
+mod transport;
+mod update;
+mod search;
+pub mod config;
+pub mod output;
+mod status_file;
+mod transactions;
 
 use crate::packages::async_fns::AsyncState;
+use crate::packages::cache::{Fingerprint, SourceFile, SourceKind};
+use crate::packages::config::Config;
+use crate::packages::release::{Release, ReleaseCheck};
+use crate::packages::signatures::Keyring;
+use crate::packages::transactions::Transactions;
+use crate::packages::update::Repositories;
+use crate::packages::verify_cache::VerifyCache;
 
 static PACKAGE_COUNTER: AtomicI32 = AtomicI32::new(0);
 
 pub struct Packages {
     dependencies : HashMap<i32,Vec<Dependency>>,
-    md5sums : HashMap<i32,String>,
+    checksums : HashMap<i32,Checksums>,
+    package_files : HashMap<i32,PackageFile>,
+    details : HashMap<i32,PackageDetails>,
     available_debvers : HashMap<i32,DebianVersionNum>,
     installed_debvers : HashMap<i32,DebianVersionNum>,
+    // the stanzas of the loaded status files, as written; one per architecture for Multi-Arch packages
+    installed_stanzas : HashMap<i32,Vec<String>>,
     package_name_to_num : HashMap<String, i32>,
     package_num_to_name : HashMap<i32, String>,
+    // bumped whenever a package name is added, so that the completer knows when to pick up the names again
+    names_generation : u64,
     async_state : AsyncState,
+    sources : Vec<SourceFile>,
+    releases : Vec<Release>,
+    package_releases : HashMap<i32, usize>,
+    release_check : ReleaseCheck,
+    keyring : Option<Keyring>,
+    allow_unverified : bool,
+    verify_cache : VerifyCache,
+    repositories : Repositories,
+    config : Config,
+    transactions : Transactions,
+}
+
+// the descriptive fields of a package's stanza, for search
+pub struct PackageDetails {
+    section : Option<String>,
+    priority : Option<String>,
+    description : Option<String>,
 }
 
 // Dependency([X, Y, Z]) means X|Y|Z
@@ -33,16 +82,44 @@ pub struct RelVersionedPackageNum {
 }
 pub type Dependency = Vec<RelVersionedPackageNum>;
 
+// one alternative of a dependency in JSON output; relation and version are null for an unversioned dependency
+#[derive(Serialize)]
+struct Alternative<'a> {
+    package : &'a str,
+    relation : Option<String>,
+    version : Option<&'a str>,
+}
+
+// where the .deb of an available package lives, relative to the mirror (Filename and Size fields)
+pub struct PackageFile {
+    filename : String,
+    size : Option<u64>
+}
+
 impl Packages {
     pub fn new() -> Packages {
         Packages { 
             dependencies : HashMap::new(), 
-            md5sums : HashMap::new(),
+            checksums : HashMap::new(),
+            package_files : HashMap::new(),
+            details : HashMap::new(),
             available_debvers : HashMap::new(),
             installed_debvers : HashMap::new(),
+            installed_stanzas : HashMap::new(),
             package_name_to_num : HashMap::new(), 
             package_num_to_name : HashMap::new(),
+            names_generation : 0,
             async_state : AsyncState::new(),
+            sources : vec![],
+            releases : vec![],
+            package_releases : HashMap::new(),
+            release_check : ReleaseCheck::Refuse,
+            keyring : None,
+            allow_unverified : false,
+            verify_cache : VerifyCache::new(),
+            repositories : Repositories::new(),
+            config : Config::default(),
+            transactions : Transactions::new(),
         }
     }
 
@@ -51,6 +128,11 @@ impl Packages {
         self.package_name_to_num.keys().map(|x| &x[..]).collect()
     }
 
+    /// Changes whenever get_package_names would return something new.
+    pub fn names_generation(&self) -> u64 {
+        self.names_generation
+    }
+
     fn get_package_name(&self, package_num: i32) -> &str {
         return self.package_num_to_name.get(&package_num).unwrap();
     }
@@ -67,6 +149,7 @@ impl Packages {
             self.package_name_to_num.insert(String::from(package_name), pnum);
             self.package_num_to_name.insert(pnum, String::from(package_name));
             self.dependencies.insert(pnum, vec![]);
+            self.names_generation += 1;
             PACKAGE_COUNTER.fetch_add(1, Ordering::SeqCst);
             return pnum;
         } else {
@@ -101,14 +184,11 @@ impl Packages {
         }
     }
 
-    pub fn get_md5sum(&self, package_name: &str) -> Option<&str> {
+    pub fn get_checksums(&self, package_name: &str) -> Option<&Checksums> {
         let package_num = self.package_name_to_num.get(package_name);
         return match package_num {
             None => None,
-            Some(x) => match self.md5sums.get(x) {
-                None => None,
-                Some(y) => Some(y)
-            }
+            Some(x) => self.checksums.get(x)
         }
     }
 
@@ -127,38 +207,67 @@ impl Packages {
         }).format(" | ").to_string();
     }
 
+    fn dep2json<'a>(&'a self, dep: &'a Dependency) -> Vec<Alternative<'a>> {
+        dep.iter().map(|d| Alternative {
+            package : self.get_package_name(d.package_num),
+            relation : d.rel_version.as_ref().map(|(rel, _)| rel.to_string()),
+            version : d.rel_version.as_ref().map(|(_, ver)| ver.as_str()),
+        }).collect()
+    }
+
+    fn names(&self, package_nums: &[i32]) -> Vec<&str> {
+        package_nums.iter().map(|p| self.get_package_name(*p)).collect()
+    }
+
     // output commands
     pub fn print_deps(&self, package_name: &str) {
         if !self.package_exists(package_name) {
-            println!("no such package {}", package_name);
+            output::no_such_package(package_name);
             return;
         }
         let deps : &Vec<Dependency> = &*self.dependencies.get(self.get_package_num(package_name)).unwrap();
+        if output::json() {
+            let depends : Vec<Vec<Alternative>> = deps.iter().map(|d| self.dep2json(d)).collect();
+            output::emit("deps", &json!({ "package": package_name, "depends": depends }));
+            return;
+        }
         println!("{:?} depends on {:?}", package_name, self.deps2str(deps));
     }
 
     pub fn print_transitive_dep_solution(&self, package_name: &str) {
         if !self.package_exists(package_name) {
-            println!("no such package {}", package_name);
+            output::no_such_package(package_name);
             return;
         }
         let dep_solution : Vec<i32> = self.transitive_dep_solution(package_name);
+        if output::json() {
+            output::emit("transitive-dep-solution", &json!({ "package": package_name, "solution": self.names(&dep_solution) }));
+            return;
+        }
         println!("{:?} transitive dependency solution: {:?}", package_name, dep_solution.iter().map(|dep| self.get_package_name(*dep)).format(", ").to_string());
     }
 
     pub fn print_how_to_install(&self, package_name: &str) {
         if !self.package_exists(package_name) {
-            println!("no such package {}", package_name);
+            output::no_such_package(package_name);
             return;
         }
-        println!("Package {}:", package_name);
         let pkgs_to_install:Vec<i32> = self.compute_how_to_install(package_name);
+        if output::json() {
+            output::emit("how-to-install", &json!({ "package": package_name, "install": self.names(&pkgs_to_install) }));
+            return;
+        }
+        println!("Package {}:", package_name);
         println!("{:?} to install: {:?}", package_name, pkgs_to_install.iter().map(|dep| self.get_package_name(*dep)).format(", ").to_string());
     }
 
     pub fn print_info(&self, package_name: &str) {
         if !self.package_exists(package_name) {
-            println!("no such package {}", package_name);
+            output::no_such_package(package_name);
+            return;
+        }
+        if output::json() {
+            self.print_info_json(package_name);
             return;
         }
         println!("Package: {}", package_name);
@@ -168,10 +277,21 @@ impl Packages {
             None => (),
             Some(a) => {
                 println!("Version: {}", a.to_string());
-                println!("MD5Sum: {}", self.get_md5sum(package_name).unwrap().to_string());
+                if let Some(sums) = self.get_checksums(package_name) {
+                    for sum in sums.iter() {
+                        match sum.algorithm() {
+                            HashAlgorithm::Md5 => println!("MD5Sum: {}", sum),
+                            _ => println!("{}: {}", sum.algorithm().field_name(), sum)
+                        }
+                    }
+                }
                 println!("Depends: {}", self.deps2str(&*self.dependencies.get(self.get_package_num(package_name)).unwrap()));
             }
         }
+        if let Some(release) = self.get_release(package_name) {
+            if let Some(origin) = &release.origin { println!("Origin: {}", origin); }
+            if let Some(suite) = &release.suite { println!("Suite: {}", suite); }
+        }
         match i {
             None => (),
             Some(i) => { println!("Installed-Version: {}", i.to_string()) }
@@ -183,14 +303,41 @@ impl Packages {
         }
     }
 
-    // generate output for package-verifier
+
+    fn print_info_json(&self, package_name: &str) {
+        let available = self.get_available_debver(package_name);
+        let installed = self.get_installed_debver(package_name);
+        let checksums : serde_json::Map<String, serde_json::Value> = self.get_checksums(package_name).into_iter()
+            .flat_map(|sums| sums.iter())
+            .map(|sum| (sum.algorithm().to_string(), json!(sum.to_string())))
+            .collect();
+        let depends : Option<Vec<Vec<Alternative>>> = available
+            .map(|_| self.dependencies.get(self.get_package_num(package_name)).unwrap().iter().map(|d| self.dep2json(d)).collect());
+        let release = self.get_release(package_name);
+        output::emit("info", &json!({
+            "package": package_name,
+            "version": available.map(|v| v.to_string()),
+            "checksums": checksums,
+            "depends": depends,
+            "origin": release.and_then(|r| r.origin.as_deref()),
+            "suite": release.and_then(|r| r.suite.as_deref()),
+            "installed_version": installed.map(|v| v.to_string()),
+            "newer_available": available.zip(installed).map(|(a, i)| a > i),
+        }));
+    }
+
+    // generate output for package-verifier: one row per package, with an empty cell for each unknown checksum.
+    // The md5 column keeps its original name, hash, so that older readers still find it.
     pub fn output_md5s(&self, fname: &str) {
         let path = Path::new(fname);
-        let mut md5s : String = "name,version,hash\n".to_owned();
+        let mut md5s : String = "name,version,hash,sha256,sha512\n".to_owned();
         for pn in self.get_package_names() {
-            match (self.get_available_debver(pn), self.get_md5sum(pn)) {
-                (Some(v), Some(m)) => {
-                    let row = format!("{},{},{}\n",pn,v.to_string(),m);
+            match (self.get_available_debver(pn), self.get_checksums(pn)) {
+                (Some(v), Some(sums)) if !sums.is_empty() => {
+                    let cells = checksums::ALL_ALGORITHMS.iter()
+                        .map(|a| sums.get(*a).map(|c| c.to_string()).unwrap_or_default())
+                        .join(",");
+                    let row = format!("{},{},{}\n",pn,v,cells);
                     md5s.push_str(&row)
                 }
                 (_, _) => ()
@@ -199,21 +346,62 @@ impl Packages {
         std::fs::write(path, md5s).unwrap();
     }
 
-    // provided parse function to let students do the async io part independently
+    // provided parse function to let students do the async io part independently.
+    // Reads the hash (md5), sha256 and sha512 columns, whichever are present.
+    // CSV files carry no signature, so they are only loaded if unverified data is allowed.
     pub fn parse_csv(&mut self, filename: &str) {
-        let mut rdr = csv::Reader::from_path(filename).unwrap();
-        for line in rdr.records() {
-            let line = line.unwrap();
-            let package_name = String::from(line.get(0).unwrap());
-            let debver = String::from(line.get(1).unwrap()).parse::<debversion::DebianVersionNum>().unwrap();
-            let md5sum = String::from(line.get(2).unwrap());
-
+        if self.check_unverified(&format!("CSV file {}", filename), "CSV files are not signed").is_err() {
+            return;
+        }
+        let fingerprint = Fingerprint::of(filename);
+        // the whole file is read before anything is inserted, so that a bad line loads nothing
+        let rows = match read_csv(filename) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("could not read {}: {}", filename, e);
+                self.print_loaded(filename, true, false);
+                return;
+            }
+        };
+        for (package_name, debver, sums) in rows {
             let package_num = self.get_package_num_inserting(&package_name);
             self.available_debvers.insert(package_num, debver);
-            self.md5sums.insert(package_num, md5sum);
-
+            self.checksums.insert(package_num, sums);
+        }
+        if let Ok(fp) = fingerprint {
+            self.record_source(SourceKind::Csv, filename, fp, false);
         }
 
-        println!("Packages available: {}", self.available_debvers.keys().len());
+        self.print_loaded(filename, true, false);
+    }
+}
+
+// The name, version and checksums on each line of a CSV file.
+fn read_csv(filename: &str) -> Result<Vec<(String, debversion::DebianVersionNum, Checksums)>, String> {
+    let mut rdr = csv::Reader::from_reader(compression::open(filename).map_err(|e| e.to_string())?);
+    let headers = rdr.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let (name_col, version_col) = (column("name").unwrap_or(0), column("version").unwrap_or(1));
+    let hash_cols : Vec<(usize, HashAlgorithm)> = [("hash", HashAlgorithm::Md5), ("sha256", HashAlgorithm::Sha256), ("sha512", HashAlgorithm::Sha512)]
+        .iter()
+        .filter_map(|(name, algorithm)| column(name).map(|c| (c, *algorithm)))
+        .collect();
+    let mut rows = vec![];
+    for line in rdr.records() {
+        let line = line.map_err(|e| e.to_string())?;
+        let at = || line.position().map_or(String::new(), |p| format!(" on line {}", p.line()));
+        let (package_name, version) = match (line.get(name_col), line.get(version_col)) {
+            (Some(n), Some(v)) if !n.is_empty() && !v.is_empty() => (n, v),
+            _ => return Err(format!("missing name or version{}", at()))
+        };
+        let debver = version.parse::<debversion::DebianVersionNum>().map_err(|e| format!("bad version {:?}{}: {}", version, at(), e))?;
+        let mut sums = Checksums::new();
+        for (col, algorithm) in &hash_cols {
+            if let Some(Ok(sum)) = line.get(*col).filter(|h| !h.is_empty()).map(|h| Checksum::from_hex(*algorithm, h)) {
+                sums.insert(sum);
+            }
+        }
+        rows.push((package_name.to_string(), debver, sums));
     }
+    Ok(rows)
 }
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use crate::Packages;
+
+// Whether commands print JSON objects instead of text. It is global rather than part of Packages because
+// request results and errors are printed from code that has no Packages at hand.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+pub fn set_json(on: bool) {
+    JSON.store(on, Ordering::SeqCst);
+}
+
+#[derive(Serialize)]
+struct Tagged<'a, T: Serialize> {
+    #[serde(rename = "type")]
+    kind : &'a str,
+    #[serde(flatten)]
+    value : &'a T,
+}
+
+/// Prints value as a JSON object on one line, with a "type" field saying what it is.
+pub fn emit<T: Serialize>(kind: &str, value: &T) {
+    println!("{}", serde_json::to_string(&Tagged { kind, value }).unwrap());
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    message : &'a str,
+}
+
+/// Prints a progress or status message: as is, or as a "status" object in JSON mode.
+pub fn status(message: &str) {
+    if json() {
+        emit("status", &Message { message });
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Prints a warning: after "Warning: ", or as a "warning" object in JSON mode.
+pub fn warning(message: &str) {
+    if json() {
+        emit("warning", &Message { message });
+    } else {
+        println!("Warning: {}", message);
+    }
+}
+
+/// Prints an error message as an "error" object; error! uses this in JSON mode.
+pub fn error(message: &str) {
+    emit("error", &Message { message });
+}
+
+/// Marks the current command as failed and prints message as is, or as an "error" object in JSON mode.
+pub fn failure(message: &str) {
+    crate::note_failure();
+    if json() {
+        error(message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Reports a package name that isn't loaded.
+pub fn no_such_package(package_name: &str) {
+    failure(&format!("no such package {}", package_name));
+}
+
+impl Packages {
+    pub fn set_output(&mut self, format: &str) {
+        match format {
+            "text" => set_json(false),
+            "json" => set_json(true),
+            _ => error!("unknown output format {} (expected text or json)", format)
+        }
+    }
+}
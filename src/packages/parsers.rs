@@ -1,155 +1,208 @@
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::io;
 
-use regex::Regex;
+use rayon::prelude::*;
+use serde_json::json;
 
 use crate::Packages;
-use crate::packages::{Dependency, RelVersionedPackageNum};
+use crate::packages::{Dependency, PackageDetails, PackageFile, RelVersionedPackageNum};
+use crate::packages::cache::{Fingerprint, SourceKind};
+use crate::packages::compression;
+use crate::packages::output;
 
-use rpkg::debversion::{self};
+use rpkg::checksums::{self, Checksum, Checksums};
+use rpkg::deb822::{self, Paragraph, Relation};
+use rpkg::debversion::{self, DebianVersionNum};
 
-const KEYVAL_REGEX : &str = r"(?P<key>(\w|-)+): (?P<value>.+)";
-const PKGNAME_AND_VERSION_REGEX : &str = r"(?P<pkg>(\w|\.|\+|-)+)( \((?P<op>(<|=|>)(<|=|>)?) (?P<ver>.*)\))?";
+/// The fields of one Packages or status stanza that we keep, borrowed from the input text.
+/// Stanzas are parsed in parallel; interning the names into Packages happens afterwards, in file order.
+struct Stanza<'a> {
+    package : Option<&'a str>,
+    version : Option<DebianVersionNum>,
+    checksums : Checksums,
+    depends : Option<Vec<Vec<Relation<'a>>>>,
+    filename : Option<&'a str>,
+    size : Option<u64>,
+    section : Option<&'a str>,
+    priority : Option<&'a str>,
+    description : Option<&'a str>,
+    /// the whole stanza, which write-installed reproduces for installed packages
+    text : &'a str,
+}
+
+impl<'a> Stanza<'a> {
+    fn parse(para: &Paragraph<'a>) -> Stanza<'a> {
+        let mut stanza = Stanza { package: None, version: None, checksums: Checksums::new(), depends: None, filename: None, size: None,
+            section: None, priority: None, description: None, text: para.as_str() };
+        for field in para.fields() {
+            let (key, value) = (field.name, field.value);
+            if key.eq_ignore_ascii_case("Package") {
+                stanza.package = Some(value);
+            } else if key.eq_ignore_ascii_case("Version") {
+                stanza.version = Some(value.parse::<debversion::DebianVersionNum>().unwrap());
+            } else if let Some(algorithm) = checksums::ALL_ALGORITHMS.iter().find(|a| key.eq_ignore_ascii_case(a.field_name())) {
+                if let Ok(sum) = Checksum::from_hex(*algorithm, value) {
+                    stanza.checksums.insert(sum);
+                }
+            } else if key.eq_ignore_ascii_case("Depends") {
+                stanza.depends = Some(deb822::parse_relations(value));
+            } else if key.eq_ignore_ascii_case("Filename") {
+                stanza.filename = Some(value);
+            } else if key.eq_ignore_ascii_case("Size") {
+                stanza.size = value.parse().ok();
+            } else if key.eq_ignore_ascii_case("Section") {
+                stanza.section = Some(value);
+            } else if key.eq_ignore_ascii_case("Priority") {
+                stanza.priority = Some(value);
+            } else if key.eq_ignore_ascii_case("Description") {
+                stanza.description = Some(value);
+            }
+        }
+        stanza
+    }
+}
 
 impl Packages {
     /// Loads packages and version numbers from a file, calling get_package_num_inserting on the package name
     /// and inserting the appropriate value into the installed_debvers map with the parsed version number.
+    /// gzip- and xz-compressed files are decompressed into memory before parsing. The status file is the local
+    /// record of what is installed rather than something downloaded, so it needs no signature.
     pub fn parse_installed(&mut self, filename: &str) {
-        let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
-        if let Ok(lines) = read_lines(filename) {
-            let mut current_package_num = 0;
-            for line in lines {
-                if let Ok(ip) = line {
-                    // do something with ip
+        match Fingerprint::read(filename).and_then(|(fp, data)| Ok((fp, self.load_stanzas(&data, Packages::insert_installed_stanza)?))) {
+            Ok((fp, _)) => self.record_source(SourceKind::Installed, filename, fp, true),
+            Err(e) => error!("could not read {}: {}", filename, e)
+        }
+        self.print_loaded(filename, false, true);
+    }
 
-                    // match the line as key value pair
-                    match kv_regexp.captures(&ip)
-                    {
-                        None => (),
-                        Some(caps) => 
-                        {
-                            // parse line into key val
-                            let (key, value) = (caps.name("key").unwrap().as_str(), caps.name("value").unwrap().as_str());
-                            match key
-                            {
-                                // if key is package, insert name into hash
-                                "Package" =>
-                                {
-                                    current_package_num = self.get_package_num_inserting(value);
-                                }
-                                // if key is version, map current package to version
-                                "Version" =>
-                                {
-                                    let debver = value.trim().parse::<debversion::DebianVersionNum>().unwrap();
-                                    self.installed_debvers.insert(current_package_num, debver);
-                                }
-                                _ => ()
-                            }
-                        }
-                    }
-                }
-            }
+    /// Prints how many packages are available and/or installed after loading file, or a "loaded" object with
+    /// both counts in JSON mode.
+    pub(crate) fn print_loaded(&self, file: &str, available: bool, installed: bool) {
+        let (n_available, n_installed) = (self.available_debvers.len(), self.installed_debvers.len());
+        if output::json() {
+            output::emit("loaded", &json!({ "file": file, "available": n_available, "installed": n_installed }));
+            return;
+        }
+        if available {
+            println!("Packages available: {}", n_available);
+        }
+        if installed {
+            println!("Packages installed: {}", n_installed);
         }
-        println!("Packages installed: {}", self.installed_debvers.keys().len());
     }
 
-    /// Loads packages, version numbers, dependencies, and md5sums from a file, calling get_package_num_inserting on the package name
-    /// and inserting the appropriate values into the dependencies, md5sum, and available_debvers maps.
+    /// Loads packages, version numbers, dependencies, checksums (MD5sum, SHA256, SHA512), and .deb file names and sizes from a file, calling get_package_num_inserting
+    /// on the package name and inserting the appropriate values into the dependencies, checksums, and available_debvers maps.
+    /// gzip- and xz-compressed files are decompressed into memory before parsing.
+    ///
+    /// The file is first checked against its entry in the loaded Release files, and the packages it defines are
+    /// associated with that Release. It is refused unless that Release is signed by a key in the keyring, or
+    /// unverified data is allowed.
     pub fn parse_packages(&mut self, filename: &str) {
-        let kv_regexp = Regex::new(KEYVAL_REGEX).unwrap();
-        let pkgver_regexp = Regex::new(PKGNAME_AND_VERSION_REGEX).unwrap();
+        self.load_packages(filename);
+    }
 
-        if let Ok(lines) = read_lines(filename) {
-            let mut current_package_num = 0;
-            for line in lines {
-                if let Ok(ip) = line {
-                    // do more things with ip
+    /// Like parse_packages, and returns the numbers of the packages the file defines.
+    pub(crate) fn load_packages(&mut self, filename: &str) -> Vec<i32> {
+        // the file is read once; the Release check, the fingerprint and the parser all see the same bytes
+        let (fp, data) = match Fingerprint::read(filename) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("could not read {}: {}", filename, e);
+                self.print_loaded(filename, true, false);
+                return vec![];
+            }
+        };
+        let (release, authenticated) = match self.check_index_against_releases(filename, &data) {
+            Err(()) => return vec![],
+            Ok(r) => r
+        };
+        let mut loaded = vec![];
+        match self.load_stanzas(&data, Packages::insert_available_stanza) {
+            Ok(package_nums) => {
+                if let Some(idx) = release {
+                    for package_num in &package_nums {
+                        self.package_releases.insert(*package_num, idx);
+                    }
+                }
+                self.record_source(SourceKind::Packages, filename, fp, authenticated);
+                loaded = package_nums;
+            }
+            Err(e) => error!("could not read {}: {}", filename, e)
+        }
+        self.print_loaded(filename, true, false);
+        loaded
+    }
 
-                    // match the line as key value pair
-                    match kv_regexp.captures(&ip)
-                    {
-                        None => (),
-                        Some(caps) => 
-                        {
-                            // parse line into key val
-                            let (key, value) = (caps.name("key").unwrap().as_str(), caps.name("value").unwrap().as_str());
-                            match key
-                            {
-                                // if key is package, insert name into hash
-                                "Package" =>
-                                {
-                                    current_package_num = self.get_package_num_inserting(value);
-                                }
-                                // if key is version, map current package to version
-                                "Version" =>
-                                {
-                                    let debver = value.trim().parse::<debversion::DebianVersionNum>().unwrap();
-                                    self.available_debvers.insert(current_package_num, debver);
-                                }
-                                // map current package to md5
-                                "MD5sum" =>
-                                {
-                                    self.md5sums.insert(current_package_num, value.to_string());
-                                }
-                                // map current package to vector of dependencies
-                                "Depends" =>
-                                {
-                                    // make dependency list
-                                    let dep_list = value.split(',');
-                                    let mut dep_vec : Vec<Dependency> = Vec::new();
-                                    for dep in dep_list {
+    // Parses the contents of a file, decompressing them first if they are compressed. The whole file is decompressed
+    // before anything is inserted, so that a decompression error partway through leaves nothing of the file behind,
+    // and bytes that aren't UTF-8 are replaced either way. The stanzas are parsed in parallel and insert sees them in
+    // file order. Returns the numbers of the packages that were inserted.
+    fn load_stanzas(&mut self, data: &[u8], insert: fn(&mut Packages, Stanza) -> Option<i32>) -> io::Result<Vec<i32>> {
+        let data = compression::decompress(data)?;
+        let text = String::from_utf8_lossy(&data);
+        let mut package_nums = vec![];
+        for stanza in parse_stanzas(&text) {
+            package_nums.extend(insert(self, stanza));
+        }
+        Ok(package_nums)
+    }
 
-                                        // for each dependency, make alternative list
-                                        let alt_list = dep.split('|');
-                                        let mut alt_vec : Vec<RelVersionedPackageNum> = Vec::new();
-                                        for alt in alt_list {
+    fn insert_installed_stanza(&mut self, stanza: Stanza) -> Option<i32> {
+        let package_name = stanza.package?;
+        let package_num = self.get_package_num_inserting(package_name);
+        // the Packages file describes the available version, which is what search looks at
+        self.details.entry(package_num).or_insert_with(|| PackageDetails::of(&stanza));
+        // a later stanza for the same architecture replaces the earlier one, as a later version does
+        let stanzas = self.installed_stanzas.entry(package_num).or_default();
+        let arch = |text: &str| deb822::paragraphs(text).next().and_then(|p| p.get("Architecture")).map(String::from);
+        let stanza_arch = arch(stanza.text);
+        stanzas.retain(|s| arch(s) != stanza_arch);
+        stanzas.push(stanza.text.to_string());
+        if let Some(debver) = stanza.version {
+            self.installed_debvers.insert(package_num, debver);
+        }
+        Some(package_num)
+    }
 
-                                            // store info for each alternative
-                                            let mut alt_info = RelVersionedPackageNum {package_num: 0, rel_version: None};
-                                            // match for package name, version, & associated operation 
-                                            match pkgver_regexp.captures(alt)
-                                            {
-                                                None => (),
-                                                Some(caps) =>
-                                                {
-                                                    // parse the alt info and add to struct
-                                                    let (pkg, op, ver) = (caps.name("pkg").unwrap().as_str(), caps.name("op"), caps.name("ver"));
-                                                    alt_info.package_num = self.get_package_num_inserting(pkg);
+    fn insert_available_stanza(&mut self, stanza: Stanza) -> Option<i32> {
+        let package_name = stanza.package?;
+        let package_num = self.get_package_num_inserting(package_name);
+        self.details.insert(package_num, PackageDetails::of(&stanza));
+        if let Some(debver) = stanza.version {
+            self.available_debvers.insert(package_num, debver);
+        }
+        if !stanza.checksums.is_empty() {
+            self.checksums.insert(package_num, stanza.checksums);
+        }
+        if let Some(filename) = stanza.filename {
+            self.package_files.insert(package_num, PackageFile { filename: filename.to_string(), size: stanza.size });
+        }
+        if let Some(depends) = stanza.depends {
+            let dep_vec : Vec<Dependency> = depends.into_iter().map(|alts| self.intern_dependency(alts)).collect();
+            self.dependencies.insert(package_num, dep_vec);
+        }
+        Some(package_num)
+    }
 
-                                                    // some dependencies dont have version (assumes latest version when installed)
-                                                    if !ver.is_none()
-                                                    {
-                                                        let op = op.unwrap().as_str().parse::<debversion::VersionRelation>().unwrap();
-                                                        let ver = ver.unwrap().as_str();
-                                                        alt_info.rel_version = Some((op, ver.to_string()));
-                                                    }
+    fn intern_dependency(&mut self, alts: Vec<Relation>) -> Dependency {
+        alts.into_iter().map(|alt| RelVersionedPackageNum {
+            package_num : self.get_package_num_inserting(alt.name),
+            rel_version : alt.version.map(|(op, ver)| (op, ver.to_string()))
+        }).collect()
+    }
+}
 
-                                                    // add alternatives
-                                                    alt_vec.push(alt_info);
-                                                }
-                                            }
-                                        }
-                                        // add dependencies
-                                        dep_vec.push(alt_vec);
-                                    }
-                                    self.dependencies.insert(current_package_num, dep_vec);
-                                }
-                                _ => ()
-                            }
-                        }
-                    }
-                }
-            }
+impl PackageDetails {
+    fn of(stanza: &Stanza) -> PackageDetails {
+        PackageDetails {
+            section : stanza.section.map(String::from),
+            priority : stanza.priority.map(String::from),
+            description : stanza.description.map(String::from),
         }
-        println!("Packages available: {}", self.available_debvers.keys().len());
     }
 }
 
-
-// standard template code downloaded from the Internet somewhere
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+fn parse_stanzas(text: &str) -> Vec<Stanza<'_>> {
+    let paras : Vec<Paragraph> = deb822::paragraphs(text).collect();
+    paras.par_iter().map(Stanza::parse).collect()
 }
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use pgp::composed::CleartextSignedMessage;
+use serde_json::json;
+
+use rpkg::checksums::{Checksum, Checksums, HashAlgorithm, MultiHasher};
+use rpkg::deb822;
+
+use crate::Packages;
+use crate::packages::cache::{Fingerprint, SourceKind};
+use crate::packages::output;
+use crate::packages::signatures::Keyring;
+
+/// What load-packages does with an index whose size or hash doesn't match its Release entry.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseCheck {
+    Refuse,
+    Warn,
+}
+
+impl FromStr for ReleaseCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(ReleaseCheck::Refuse),
+            "warn" => Ok(ReleaseCheck::Warn),
+            _ => Err(format!("bad release check {} (expected refuse or warn)", s))
+        }
+    }
+}
+
+/// One line of a Release file's MD5Sum/SHA256/SHA512 tables, merged across the tables.
+#[derive(Default)]
+pub struct ReleaseEntry {
+    pub size : u64,
+    pub checksums : Checksums,
+}
+
+/// The parts of a Release (or InRelease) file that we use.
+pub struct Release {
+    pub path : String,
+    pub origin : Option<String>,
+    pub suite : Option<String>,
+    pub codename : Option<String>,
+    pub architectures : Vec<String>,
+    pub components : Vec<String>,
+    pub files : HashMap<String, ReleaseEntry>,
+    /// id of the key whose signature verified, if any
+    pub signed_by : Option<String>,
+}
+
+const CLEARSIGN_HEADER : &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+
+/// The contents of a Release or InRelease file.
+pub struct ReleaseText {
+    /// the text that the fields are parsed from: for an InRelease file, exactly the text that its signature covers
+    pub text : String,
+    /// the parsed clearsigned message, for an InRelease file
+    pub clearsigned : Option<CleartextSignedMessage>,
+}
+
+impl ReleaseText {
+    pub fn new(text: String) -> Result<ReleaseText, String> {
+        if !text.starts_with(CLEARSIGN_HEADER) {
+            return Ok(ReleaseText { text, clearsigned: None });
+        }
+        let (msg, _) = CleartextSignedMessage::from_string(&text).map_err(|e| format!("bad clearsigned message: {}", e))?;
+        // the signed text is normalized to CRLF line endings
+        Ok(ReleaseText { text: msg.signed_text().replace("\r\n", "\n"), clearsigned: Some(msg) })
+    }
+}
+
+impl Release {
+    /// Parses the fields of a Release file; for an InRelease file, text is ReleaseText's signed text.
+    pub fn parse(path: &str, text: &str) -> Release {
+        let mut release = Release {
+            path : path.to_string(),
+            origin : None,
+            suite : None,
+            codename : None,
+            architectures : vec![],
+            components : vec![],
+            files : HashMap::new(),
+            signed_by : None,
+        };
+        let para = match deb822::paragraphs(text).next() {
+            None => return release,
+            Some(p) => p
+        };
+        for field in para.fields() {
+            let (key, value) = (field.name, field.value);
+            if key.eq_ignore_ascii_case("Origin") {
+                release.origin = Some(value.to_string());
+            } else if key.eq_ignore_ascii_case("Suite") {
+                release.suite = Some(value.to_string());
+            } else if key.eq_ignore_ascii_case("Codename") {
+                release.codename = Some(value.to_string());
+            } else if key.eq_ignore_ascii_case("Architectures") {
+                release.architectures = value.split_whitespace().map(String::from).collect();
+            } else if key.eq_ignore_ascii_case("Components") {
+                release.components = value.split_whitespace().map(String::from).collect();
+            } else if let Ok(algorithm) = key.parse::<HashAlgorithm>() {
+                for line in value.lines() {
+                    let parts : Vec<&str> = line.split_whitespace().collect();
+                    if parts.len() != 3 { continue; }
+                    let sum = match Checksum::from_hex(algorithm, parts[0]) {
+                        Ok(s) => s,
+                        Err(_) => continue
+                    };
+                    let entry = release.files.entry(parts[2].to_string()).or_default();
+                    entry.size = parts[1].parse().unwrap_or(0);
+                    entry.checksums.insert(sum);
+                }
+            }
+        }
+        release
+    }
+
+    /// Finds the entry describing a local index file. Local files are usually named after the path in the
+    /// Release file, either literally (dists/sid/main/binary-amd64/Packages) or flattened the way apt's
+    /// lists directory does (..._dists_sid_main_binary-amd64_Packages); the longest such match wins.
+    pub fn find_entry(&self, filename: &str) -> Option<(&str, &ReleaseEntry)> {
+        let flat = filename.replace('/', "_");
+        self.files.iter()
+            .filter(|(path, _)| {
+                let flat_path = path.replace('/', "_");
+                flat.ends_with(&flat_path) && (flat.len() == flat_path.len() || flat[..flat.len() - flat_path.len()].ends_with('_'))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map(|(path, entry)| (path.as_str(), entry))
+    }
+}
+
+// size and checksums of a file's bytes as they are on disk
+pub(crate) fn hash_file(filename: &str) -> io::Result<(u64, Checksums)> {
+    let mut file = File::open(filename)?;
+    let mut hasher = MultiHasher::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok((hasher.size(), hasher.finish()))
+}
+
+/// The size and MD5, SHA256 and SHA512 of bytes already in memory.
+pub(crate) fn hash_bytes(data: &[u8]) -> (u64, Checksums) {
+    let mut hasher = MultiHasher::new();
+    hasher.update(data);
+    (hasher.size(), hasher.finish())
+}
+
+impl Packages {
+    pub fn set_release_check(&mut self, check: &str) {
+        match check.parse::<ReleaseCheck>() {
+            Ok(c) => self.release_check = c,
+            Err(e) => error!("{}", e)
+        }
+    }
+
+    pub fn set_keyring(&mut self, path: &str) {
+        match Keyring::load(path) {
+            Ok(k) => {
+                if output::json() {
+                    output::emit("keyring", &json!({ "path": path, "keys": k.len() }));
+                } else {
+                    println!("Keyring {}: {} keys", path, k.len());
+                }
+                self.keyring = Some(k);
+            }
+            Err(e) => error!("{}", e)
+        }
+    }
+
+    pub fn set_allow_unverified(&mut self, allow: &str) {
+        match allow.parse::<bool>() {
+            Ok(a) => self.allow_unverified = a,
+            Err(_) => error!("expected true or false, got {}", allow)
+        }
+    }
+
+    // InRelease files carry their signature inline; a plain Release file is signed by Release.gpg next to it
+    fn verify_release_signature(&self, filename: &str, contents: &ReleaseText) -> Result<String, String> {
+        let keyring = self.keyring.as_ref().ok_or_else(|| String::from("no keyring set"))?;
+        if let Some(msg) = &contents.clearsigned {
+            return keyring.verify_clearsigned(msg);
+        }
+        let sig_path = format!("{}.gpg", filename);
+        if !Path::new(&sig_path).exists() {
+            return Err(format!("not clearsigned and no {}", sig_path));
+        }
+        keyring.verify_detached(contents.text.as_bytes(), &sig_path)
+    }
+
+    /// Parses a Release or InRelease file and remembers it, so that later load-packages commands can
+    /// check their index files against it and label the packages with its Suite and Origin.
+    /// If a keyring is set, the file's inline (InRelease) or detached (Release.gpg) signature is verified.
+    pub fn parse_release(&mut self, filename: &str) {
+        let fingerprint = Fingerprint::of(filename);
+        let contents = match fs::read_to_string(filename).map_err(|e| format!("could not read {}: {}", filename, e))
+                .and_then(|t| ReleaseText::new(t).map_err(|e| format!("could not parse {}: {}", filename, e))) {
+            Ok(c) => c,
+            Err(e) => { error!("{}", e); return }
+        };
+        let mut release = Release::parse(filename, &contents.text);
+        let signature = self.verify_release_signature(filename, &contents);
+        release.signed_by = signature.clone().ok();
+        if output::json() {
+            output::emit("release", &json!({
+                "path": filename, "origin": release.origin, "suite": release.suite, "codename": release.codename,
+                "components": release.components, "architectures": release.architectures, "files": release.files.len(),
+                "signed_by": release.signed_by, "unverified_reason": signature.err(),
+            }));
+        } else {
+            match signature {
+                Ok(key_id) => println!("Signature: good, key {}", key_id),
+                Err(reason) => println!("Signature: not verified ({})", reason)
+            }
+            println!("Release {}: Origin {}, Suite {}, Codename {}, Components {}, Architectures {}, {} files",
+                filename,
+                release.origin.as_deref().unwrap_or("?"),
+                release.suite.as_deref().unwrap_or("?"),
+                release.codename.as_deref().unwrap_or("?"),
+                release.components.join(" "),
+                release.architectures.join(" "),
+                release.files.len());
+        }
+        // package_releases holds indices into releases, so reloading a file replaces it in place
+        match self.releases.iter().position(|r| r.path == filename) {
+            Some(i) => self.releases[i] = release,
+            None => self.releases.push(release)
+        }
+        // recorded so that a stale cache loads it again before the indexes it describes
+        if let Ok(fp) = fingerprint {
+            self.record_source(SourceKind::Release, filename, fp, true);
+        }
+    }
+
+    /// Checks the contents of filename, as read into data, against the loaded Release files. Returns Err(()) if
+    /// the file should not be loaded, and otherwise the index of the Release that describes it, if any, and whether
+    /// the file is authenticated. The caller parses the same data, so nothing is parsed that wasn't checked.
+    ///
+    /// An index is only loaded if it matches its entry in a Release file whose signature verified against the
+    /// keyring, unless unverified data is allowed.
+    pub(crate) fn check_index_against_releases(&self, filename: &str, data: &[u8]) -> Result<(Option<usize>, bool), ()> {
+        let (size, sums) = hash_bytes(data);
+        let matched = self.match_index_to_release(filename, size, &sums)?;
+        let unauthenticated = match (matched, &self.keyring) {
+            (None, _) => Some(String::from("it is not listed in any loaded Release file")),
+            (Some((idx, false)), _) => Some(format!("it does not match its entry in {}", self.releases[idx].path)),
+            (Some((idx, true)), None) => Some(format!("{} can't be verified without a keyring", self.releases[idx].path)),
+            (Some((idx, true)), Some(keyring)) if self.releases[idx].signed_by.is_none() =>
+                Some(format!("{} is not signed by a key in {}", self.releases[idx].path, keyring.path)),
+            (Some(_), _) => None
+        };
+        let release = matched.map(|(idx, _)| idx);
+        match unauthenticated {
+            None => Ok((release, true)),
+            Some(reason) => self.check_unverified(&format!("index {}", filename), &reason).map(|()| (release, false))
+        }
+    }
+
+    /// Decides whether data that couldn't be authenticated is loaded anyway: with a warning if unverified data is
+    /// allowed, and otherwise not, with an error.
+    pub(crate) fn check_unverified(&self, what: &str, reason: &str) -> Result<(), ()> {
+        if self.allow_unverified {
+            output::warning(&format!("loading unauthenticated {}: {}", what, reason));
+            Ok(())
+        } else {
+            error!("refusing to load unauthenticated {}: {} (use --allow-unverified or set-allow-unverified true to override)", what, reason);
+            Err(())
+        }
+    }
+
+    // Finds the Release entry for filename and compares its size and hash. Returns the index of the Release
+    // and whether the file matched, or Err(()) if it didn't match and release_check says to refuse it.
+    fn match_index_to_release(&self, filename: &str, size: u64, sums: &Checksums) -> Result<Option<(usize, bool)>, ()> {
+        if self.releases.is_empty() {
+            return Ok(None);
+        }
+
+        let mut found = self.releases.iter().enumerate()
+            .filter_map(|(i, r)| r.find_entry(filename).map(|(p, e)| (i, p, e)))
+            .max_by_key(|(_, p, _)| p.len());
+        if found.is_none() {
+            // maybe the file was renamed; look it up by content instead
+            found = self.releases.iter().enumerate()
+                .flat_map(|(i, r)| r.files.iter().map(move |(p, e)| (i, p.as_str(), e)))
+                .find(|(_, _, e)| e.size == size && e.checksums.strongest().is_some_and(|s| sums.get(s.algorithm()) == Some(s)));
+        }
+        let (idx, path, entry) = match found {
+            None => {
+                output::warning(&format!("{} is not listed in any loaded Release file", filename));
+                return Ok(None);
+            }
+            Some(f) => f
+        };
+
+        // compare the strongest checksum that the Release file lists
+        let problem = if entry.size != size {
+            Some(format!("size {} does not match {} in {}", size, entry.size, self.releases[idx].path))
+        } else {
+            match entry.checksums.strongest() {
+                None => None,
+                Some(expected) => {
+                    let actual = sums.get(expected.algorithm()).unwrap();
+                    if actual != expected {
+                        Some(format!("{} {} does not match {} in {}", expected.algorithm().field_name(), actual, expected, self.releases[idx].path))
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+        match (problem, self.release_check) {
+            (None, _) => Ok(Some((idx, true))),
+            (Some(p), ReleaseCheck::Warn) => {
+                output::warning(&format!("{} ({}): {}", filename, path, p));
+                Ok(Some((idx, false)))
+            }
+            (Some(p), ReleaseCheck::Refuse) => {
+                error!("refusing to load {} ({}): {}", filename, path, p);
+                Err(())
+            }
+        }
+    }
+
+    pub(crate) fn get_release(&self, package_name: &str) -> Option<&Release> {
+        let package_num = self.package_name_to_num.get(package_name)?;
+        self.package_releases.get(package_num).map(|i| &self.releases[*i])
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::Packages;
+use crate::packages::output;
+use crate::packages::verify_cache::format_timestamp;
+
+/// How a verification request ended.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Match,
+    Mismatch,
+    /// the server answered, but we have no local checksum for the requested version
+    NoLocalChecksum,
+    /// 200, but the body isn't a checksum of the requested kind
+    BadResponse,
+    HttpError,
+    TransportError,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Match => "match",
+            Outcome::Mismatch => "mismatch",
+            Outcome::NoLocalChecksum => "no-local-checksum",
+            Outcome::BadResponse => "bad-response",
+            Outcome::HttpError => "http-error",
+            Outcome::TransportError => "transport-error"
+        })
+    }
+}
+
+/// The final result of one enq-verify request, as execute() reports it.
+#[derive(Clone, Serialize)]
+pub struct VerifyResult {
+    pub package : String,
+    pub version : String,
+    pub url : String,
+    pub server : String,
+    pub algorithm : String,
+    pub outcome : Outcome,
+    pub http_status : Option<u32>,
+    pub expected : Option<String>,
+    pub received : Option<String>,
+    pub matches : Option<bool>,
+    pub error : Option<String>,
+    /// time taken by the last attempt
+    pub latency_ms : u64,
+    /// time from enqueueing the request to its result, including time spent queued and earlier attempts
+    pub total_ms : u64,
+    pub retries : u32,
+    /// set if the answer came from the verification cache instead of the server: when it was received
+    pub cached_at : Option<u64>,
+    /// when the answer arrived, in seconds since the Unix epoch; the verification cache records it
+    #[serde(skip)]
+    pub received_at : u64,
+}
+
+impl VerifyResult {
+    fn label(&self) -> String {
+        if self.algorithm == "md5" { self.package.clone() } else { format!("{} ({})", self.package, self.algorithm) }
+    }
+
+    /// Whether the request counts as failed: the checksums differ or no usable answer came back.
+    pub fn failed(&self) -> bool {
+        !matches!(self.outcome, Outcome::Match | Outcome::NoLocalChecksum)
+    }
+
+    /// Prints the console status line for this result, or the result as a "verify" object in JSON mode.
+    pub fn print(&self) {
+        if output::json() {
+            output::emit("verify", self);
+            return;
+        }
+        let label = self.label();
+        let tries = if self.retries > 0 { format!(" after {} attempts", self.retries + 1) } else { String::new() };
+        // after retries, the last attempt's time says little about how long the request took
+        let total = if self.retries > 0 { format!(", {} ms since queued", self.total_ms) } else { String::new() };
+        match self.outcome {
+            Outcome::Match | Outcome::Mismatch if self.cached_at.is_some() =>
+                println!("verifying {}, matches: {:?} (cached answer from {} at {})", label, self.outcome == Outcome::Match,
+                    self.server, format_timestamp(self.cached_at.unwrap())),
+            Outcome::Match | Outcome::Mismatch =>
+                println!("verifying {}, matches: {:?} ({} ms{}{})", label, self.outcome == Outcome::Match, self.latency_ms, total, tries),
+            Outcome::NoLocalChecksum =>
+                println!("verifying {}, no local {} checksum for version {}", label, self.algorithm, self.version),
+            Outcome::BadResponse =>
+                println!("verifying {}, got {}", label, self.error.as_deref().unwrap_or("")),
+            Outcome::HttpError if self.http_status.is_some_and(|s| s >= 400) =>
+                println!("got error {} on request for package {} version {}{}", self.http_status.unwrap(), self.package, self.version, tries),
+            Outcome::HttpError =>
+                println!("got unexpected response {} on request for package {} version {}", self.http_status.unwrap_or(0), self.package, self.version),
+            Outcome::TransportError =>
+                println!("request for package {} version {} failed{}: {}", self.package, self.version, tries, self.error.as_deref().unwrap_or(""))
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ReportFormat {
+    Json,
+    Junit,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "junit" => Ok(ReportFormat::Junit),
+            _ => Err(format!("unknown report format {} (expected json or junit)", s))
+        }
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+// One testcase per request: mismatches and HTTP errors are failures, transport errors are errors, and
+// requests without a local checksum are skipped.
+fn junit(results: &[VerifyResult]) -> String {
+    let count = |o: &[Outcome]| results.iter().filter(|r| o.contains(&r.outcome)).count();
+    let total_ms : u64 = results.iter().map(|r| r.total_ms).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuite name=\"rpkg-verify\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        results.len(),
+        count(&[Outcome::Mismatch, Outcome::HttpError, Outcome::BadResponse]),
+        count(&[Outcome::TransportError]),
+        count(&[Outcome::NoLocalChecksum]),
+        total_ms as f64 / 1000.0));
+    for r in results {
+        xml.push_str(&format!("  <testcase classname=\"verify.{}\" name=\"{} {}\" time=\"{:.3}\">\n",
+            xml_escape(&r.algorithm), xml_escape(&r.package), xml_escape(&r.version), r.total_ms as f64 / 1000.0));
+        let message = match r.outcome {
+            Outcome::Match => None,
+            Outcome::Mismatch => Some(("failure", String::from("checksum mismatch"))),
+            Outcome::BadResponse => Some(("failure", r.error.clone().unwrap_or_default())),
+            Outcome::HttpError => Some(("failure", format!("HTTP status {}", r.http_status.unwrap_or(0)))),
+            Outcome::TransportError => Some(("error", r.error.clone().unwrap_or_default())),
+            Outcome::NoLocalChecksum => Some(("skipped", format!("no local {} checksum for version {}", r.algorithm, r.version)))
+        };
+        if let Some((tag, message)) = message {
+            xml.push_str(&format!("    <{} message=\"{}\"/>\n", tag, xml_escape(&message)));
+        }
+        xml.push_str(&format!("    <system-out>url: {}\nhttp status: {}\nexpected: {}\nreceived: {}\nretries: {}</system-out>\n",
+            xml_escape(&r.url),
+            r.http_status.map_or(String::from("none"), |s| s.to_string()),
+            r.expected.as_deref().unwrap_or("none"),
+            xml_escape(r.received.as_deref().unwrap_or("none")),
+            r.retries));
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+impl Packages {
+    /// Writes the results of every request that execute() has finished so far to filename, as a JSON
+    /// array or a JUnit XML test suite.
+    pub fn write_verify_report(&self, filename: &str, format_name: &str) {
+        let format = match format_name.parse::<ReportFormat>() {
+            Ok(f) => f,
+            Err(e) => { error!("{}", e); return }
+        };
+        let results = self.verify_results();
+        let text = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(results).unwrap() + "\n",
+            ReportFormat::Junit => junit(results)
+        };
+        match fs::write(filename, text) {
+            Ok(()) if output::json() => output::emit("verify-report", &json!({ "path": filename, "format": format_name, "requests": results.len() })),
+            Ok(()) => println!("Report written to {} ({} requests)", filename, results.len()),
+            Err(e) => error!("could not write {}: {}", filename, e)
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use rpkg::debversion::{self, DebianVersionNum, VersionRelation};
+
+use crate::Packages;
+use crate::packages::output;
+
+/// A parsed search pattern. The syntax follows aptitude's: terms are joined by juxtaposition (and) or |
+/// (or), ! negates, and parentheses group. A word without ~ is a name regex.
+enum Pattern {
+    /// ~n<regex>
+    Name(Regex),
+    /// ~d<regex>, the whole description
+    Description(Regex),
+    /// ~s<regex>
+    Section(Regex),
+    /// ~p<regex>
+    Priority(Regex),
+    /// ~i
+    Installed,
+    /// ~U: installed, and the available version is newer
+    Upgradable,
+    /// ~D<package>: one of the alternatives of a direct dependency is package
+    DependsOn(String),
+    /// ~V<op><version>, e.g. ~V>=2.0; compares the available version, or the installed one if none is available
+    Version(VersionRelation, DebianVersionNum),
+    Not(Box<Pattern>),
+    And(Vec<Pattern>),
+    Or(Vec<Pattern>),
+}
+
+struct Parser<'a> {
+    rest : &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => { self.rest = rest; true }
+            None => false
+        }
+    }
+
+    // expr := and ('|' and)*
+    fn expr(&mut self) -> Result<Pattern, String> {
+        let mut alternatives = vec![self.and()?];
+        while self.eat('|') {
+            alternatives.push(self.and()?);
+        }
+        Ok(if alternatives.len() == 1 { alternatives.pop().unwrap() } else { Pattern::Or(alternatives) })
+    }
+
+    // and := unary unary*
+    fn and(&mut self) -> Result<Pattern, String> {
+        let mut terms = vec![self.unary()?];
+        loop {
+            self.skip_spaces();
+            if self.rest.is_empty() || self.rest.starts_with('|') || self.rest.starts_with(')') {
+                break;
+            }
+            terms.push(self.unary()?);
+        }
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { Pattern::And(terms) })
+    }
+
+    // unary := '!' unary | '(' expr ')' | term
+    fn unary(&mut self) -> Result<Pattern, String> {
+        if self.eat('!') {
+            return Ok(Pattern::Not(Box::new(self.unary()?)));
+        }
+        if self.eat('(') {
+            let inner = self.expr()?;
+            if !self.eat(')') {
+                return Err(String::from("missing )"));
+            }
+            return Ok(inner);
+        }
+        self.skip_spaces();
+        match self.rest.strip_prefix('~') {
+            None => {
+                let word = self.argument()?;
+                if word.is_empty() {
+                    return Err(format!("expected a term at {:?}", self.rest));
+                }
+                Ok(Pattern::Name(regex(&word)?))
+            }
+            Some(rest) => {
+                let mut chars = rest.chars();
+                let kind = chars.next().ok_or("~ needs a letter")?;
+                self.rest = chars.as_str();
+                match kind {
+                    'n' => Ok(Pattern::Name(regex(&self.argument()?)?)),
+                    'd' => Ok(Pattern::Description(regex(&self.argument()?)?)),
+                    's' => Ok(Pattern::Section(regex(&self.argument()?)?)),
+                    'p' => Ok(Pattern::Priority(regex(&self.argument()?)?)),
+                    'i' => Ok(Pattern::Installed),
+                    'U' => Ok(Pattern::Upgradable),
+                    'D' => match self.argument()? {
+                        name if name.is_empty() => Err(String::from("~D needs a package name")),
+                        name => Ok(Pattern::DependsOn(name))
+                    },
+                    'V' => version_term(&self.argument()?),
+                    _ => Err(format!("unknown term ~{}", kind))
+                }
+            }
+        }
+    }
+
+    // a term's argument runs to the next space, | or ), unless it is in double quotes
+    fn argument(&mut self) -> Result<String, String> {
+        if let Some(rest) = self.rest.strip_prefix('"') {
+            let end = rest.find('"').ok_or("unclosed \"")?;
+            self.rest = &rest[end + 1..];
+            return Ok(rest[..end].to_string());
+        }
+        let end = self.rest.find(|c: char| c.is_whitespace() || c == '|' || c == ')').unwrap_or(self.rest.len());
+        let (arg, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(arg.to_string())
+    }
+}
+
+// matching is case-insensitive, as in aptitude
+fn regex(s: &str) -> Result<Regex, String> {
+    RegexBuilder::new(s).case_insensitive(true).build().map_err(|e| format!("bad regex {:?}: {}", s, e))
+}
+
+fn version_term(arg: &str) -> Result<Pattern, String> {
+    let (op, version) = ["<<", "<=", ">=", ">>", "="].iter()
+        .find_map(|op| arg.strip_prefix(op).map(|v| (*op, v)))
+        .unwrap_or(("=", arg));
+    if version.is_empty() {
+        return Err(String::from("~V needs a version, e.g. ~V>=2.0"));
+    }
+    let op = op.parse::<VersionRelation>().unwrap();
+    let version = version.parse::<DebianVersionNum>().map_err(|_| format!("bad version {}", version))?;
+    Ok(Pattern::Version(op, version))
+}
+
+fn parse(pattern: &str) -> Result<Pattern, String> {
+    let mut parser = Parser { rest: pattern };
+    let parsed = parser.expr()?;
+    parser.skip_spaces();
+    if !parser.rest.is_empty() {
+        return Err(format!("unexpected {:?}", parser.rest));
+    }
+    Ok(parsed)
+}
+
+#[derive(Serialize)]
+struct Match<'a> {
+    package : &'a str,
+    version : Option<String>,
+    installed_version : Option<String>,
+}
+
+impl Packages {
+    fn matches(&self, pattern: &Pattern, num: i32) -> bool {
+        let details = self.details.get(&num);
+        let field = |re: &Regex, f: fn(&crate::packages::PackageDetails) -> &Option<String>|
+            details.and_then(|d| f(d).as_deref()).is_some_and(|v| re.is_match(v));
+        match pattern {
+            Pattern::Name(re) => re.is_match(self.get_package_name(num)),
+            Pattern::Description(re) => field(re, |d| &d.description),
+            Pattern::Section(re) => field(re, |d| &d.section),
+            Pattern::Priority(re) => field(re, |d| &d.priority),
+            Pattern::Installed => self.installed_debvers.contains_key(&num),
+            Pattern::Upgradable => match (self.available_debvers.get(&num), self.installed_debvers.get(&num)) {
+                (Some(a), Some(i)) => a > i,
+                _ => false
+            },
+            Pattern::DependsOn(name) => self.dependencies.get(&num).is_some_and(|deps|
+                deps.iter().flatten().any(|alt| self.get_package_name(alt.package_num) == name)),
+            Pattern::Version(op, version) => self.available_debvers.get(&num).or_else(|| self.installed_debvers.get(&num))
+                .is_some_and(|v| debversion::cmp_debversion_with_op(op, v, version)),
+            Pattern::Not(p) => !self.matches(p, num),
+            Pattern::And(ps) => ps.iter().all(|p| self.matches(p, num)),
+            Pattern::Or(ps) => ps.iter().any(|p| self.matches(p, num))
+        }
+    }
+
+    /// Prints the name, available version and installed version of every package that matches pattern, by name.
+    /// Only packages with a stanza of their own are searched, not names that only appear in dependencies.
+    pub fn search(&self, pattern: &str) {
+        let parsed = match parse(pattern) {
+            Ok(p) => p,
+            Err(e) => { error!("bad search pattern {:?}: {}", pattern, e); return }
+        };
+        let mut nums : Vec<i32> = self.package_num_to_name.keys()
+            .filter(|n| self.available_debvers.contains_key(n) || self.installed_debvers.contains_key(n))
+            .filter(|n| self.matches(&parsed, **n))
+            .copied()
+            .collect();
+        nums.sort_by_key(|n| self.get_package_name(*n));
+        let found : Vec<Match> = nums.iter().map(|n| Match {
+            package : self.get_package_name(*n),
+            version : self.available_debvers.get(n).map(|v| v.to_string()),
+            installed_version : self.installed_debvers.get(n).map(|v| v.to_string()),
+        }).collect();
+
+        if output::json() {
+            output::emit("search", &serde_json::json!({ "pattern": pattern, "matches": found }));
+            return;
+        }
+        if found.is_empty() {
+            println!("no packages match {}", pattern);
+        }
+        for m in found {
+            match m.installed_version {
+                None => println!("{} {}", m.package, m.version.unwrap_or_default()),
+                Some(i) => println!("{} {} [installed {}]", m.package, m.version.as_deref().unwrap_or("-"), i)
+            }
+        }
+    }
+}
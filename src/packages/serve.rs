@@ -0,0 +1,160 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{self, AtomicUsize};
+use std::thread;
+use std::time::Duration;
+
+use rpkg::checksums::{Checksums, HashAlgorithm};
+use rpkg::debversion::DebianVersionNum;
+
+use crate::Packages;
+
+/// Settings for `rpkg serve`. The delay and failure switches let tests exercise the verifier's
+/// timeouts and retries.
+pub struct ServeOptions {
+    /// the address to listen on; the loopback address unless asked otherwise, since this is a test server
+    pub bind : IpAddr,
+    pub port : u16,
+    /// answer at most this many connections at once; the rest wait to be accepted
+    pub max_connections : usize,
+    /// wait this long before answering each request
+    pub delay : Duration,
+    /// answer every nth request with fail_status instead of looking it up; 0 never fails
+    pub fail_every : usize,
+    pub fail_status : u32,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions { bind: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 4590, max_connections: 64, delay: Duration::ZERO, fail_every: 0, fail_status: 503 }
+    }
+}
+
+// what the server knows: the available version of each package and its checksums
+struct ServeTable {
+    packages : HashMap<String, (DebianVersionNum, Checksums)>,
+    options : ServeOptions,
+    requests : AtomicUsize,
+    // connections being answered, and a signal for when one is done
+    open : Mutex<usize>,
+    closed : Condvar,
+}
+
+impl ServeTable {
+    // answers /rest/v1/checksums/<pkg>/<version> (md5) and /rest/v2/checksums/<pkg>/<version>/<algorithm>
+    fn lookup(&self, path: &str) -> Option<String> {
+        let parts : Vec<String> = path.trim_start_matches('/').split('/')
+            .map(|p| urlencoding::decode(p).map(|d| d.into_owned()).unwrap_or_else(|_| p.to_string()))
+            .collect();
+        let (pkg, version, algorithm) = match parts.iter().map(|p| p.as_str()).collect::<Vec<&str>>()[..] {
+            ["rest", "v1", "checksums", pkg, version] => (pkg, version, HashAlgorithm::Md5),
+            ["rest", "v2", "checksums", pkg, version, algorithm] => (pkg, version, algorithm.parse().ok()?),
+            _ => return None
+        };
+        let (available, sums) = self.packages.get(pkg)?;
+        if version.parse::<DebianVersionNum>().ok()?.cmp(available) != Ordering::Equal {
+            return None;
+        }
+        sums.get(algorithm).map(|sum| sum.to_string())
+    }
+
+    // frees the slot of a connection that has been answered
+    fn close(&self) {
+        *self.open.lock().unwrap() -= 1;
+        self.closed.notify_one();
+    }
+
+    fn respond(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // skip the headers
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+
+        let n = self.requests.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        if !self.options.delay.is_zero() {
+            thread::sleep(self.options.delay);
+        }
+        let mut words = request_line.split_whitespace();
+        let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        let (status, body) = if self.options.fail_every > 0 && n.is_multiple_of(self.options.fail_every) {
+            (self.options.fail_status, String::new())
+        } else if method != "GET" {
+            (405, String::new())
+        } else {
+            match self.lookup(path) {
+                Some(sum) => (200, sum),
+                None => (404, String::new())
+            }
+        };
+        println!("{} {} -> {}", method, path, status);
+
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, reason(status), body.len(), body)?;
+        stream.flush()
+    }
+}
+
+fn reason(status: u32) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error"
+    }
+}
+
+impl Packages {
+    /// Serves the checksums of the available packages over HTTP, the way the verification server does,
+    /// so that set-server can point at it. Runs until the process is killed.
+    pub fn serve(&self, options: ServeOptions) {
+        let listener = match TcpListener::bind((options.bind, options.port)) {
+            Ok(l) => l,
+            Err(e) => { error!("could not listen on {}:{}: {}", options.bind, options.port, e); return }
+        };
+        // the port may have been 0, i.e. any free one
+        let addr = match listener.local_addr() {
+            Ok(a) => a,
+            Err(e) => { error!("{}", e); return }
+        };
+        let mut packages = HashMap::new();
+        for (num, version) in &self.available_debvers {
+            if let Some(sums) = self.checksums.get(num) {
+                packages.insert(self.get_package_name(*num).to_string(), (version.clone(), sums.clone()));
+            }
+        }
+        println!("Serving checksums for {} packages on {}", packages.len(), addr);
+        let table = Arc::new(ServeTable { packages, options, requests: AtomicUsize::new(0), open: Mutex::new(0), closed: Condvar::new() });
+
+        loop {
+            // wait for a free slot before accepting, so that the connections over the limit queue up in the listen backlog
+            {
+                let open = table.open.lock().unwrap();
+                let mut open = table.closed.wait_while(open, |open| *open >= table.options.max_connections).unwrap();
+                *open += 1;
+            }
+            let stream = match listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) => { error!("{}", e); table.close(); continue }
+            };
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                if let Err(e) = table.respond(stream) {
+                    error!("{}", e);
+                }
+                table.close();
+            });
+        }
+    }
+}
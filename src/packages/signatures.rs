@@ -0,0 +1,62 @@
+use std::fs::File;
+
+use pgp::composed::{CleartextSignedMessage, Deserializable, DetachedSignature, SignedPublicKey};
+use pgp::types::KeyDetails;
+
+/// The public keys that Release signatures are checked against, e.g. /usr/share/keyrings/debian-archive-keyring.gpg.
+/// Both binary and ASCII-armored keyrings are accepted.
+pub struct Keyring {
+    pub path : String,
+    keys : Vec<SignedPublicKey>,
+}
+
+impl Keyring {
+    pub fn load(path: &str) -> Result<Keyring, String> {
+        let file = File::open(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let (keys, _) = SignedPublicKey::from_reader_many(file).map_err(|e| format!("bad keyring {}: {}", path, e))?;
+        let keys : Vec<SignedPublicKey> = keys.filter_map(|k| k.ok()).collect();
+        if keys.is_empty() {
+            return Err(format!("no public keys in {}", path));
+        }
+        Ok(Keyring { path: path.to_string(), keys })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Verifies a clearsigned document such as InRelease. Returns the id of the key that signed it.
+    pub fn verify_clearsigned(&self, msg: &CleartextSignedMessage) -> Result<String, String> {
+        for key in &self.keys {
+            if msg.verify(key).is_ok() {
+                return Ok(key.legacy_key_id().to_string());
+            }
+            for subkey in &key.public_subkeys {
+                if msg.verify(subkey).is_ok() {
+                    return Ok(subkey.legacy_key_id().to_string());
+                }
+            }
+        }
+        Err(format!("no good signature by a key in {}", self.path))
+    }
+
+    /// Verifies data against a detached signature file such as Release.gpg, which may hold several signatures.
+    /// Returns the id of the first key with a good signature.
+    pub fn verify_detached(&self, data: &[u8], sig_path: &str) -> Result<String, String> {
+        let file = File::open(sig_path).map_err(|e| format!("could not read {}: {}", sig_path, e))?;
+        let (sigs, _) = DetachedSignature::from_reader_many(file).map_err(|e| format!("bad signature file {}: {}", sig_path, e))?;
+        for sig in sigs.flatten() {
+            for key in &self.keys {
+                if sig.verify(key, data).is_ok() {
+                    return Ok(key.legacy_key_id().to_string());
+                }
+                for subkey in &key.public_subkeys {
+                    if sig.verify(subkey, data).is_ok() {
+                        return Ok(subkey.legacy_key_id().to_string());
+                    }
+                }
+            }
+        }
+        Err(format!("no good signature by a key in {}", self.path))
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use rpkg::deb822;
+
+use crate::Packages;
+use crate::packages::{compression, output};
+
+// fields of an installed stanza that describe the package rather than one version of it, and so are carried
+// over when a simulated install upgrades it
+const KEPT_ON_UPGRADE : &[&str] = &["Essential", "Maintainer", "Architecture", "Multi-Arch", "Homepage"];
+
+impl Packages {
+    /// Writes the installed packages to filename as a dpkg status file, one stanza per package in name order,
+    /// and returns how many were written. A .gz or .xz extension compresses the file.
+    pub(crate) fn write_status_file(&self, filename: &str) -> io::Result<usize> {
+        let mut nums : Vec<i32> = self.installed_debvers.keys().copied().collect();
+        nums.sort_by_key(|n| self.get_package_name(*n));
+        let mut out = compression::create(filename)?;
+        for (i, num) in nums.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            out.write_all(self.status_stanza(*num).as_bytes())?;
+        }
+        out.finish()?;
+        Ok(nums.len())
+    }
+
+    /// Writes the installed packages to a status file that load-installed can read back.
+    pub fn write_installed(&self, filename: &str) {
+        match self.write_status_file(filename) {
+            Ok(n) if output::json() => output::emit("write-installed", &json!({ "path": filename, "packages": n })),
+            Ok(n) => println!("Wrote {} installed packages to {}", n, filename),
+            Err(e) => error!("could not write {}: {}", filename, e)
+        }
+    }
+
+    // The stanzas from the status file the package was loaded from, as they were, if it is still installed at
+    // that version. Otherwise (a simulated install) Package, Status and Version, plus the descriptive fields and
+    // dependencies that we know of.
+    fn status_stanza(&self, num: i32) -> String {
+        let version = &self.installed_debvers[&num];
+        let originals : Vec<deb822::Paragraph> = self.installed_stanzas.get(&num).into_iter().flatten()
+            .filter_map(|text| deb822::paragraphs(text).next())
+            .collect();
+        let unchanged : Vec<&str> = originals.iter()
+            .filter(|p| p.get("Version").is_some_and(|v| v.parse().ok().as_ref() == Some(version)))
+            .map(|p| p.as_str())
+            .collect();
+        if !unchanged.is_empty() {
+            return unchanged.join("\n\n") + "\n";
+        }
+
+        let mut stanza = format!("Package: {}\nStatus: install ok installed\n", self.get_package_name(num));
+        let details = self.details.get(&num);
+        if let Some(priority) = details.and_then(|d| d.priority.as_ref()) {
+            stanza.push_str(&format!("Priority: {}\n", priority));
+        }
+        if let Some(section) = details.and_then(|d| d.section.as_ref()) {
+            stanza.push_str(&format!("Section: {}\n", section));
+        }
+        for field in originals.iter().take(1).flat_map(|p| p.fields()) {
+            if KEPT_ON_UPGRADE.iter().any(|k| field.name.eq_ignore_ascii_case(k)) {
+                stanza.push_str(&format!("{}: {}\n", field.name, field.value));
+            }
+        }
+        stanza.push_str(&format!("Version: {}\n", version));
+        if let Some(deps) = self.dependencies.get(&num).filter(|d| !d.is_empty()) {
+            stanza.push_str(&format!("Depends: {}\n", self.deps2str(deps)));
+        }
+        if let Some(description) = details.and_then(|d| d.description.as_ref()) {
+            stanza.push_str(&format!("Description: {}\n", description));
+        }
+        stanza
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde_json::json;
+
+use rpkg::debversion::DebianVersionNum;
+
+use crate::Packages;
+use crate::packages::output;
+
+/// One package's installed version before and after a transaction; None means not installed.
+#[derive(Serialize)]
+struct Change {
+    package : String,
+    before : Option<String>,
+    after : Option<String>,
+}
+
+/// A simulated install or remove, applied to the installed versions in memory.
+#[derive(Serialize)]
+struct Transaction {
+    id : usize,
+    /// e.g. "install bash"
+    command : String,
+    changes : Vec<Change>,
+    /// the status file that commit-to wrote after this transaction, if any
+    committed_to : Option<String>,
+    /// the versions to restore on rollback
+    #[serde(skip)]
+    undo : Vec<(i32, Option<DebianVersionNum>)>,
+}
+
+impl Transaction {
+    // kind is the JSON type; the text heading is e.g. "rolled back 2: install bash"
+    fn print(&self, kind: &str, heading: &str) {
+        if output::json() {
+            output::emit(kind, self);
+            return;
+        }
+        let committed = self.committed_to.as_ref().map_or(String::new(), |f| format!(" (committed to {})", f));
+        println!("{} {}: {}{}", heading, self.id, self.command, committed);
+        for c in &self.changes {
+            println!("  {} {} -> {}", c.package, c.before.as_deref().unwrap_or("(not installed)"), c.after.as_deref().unwrap_or("(removed)"));
+        }
+    }
+}
+
+/// The log of simulated transactions. Those after the last commit-to can be rolled back.
+pub struct Transactions {
+    log : Vec<Transaction>,
+    // how many transactions at the start of the log have been committed
+    committed : usize,
+}
+
+impl Transactions {
+    pub fn new() -> Transactions {
+        Transactions { log: vec![], committed: 0 }
+    }
+}
+
+impl Packages {
+    // Sets the installed version of each package (None removes it) and logs the transaction.
+    fn apply_transaction(&mut self, command: String, mut new_versions: Vec<(i32, Option<DebianVersionNum>)>) {
+        new_versions.sort_by_key(|(n, _)| self.get_package_name(*n));
+        let mut changes = vec![];
+        let mut undo = vec![];
+        for (num, after) in new_versions {
+            let before = match &after {
+                Some(v) => self.installed_debvers.insert(num, v.clone()),
+                None => self.installed_debvers.remove(&num)
+            };
+            changes.push(Change {
+                package : self.get_package_name(num).to_string(),
+                before : before.as_ref().map(|v| v.to_string()),
+                after : after.as_ref().map(|v| v.to_string()),
+            });
+            undo.push((num, before));
+        }
+        let transaction = Transaction { id: self.transactions.log.len() + 1, command, changes, committed_to: None, undo };
+        transaction.print("transaction", "transaction");
+        self.transactions.log.push(transaction);
+    }
+
+    /// Simulates installing the available version of package_name, along with whatever how-to-install says it needs.
+    pub fn install(&mut self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            output::no_such_package(package_name);
+            return;
+        }
+        let num = *self.get_package_num(package_name);
+        let mut plan = self.compute_how_to_install(package_name);
+        if self.available_debvers.get(&num) != self.installed_debvers.get(&num) && !plan.contains(&num) {
+            plan.push(num);
+        }
+        if plan.is_empty() {
+            output::status(&format!("{} is already installed at version {}", package_name, self.installed_debvers[&num]));
+            return;
+        }
+        let mut new_versions = vec![];
+        for n in plan {
+            match self.available_debvers.get(&n) {
+                Some(v) => new_versions.push((n, Some(v.clone()))),
+                None => { error!("can't install {}: {} has no available version; nothing was changed", package_name, self.get_package_name(n)); return }
+            }
+        }
+        self.apply_transaction(format!("install {}", package_name), new_versions);
+    }
+
+    /// Simulates removing package_name, and every installed package with a dependency that it leaves unsatisfied.
+    pub fn remove(&mut self, package_name: &str) {
+        if !self.package_exists(package_name) {
+            output::no_such_package(package_name);
+            return;
+        }
+        let num = *self.get_package_num(package_name);
+        if !self.installed_debvers.contains_key(&num) {
+            error!("{} is not installed", package_name);
+            return;
+        }
+        // Packages with a dependency that was satisfied before but isn't once the removed ones are gone have to go
+        // too, until nothing more breaks. Dependencies that were already broken don't count.
+        let satisfied_before : HashSet<(i32, usize)> = self.installed_debvers.keys()
+            .flat_map(|n| self.dependencies[n].iter().enumerate()
+                .filter(|(_, dep)| self.dep_is_satisfied(dep).is_some())
+                .map(move |(i, _)| (*n, i)))
+            .collect();
+        let before = self.installed_debvers.clone();
+        let mut removed = vec![num];
+        self.installed_debvers.remove(&num);
+        loop {
+            let broken : Vec<i32> = self.installed_debvers.keys()
+                .filter(|n| self.dependencies[n].iter().enumerate()
+                    .any(|(i, dep)| satisfied_before.contains(&(**n, i)) && self.dep_is_satisfied(dep).is_none()))
+                .copied()
+                .collect();
+            if broken.is_empty() { break; }
+            for n in broken {
+                self.installed_debvers.remove(&n);
+                removed.push(n);
+            }
+        }
+        self.installed_debvers = before;
+        self.apply_transaction(format!("remove {}", package_name), removed.into_iter().map(|n| (n, None)).collect());
+    }
+
+    /// Undoes the last transaction, or all of them since the last commit-to.
+    pub fn rollback(&mut self, all: bool) {
+        let committed = self.transactions.committed;
+        if self.transactions.log.len() == committed {
+            error!("no transactions to roll back{}", if committed > 0 { " since the last commit-to" } else { "" });
+            return;
+        }
+        let keep = if all { committed } else { self.transactions.log.len() - 1 };
+        for transaction in self.transactions.log.drain(keep..).rev().collect::<Vec<Transaction>>() {
+            for (num, version) in transaction.undo.iter().rev() {
+                match version {
+                    Some(v) => { self.installed_debvers.insert(*num, v.clone()); }
+                    None => { self.installed_debvers.remove(num); }
+                }
+            }
+            transaction.print("rollback", "rolled back");
+        }
+    }
+
+    /// Writes the installed state to a status file; the transactions so far can no longer be rolled back.
+    pub fn commit_to(&mut self, filename: &str) {
+        match self.write_status_file(filename) {
+            Ok(n) => {
+                if let Some(last) = self.transactions.log.last_mut() {
+                    last.committed_to = Some(filename.to_string());
+                }
+                self.transactions.committed = self.transactions.log.len();
+                if output::json() {
+                    output::emit("commit-to", &json!({ "path": filename, "packages": n, "transactions": self.transactions.committed }));
+                } else {
+                    println!("Wrote {} installed packages to {}", n, filename);
+                }
+            }
+            Err(e) => error!("could not write {}: {}", filename, e)
+        }
+    }
+
+    /// Prints every transaction so far, oldest first.
+    pub fn print_transactions(&self) {
+        if self.transactions.log.is_empty() {
+            output::status("no transactions");
+        }
+        for transaction in &self.transactions.log {
+            transaction.print("transaction", "transaction");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use curl::easy::{Easy2, Handler};
+
+use crate::Packages;
+
+/// TLS and proxy settings applied to every request, verification or download.
+#[derive(Default)]
+pub struct TransportSettings {
+    /// PEM file of CA certificates to trust instead of the system store
+    ca_bundle : Option<String>,
+    /// PEM client certificate and key for mutual TLS; the key may be in the certificate file
+    client_cert : Option<(String, Option<String>)>,
+    proxy : Proxy,
+}
+
+#[derive(Default)]
+enum Proxy {
+    /// libcurl's defaults: the http_proxy etc. variables
+    #[default]
+    Environment,
+    /// no proxy, whatever the environment says
+    Direct,
+    /// a proxy URL, e.g. http://proxy:3128
+    Url(String),
+}
+
+impl TransportSettings {
+    pub fn apply<H: Handler>(&self, easy: &mut Easy2<H>) -> Result<(), curl::Error> {
+        if let Some(ca_bundle) = &self.ca_bundle {
+            easy.cainfo(ca_bundle)?;
+        }
+        if let Some((cert, key)) = &self.client_cert {
+            easy.ssl_cert(cert)?;
+            easy.ssl_cert_type("PEM")?;
+            if let Some(key) = key {
+                easy.ssl_key(key)?;
+                easy.ssl_key_type("PEM")?;
+            }
+        }
+        match &self.proxy {
+            Proxy::Environment => {}
+            // an empty proxy overrides the environment variables
+            Proxy::Direct => easy.proxy("")?,
+            Proxy::Url(proxy) => easy.proxy(proxy)?
+        }
+        Ok(())
+    }
+}
+
+/// The URL of path on a verification server. Servers are host:port (plain HTTP) or a base URL with a scheme,
+/// e.g. https://checksums.example.org:8443.
+pub fn server_url(server: &str, path: &str) -> String {
+    if server.contains("://") {
+        format!("{}{}", server.trim_end_matches('/'), path)
+    } else {
+        format!("http://{}{}", server, path)
+    }
+}
+
+// "none" clears a setting
+fn file_setting(value: &str, what: &str) -> Result<Option<String>, ()> {
+    if value == "none" {
+        return Ok(None);
+    }
+    if !Path::new(value).is_file() {
+        error!("{} {} does not exist", what, value);
+        return Err(());
+    }
+    Ok(Some(value.to_string()))
+}
+
+impl Packages {
+    /// Trusts the CA certificates in a PEM file instead of the system store, for servers with a private CA.
+    pub fn set_ca_bundle(&mut self, path: &str) {
+        if let Ok(ca_bundle) = file_setting(path, "CA bundle") {
+            self.async_state.transport.ca_bundle = ca_bundle;
+        }
+    }
+
+    /// Presents a client certificate (and its key, unless it is in the same file) to servers that require mutual TLS.
+    pub fn set_client_cert(&mut self, cert: &str, key: Option<&str>) {
+        let cert = match file_setting(cert, "client certificate") {
+            Ok(Some(c)) => c,
+            Ok(None) => { self.async_state.transport.client_cert = None; return }
+            Err(()) => return
+        };
+        let key = match key.map(|k| file_setting(k, "client key")) {
+            None => None,
+            Some(Ok(k)) => k,
+            Some(Err(())) => return
+        };
+        self.async_state.transport.client_cert = Some((cert, key));
+    }
+
+    /// Sends requests through a proxy; "none" goes direct even if a proxy variable is set, and "env" goes back to
+    /// the http_proxy etc. variables.
+    pub fn set_proxy(&mut self, proxy: &str) {
+        self.async_state.transport.proxy = match proxy {
+            "none" => Proxy::Direct,
+            "env" => Proxy::Environment,
+            url => Proxy::Url(url.to_string())
+        };
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::Packages;
+use crate::packages::release::{Release, ReleaseText};
+
+const DEFAULT_LISTS_DIR : &str = "lists";
+
+/// One add-source line: where a Packages index lives on a Debian-style mirror.
+pub struct Repository {
+    base_url : String,
+    suite : String,
+    component : String,
+    arch : String,
+}
+
+impl Repository {
+    fn new(base_url: &str, suite: &str, component: &str, arch: &str) -> Repository {
+        Repository {
+            base_url : base_url.trim_end_matches('/').to_string(),
+            suite : suite.to_string(),
+            component : component.to_string(),
+            arch : arch.to_string(),
+        }
+    }
+
+    fn url(&self, rel: &str) -> String {
+        format!("{}/dists/{}/{}", self.base_url, self.suite, rel)
+    }
+
+    // path of the index within dists/<suite>, e.g. main/binary-amd64/Packages.xz
+    fn index(&self, name: &str) -> String {
+        format!("{}/binary-{}/{}", self.component, self.arch, name)
+    }
+
+    // Files are named the way apt's lists directory names them, e.g.
+    // deb.debian.org_debian_dists_sid_main_binary-amd64_Packages.xz, which is also how Release entries are matched.
+    fn local_path(&self, lists_dir: &str, rel: &str) -> PathBuf {
+        let host_path = self.base_url.split_once("://").map_or(self.base_url.as_str(), |(_, rest)| rest);
+        PathBuf::from(lists_dir).join(format!("{}_dists_{}_{}", host_path, self.suite, rel).replace('/', "_"))
+    }
+}
+
+pub struct Repositories {
+    list : Vec<Repository>,
+    lists_dir : String,
+    // what the last update loaded: the packages, and the Release and index files they came from
+    loaded : Vec<i32>,
+    files : Vec<String>,
+}
+
+impl Repositories {
+    pub fn new() -> Repositories {
+        Repositories { list: vec![], lists_dir: String::from(DEFAULT_LISTS_DIR), loaded: vec![], files: vec![] }
+    }
+}
+
+// sources that share a suite share its Release file
+fn unique(files: Vec<(String, PathBuf)>) -> Vec<(String, PathBuf)> {
+    let mut seen = vec![];
+    for f in files {
+        if !seen.contains(&f) {
+            seen.push(f);
+        }
+    }
+    seen
+}
+
+// the best index that the Release file lists, or Packages.xz without one
+fn pick_index(release: Option<&Release>, repo: &Repository) -> String {
+    let listed = |name: &str| release.is_none_or(|r| r.files.contains_key(&repo.index(name)));
+    ["Packages.xz", "Packages.gz", "Packages"].iter()
+        .find(|name| listed(name))
+        .map_or_else(|| repo.index("Packages.xz"), |name| repo.index(name))
+}
+
+impl Packages {
+    pub fn add_source(&mut self, base_url: &str, suite: &str, component: &str, arch: &str) {
+        let repo = Repository::new(base_url, suite, component, arch);
+        let list = &mut self.repositories.list;
+        if list.iter().any(|r| r.base_url == repo.base_url && r.suite == repo.suite && r.component == repo.component && r.arch == repo.arch) {
+            error!("source {} {} {} {} already added", base_url, suite, component, arch);
+            return;
+        }
+        list.push(repo);
+    }
+
+    /// Sets the directory that update downloads indexes to.
+    pub fn set_lists_dir(&mut self, dir: &str) {
+        self.repositories.lists_dir = dir.to_string();
+    }
+
+    /// Downloads the Release file and Packages index of every source, then loads them as load-release and
+    /// load-packages would, in place of what the previous update loaded. Files that haven't changed since the last
+    /// update aren't downloaded again. A source whose download fails is loaded from its previous copy, if there is one.
+    pub fn update(&mut self) {
+        if self.repositories.list.is_empty() {
+            error!("no sources; add one with add-source <base-url> <suite> <component> <arch>");
+            return;
+        }
+        let lists_dir = self.repositories.lists_dir.clone();
+        if let Err(e) = fs::create_dir_all(&lists_dir) {
+            error!("could not create {}: {}", lists_dir, e);
+            return;
+        }
+
+        // the Release files come first, since they say which compressions of the index the mirror has
+        let releases = unique(self.repositories.list.iter()
+            .map(|repo| (repo.url("Release"), repo.local_path(&lists_dir, "Release")))
+            .collect());
+        for (url, path) in &releases {
+            if self.keyring.is_some() {
+                let mut sig_path = path.clone().into_os_string();
+                sig_path.push(".gpg");
+                self.queue_index(&format!("{}.gpg", url), PathBuf::from(sig_path));
+            }
+            self.queue_index(url, path.clone());
+        }
+        self.execute();
+
+        let indexes = unique(self.repositories.list.iter()
+            .map(|repo| {
+                let release_path = repo.local_path(&lists_dir, "Release");
+                let release = fs::read_to_string(&release_path).ok()
+                    .and_then(|t| ReleaseText::new(t).ok())
+                    .map(|c| Release::parse(&release_path.to_string_lossy(), &c.text));
+                let index = pick_index(release.as_ref(), repo);
+                (repo.url(&index), repo.local_path(&lists_dir, &index))
+            })
+            .collect());
+        for (url, path) in &indexes {
+            self.queue_index(url, path.clone());
+        }
+        self.execute();
+
+        self.forget_update();
+        for (_, path) in releases.iter().filter(|(_, p)| p.exists()) {
+            let path = path.to_string_lossy();
+            self.parse_release(&path);
+            self.repositories.files.push(path.into_owned());
+        }
+        for (url, path) in indexes {
+            if path.exists() {
+                let path = path.to_string_lossy();
+                let loaded = self.load_packages(&path);
+                self.repositories.loaded.extend(loaded);
+                self.repositories.files.push(path.into_owned());
+            } else {
+                error!("no copy of {}; its packages are not loaded", url);
+            }
+        }
+    }
+
+    // Drops what the previous update loaded, so that packages the mirrors no longer list go away, as do the files
+    // of sources that were removed or whose index changed compression. The description of an installed package
+    // stays for search. Package numbers stay too, so every one keeps a (now empty) dependency list, as
+    // get_package_num_inserting gives it.
+    fn forget_update(&mut self) {
+        for num in std::mem::take(&mut self.repositories.loaded) {
+            self.available_debvers.remove(&num);
+            self.checksums.remove(&num);
+            self.package_files.remove(&num);
+            self.dependencies.insert(num, vec![]);
+            self.package_releases.remove(&num);
+            if !self.installed_debvers.contains_key(&num) {
+                self.details.remove(&num);
+            }
+        }
+        let files = std::mem::take(&mut self.repositories.files);
+        self.sources.retain(|s| !files.contains(&s.path));
+    }
+
+    /// Loads the Release file and index of a mirror source from the lists directory, as update left them.
+    pub(crate) fn load_downloaded_index(&mut self, name: &str, base_url: &str, suite: &str, component: &str, arch: &str) {
+        let repo = Repository::new(base_url, suite, component, arch);
+        let lists_dir = self.repositories.lists_dir.clone();
+        let release = repo.local_path(&lists_dir, "Release");
+        let index = ["Packages.xz", "Packages.gz", "Packages"].iter()
+            .map(|name| repo.local_path(&lists_dir, &repo.index(name)))
+            .find(|path| path.exists());
+        match index {
+            None => error!("source {} has not been downloaded to {}; run update", name, lists_dir),
+            Some(index) => {
+                if release.exists() {
+                    self.parse_release(&release.to_string_lossy());
+                }
+                self.parse_packages(&index.to_string_lossy());
+            }
+        }
+    }
+}
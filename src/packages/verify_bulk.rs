@@ -0,0 +1,72 @@
+use serde_json::json;
+
+use crate::Packages;
+use crate::packages::output;
+use crate::packages::report::Outcome;
+
+impl Packages {
+    // Queues one request per (package, version) pair and runs them all, with a progress counter and a summary.
+    // execute()'s connection limit keeps the number of requests in flight bounded however many are queued.
+    // Answers from the verification cache are reported first and count towards the summary, unless force is set.
+    fn verify_bulk(&mut self, what: &str, pairs: Vec<(String, String)>, force: bool) {
+        if pairs.is_empty() {
+            output::status(&format!("nothing to verify for {}", what));
+            return;
+        }
+        let first = self.verify_results().len();
+        let mut queued = 0;
+        for (pkg, version) in &pairs {
+            if !self.queue_verify(pkg, version, force).is_empty() {
+                queued += 1;
+            }
+        }
+        output::status(&format!("queued {} requests for {} ({} answered from the verification cache)", queued, what, pairs.len() - queued));
+        self.run_requests(true);
+
+        let results = &self.verify_results()[first..];
+        let count = |o: Outcome| results.iter().filter(|r| r.outcome == o).count();
+        let (matched, mismatched, no_local) = (count(Outcome::Match), count(Outcome::Mismatch), count(Outcome::NoLocalChecksum));
+        let failed = results.len() - matched - mismatched - no_local;
+        if output::json() {
+            output::emit("verify-summary", &json!({
+                "what": what, "verified": results.len(), "matched": matched, "mismatched": mismatched,
+                "no_local_checksum": no_local, "failed": failed,
+            }));
+            return;
+        }
+        println!("{}: {} verified, {} matched, {} mismatched, {} without local checksum, {} failed",
+            what, results.len(), matched, mismatched, no_local, failed);
+    }
+
+    /// Verifies the installed version of every installed package.
+    pub fn verify_installed(&mut self, force: bool) {
+        let mut pairs : Vec<(String, String)> = self.installed_debvers.iter()
+            .map(|(num, v)| (self.get_package_name(*num).to_string(), v.to_string()))
+            .collect();
+        pairs.sort();
+        self.verify_bulk("installed packages", pairs, force);
+    }
+
+    /// Verifies the available version of package_name and of everything how-to-install would pull in for it.
+    pub fn verify_plan(&mut self, package_name: &str, force: bool) {
+        if !self.package_exists(package_name) {
+            output::no_such_package(package_name);
+            return;
+        }
+        let mut nums = vec![*self.get_package_num(package_name)];
+        nums.extend(self.compute_how_to_install(package_name));
+        let pairs = nums.iter()
+            .filter_map(|num| self.available_debvers.get(num).map(|v| (self.get_package_name(*num).to_string(), v.to_string())))
+            .collect();
+        self.verify_bulk(&format!("plan for {}", package_name), pairs, force);
+    }
+
+    /// Verifies the available version of every available package.
+    pub fn verify_all(&mut self, force: bool) {
+        let mut pairs : Vec<(String, String)> = self.available_debvers.iter()
+            .map(|(num, v)| (self.get_package_name(*num).to_string(), v.to_string()))
+            .collect();
+        pairs.sort();
+        self.verify_bulk("available packages", pairs, force);
+    }
+}
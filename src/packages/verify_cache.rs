@@ -0,0 +1,179 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::Packages;
+use crate::packages::async_fns::DEFAULT_CACHE_DIR;
+use crate::packages::output;
+use crate::packages::report::{Outcome, VerifyResult};
+
+// the file's name in the cache directory, unless set-verify-cache gives another file
+const VERIFY_CACHE_NAME : &str = "verify-cache.jsonl";
+const DEFAULT_TTL_SECS : u64 = 24 * 60 * 60;
+
+/// One answer from a verification server, as stored in the verification cache (one JSON object per line).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VerifyRecord {
+    pub package : String,
+    pub version : String,
+    pub algorithm : String,
+    pub server : String,
+    pub outcome : Outcome,
+    /// the digest the server sent
+    pub received : String,
+    /// seconds since the Unix epoch
+    pub timestamp : u64,
+}
+
+/// Past verification results, so that enq-verify doesn't ask the server again about a (package, version)
+/// pair that it already answered for within the TTL. Only answers (HTTP 200) are kept; errors are always retried.
+pub struct VerifyCache {
+    // the file set with set-verify-cache, if any
+    path : Option<String>,
+    // the cache directory, which holds the file otherwise
+    dir : String,
+    ttl_secs : u64,
+    records : Vec<VerifyRecord>,
+    // the file is read on first use, so that set-verify-cache can come first
+    loaded : bool,
+}
+
+impl VerifyCache {
+    pub fn new() -> VerifyCache {
+        VerifyCache { path: None, dir: String::from(DEFAULT_CACHE_DIR), ttl_secs: DEFAULT_TTL_SECS, records: vec![], loaded: false }
+    }
+
+    fn path(&self) -> PathBuf {
+        match &self.path {
+            Some(p) => PathBuf::from(p),
+            None => Path::new(&self.dir).join(VERIFY_CACHE_NAME)
+        }
+    }
+
+    // moves to the given file and cache directory; the records are read again from there
+    fn reset(&mut self, path: Option<String>, dir: &str) {
+        *self = VerifyCache { path, dir: dir.to_string(), ttl_secs: self.ttl_secs, records: vec![], loaded: false };
+    }
+
+    /// Follows set-cache-dir, unless the file was set with set-verify-cache.
+    pub fn set_dir(&mut self, dir: &str) {
+        if self.path.is_some() {
+            self.dir = dir.to_string();
+        } else if dir != self.dir {
+            self.reset(None, dir);
+        }
+    }
+
+    fn load(&mut self) {
+        if self.loaded { return; }
+        self.loaded = true;
+        let path = self.path();
+        let text = match fs::read_to_string(&path) {
+            Ok(t) => t,
+            Err(_) => return // no cache yet
+        };
+        for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            match serde_json::from_str::<VerifyRecord>(line) {
+                Ok(r) => self.records.push(r),
+                Err(e) => output::warning(&format!("skipping line {} of {}: {}", n + 1, path.display(), e))
+            }
+        }
+    }
+
+    /// The latest answer for this request that is still within the TTL.
+    pub fn lookup(&mut self, package: &str, version: &str, algorithm: &str, server: &str) -> Option<&VerifyRecord> {
+        self.load();
+        let now = now_secs();
+        let ttl = self.ttl_secs;
+        self.records.iter().rev()
+            .find(|r| r.package == package && r.version == version && r.algorithm == algorithm && r.server == server)
+            .filter(|r| r.timestamp.saturating_add(ttl) > now)
+    }
+
+    /// Remembers the answers among results and appends them to the cache file.
+    pub fn record(&mut self, results: &[VerifyResult]) {
+        self.load();
+        let new : Vec<VerifyRecord> = results.iter()
+            .filter(|r| r.http_status == Some(200) && r.cached_at.is_none())
+            .filter_map(|r| Some(VerifyRecord {
+                package : r.package.clone(),
+                version : r.version.clone(),
+                algorithm : r.algorithm.clone(),
+                server : r.server.clone(),
+                outcome : r.outcome,
+                received : r.received.clone()?,
+                timestamp : r.received_at,
+            }))
+            .collect();
+        if new.is_empty() { return; }
+        let lines : String = new.iter().map(|r| serde_json::to_string(r).unwrap() + "\n").collect();
+        let path = self.path();
+        let written = path.parent().filter(|p| !p.as_os_str().is_empty()).map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+            .and_then(|mut f| f.write_all(lines.as_bytes()));
+        if let Err(e) = written {
+            error!("could not write {}: {}", path.display(), e);
+        }
+        self.records.extend(new);
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+pub fn format_timestamp(secs: u64) -> String {
+    // days to civil date, after Howard Hinnant's algorithm
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+impl Packages {
+    /// Switches to another cache file, keeping the TTL.
+    pub fn set_verify_cache(&mut self, path: &str) {
+        let dir = self.verify_cache.dir.clone();
+        self.verify_cache.reset(Some(path.to_string()), &dir);
+    }
+
+    /// Sets how long, in seconds, a cached answer spares asking the server again. 0 always asks.
+    pub fn set_verify_ttl(&mut self, secs: &str) {
+        match secs.parse::<u64>() {
+            Ok(s) => self.verify_cache.ttl_secs = s,
+            Err(_) => error!("bad TTL {}", secs)
+        }
+    }
+
+    /// Prints every cached verification answer for package_name, oldest first.
+    pub fn print_verify_history(&mut self, package_name: &str) {
+        self.verify_cache.load();
+        let records : Vec<&VerifyRecord> = self.verify_cache.records.iter().filter(|r| r.package == package_name).collect();
+        if output::json() {
+            output::emit("verify-history", &json!({ "package": package_name, "records": records }));
+            return;
+        }
+        if records.is_empty() {
+            println!("no verification history for {}", package_name);
+            return;
+        }
+        for r in records {
+            println!("{} {} version {} {} from {}: {} ({})",
+                format_timestamp(r.timestamp), r.package, r.version, r.algorithm, r.server,
+                r.outcome, r.received);
+        }
+    }
+}
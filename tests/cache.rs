@@ -0,0 +1,166 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::{rpkg_with, workdir, Interactive};
+
+// the fixtures come without a signed Release file
+const TRUST : &[&str] = &["--allow-unverified"];
+
+const QUERIES : &str = "info alpha --json\ninfo epsilon --json\ninfo zeta --json\ndeps delta --json\n";
+
+fn info(run: &common::Run) -> Vec<serde_json::Value> {
+    [run.objects("info"), run.objects("deps")].concat()
+}
+
+// rewrites the Packages file with beta at a different version, keeping the size the same
+fn bump_beta(dir: &Path) {
+    let path = dir.join("Packages");
+    let text = fs::read_to_string(&path).unwrap().replace("Version: 2.1\n", "Version: 2.2\n");
+    fs::write(&path, text).unwrap();
+}
+
+fn set_mtime(path: &Path, t: SystemTime) {
+    fs::File::options().write(true).open(path).unwrap().set_modified(t).unwrap();
+}
+
+#[test]
+fn round_trip_gives_the_same_answers() {
+    let dir = workdir(&["Packages", "status"]);
+    let parsed = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nload-installed status\nsave-cache c.bin\n{}", QUERIES));
+    assert_eq!(parsed.code, 0);
+    let cached = rpkg_with(dir.path(), TRUST, &format!("load-cache c.bin\n{}", QUERIES));
+    assert_eq!(cached.code, 0);
+    assert!(!cached.stdout.contains("stale"));
+    assert_eq!(info(&parsed).len(), 4);
+    assert_eq!(info(&parsed), info(&cached));
+}
+
+#[test]
+fn compressed_cache_round_trips() {
+    let dir = workdir(&["Packages", "status"]);
+    let parsed = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nload-installed status\nsave-cache c.bin.xz\n{}", QUERIES));
+    let cached = rpkg_with(dir.path(), TRUST, &format!("load-cache c.bin.xz\n{}", QUERIES));
+    assert_eq!(cached.code, 0);
+    assert_eq!(info(&parsed), info(&cached));
+}
+
+#[test]
+fn source_edited_after_save_is_reparsed() {
+    let dir = workdir(&["Packages", "status"]);
+    rpkg_with(dir.path(), TRUST, "load-packages Packages\nload-installed status\nsave-cache c.bin\n");
+    bump_beta(dir.path());
+    let run = rpkg_with(dir.path(), TRUST, "load-cache c.bin\ninfo beta --json\n");
+    assert!(run.stdout.contains("is stale: Packages changed"));
+    assert_eq!(run.objects("info")[0]["version"], "2.2");
+
+    // the rewritten cache is fresh again
+    let run = rpkg_with(dir.path(), TRUST, "load-cache c.bin\ninfo beta --json\n");
+    assert!(!run.stdout.contains("stale"));
+    assert_eq!(run.objects("info")[0]["version"], "2.2");
+}
+
+#[test]
+fn source_edited_between_load_and_save_is_stale() {
+    let dir = workdir(&["Packages", "status"]);
+    let mut session = Interactive::start(dir.path(), TRUST);
+    session.send("load-packages Packages", "Packages available");
+    bump_beta(dir.path());
+    session.send("save-cache c.bin", "Cache written");
+    assert_eq!(session.finish(), 0);
+
+    // the cache holds 2.1, which is not what the file says now
+    let run = rpkg_with(dir.path(), TRUST, "load-cache c.bin\ninfo beta --json\n");
+    assert!(run.stdout.contains("is stale: Packages changed"));
+    assert_eq!(run.objects("info")[0]["version"], "2.2");
+}
+
+#[test]
+fn touched_source_is_still_fresh() {
+    let dir = workdir(&["Packages", "status"]);
+    rpkg_with(dir.path(), TRUST, "load-packages Packages\nsave-cache c.bin\n");
+    set_mtime(&dir.path().join("Packages"), SystemTime::now() + Duration::from_secs(60));
+    let run = rpkg_with(dir.path(), TRUST, "load-cache c.bin\ninfo beta --json\n");
+    assert_eq!(run.code, 0);
+    assert!(!run.stdout.contains("stale"));
+    assert_eq!(run.objects("info")[0]["version"], "2.1");
+}
+
+#[test]
+fn same_size_edit_with_old_mtime_is_not_hashed() {
+    // an edit that keeps both the size and the modification time is taken on trust, as make would
+    let dir = workdir(&["Packages"]);
+    rpkg_with(dir.path(), TRUST, "load-packages Packages\nsave-cache c.bin\n");
+    let path = dir.path().join("Packages");
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+    bump_beta(dir.path());
+    set_mtime(&path, mtime);
+    let run = rpkg_with(dir.path(), TRUST, "load-cache c.bin\ninfo beta --json\n");
+    assert!(!run.stdout.contains("stale"));
+    assert_eq!(run.objects("info")[0]["version"], "2.1");
+}
+
+#[test]
+fn corrupt_cache_is_an_error_not_a_panic() {
+    let dir = workdir(&["Packages", "status"]);
+    rpkg_with(dir.path(), TRUST, "load-packages Packages\nload-installed status\nsave-cache c.bin\n");
+    let bytes = fs::read(dir.path().join("c.bin")).unwrap();
+
+    fs::write(dir.path().join("short.bin"), &bytes[..bytes.len() / 2]).unwrap();
+    let run = rpkg_with(dir.path(), TRUST, "load-cache short.bin\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: bad cache file short.bin: truncated cache file"));
+
+    // replace the first version string, "1.0-1" for alpha, with something that isn't a version
+    let at = bytes.windows(5).position(|w| w == b"1.0-1").unwrap();
+    let mut bad = bytes.clone();
+    bad[at..at + 5].copy_from_slice(b"1.0 \xff");
+    fs::write(dir.path().join("bad.bin"), &bad).unwrap();
+    let run = rpkg_with(dir.path(), TRUST, "load-cache bad.bin\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: bad cache file bad.bin"));
+}
+
+#[cfg(unix)]
+#[test]
+fn failure_to_write_a_compressed_cache_is_reported() {
+    let dir = workdir(&["Packages"]);
+    std::os::unix::fs::symlink("/dev/full", dir.path().join("c.bin.xz")).unwrap();
+    let run = rpkg_with(dir.path(), TRUST, "load-packages Packages\nsave-cache c.bin.xz\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: could not write cache c.bin.xz"));
+    assert!(!run.stdout.contains("Cache written"));
+}
+
+#[test]
+fn packages_keep_their_release_through_the_cache() {
+    let dir = workdir(&["Packages", "keyring.asc", "Release", "Release.gpg"]);
+    let args = &["--keyring", "keyring.asc"];
+    let parsed = rpkg_with(dir.path(), args, "load-release Release\nload-packages Packages\nsave-cache c.bin\ninfo alpha\ninfo alpha --json\n");
+    assert_eq!(parsed.code, 0);
+    let cached = rpkg_with(dir.path(), args, "load-cache c.bin\ninfo alpha\ninfo alpha --json\n");
+    assert_eq!(cached.code, 0);
+    assert!(!cached.stdout.contains("stale"));
+    for run in [&parsed, &cached] {
+        assert!(run.stdout.contains("Origin: Fixture\nSuite: sid\n"));
+    }
+    assert_eq!(cached.objects("info")[0]["suite"], "sid");
+    assert_eq!(parsed.objects("info"), cached.objects("info"));
+}
+
+#[test]
+fn truncated_cache_leaves_the_database_as_it_was() {
+    let dir = workdir(&["Packages", "status"]);
+    rpkg_with(dir.path(), TRUST, "load-packages Packages\nload-installed status\nsave-cache c.bin\n");
+    let bytes = fs::read(dir.path().join("c.bin")).unwrap();
+    // cut in the dependencies, after the names, versions and checksums
+    fs::write(dir.path().join("short.bin"), &bytes[..bytes.len() - 40]).unwrap();
+    let run = rpkg_with(dir.path(), &["--allow-unverified", "--keep-going"], "load-installed status --json\nload-cache short.bin\nload-installed status --json\ninfo alpha --json\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: bad cache file short.bin: truncated cache file"));
+    let counts : Vec<_> = run.objects("loaded").iter().map(|l| (l["available"].clone(), l["installed"].clone())).collect();
+    assert_eq!(counts, [(0.into(), 4.into()), (0.into(), 4.into())]);
+    assert!(run.objects("info")[0]["version"].is_null());
+}
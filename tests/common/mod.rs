@@ -0,0 +1,305 @@
+// Helpers for the integration tests: running rpkg on a script, and local HTTP stand-ins for the servers it talks to.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// A scratch directory holding copies of the named fixtures.
+pub fn workdir(fixtures: &[&str]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in fixtures {
+        fs::copy(fixture(name), dir.path().join(name)).unwrap();
+    }
+    dir
+}
+
+pub struct Run {
+    pub stdout : String,
+    pub code : i32,
+}
+
+impl Run {
+    /// The lines that are JSON objects of the given type.
+    pub fn objects(&self, kind: &str) -> Vec<serde_json::Value> {
+        self.stdout.lines()
+            .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+            .filter(|v| v["type"] == kind)
+            .collect()
+    }
+}
+
+/// Runs rpkg in dir with script on standard input. HOME points at dir so that no user config is picked up,
+/// and proxy variables are cleared so that requests go straight to the stand-ins.
+pub fn rpkg(dir: &Path, script: &str) -> Run {
+    rpkg_with(dir, &[], script)
+}
+
+fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rpkg"));
+    command.args(args).current_dir(dir).env("HOME", dir).env_remove("XDG_CONFIG_HOME");
+    for var in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY", "all_proxy", "ALL_PROXY", "no_proxy", "NO_PROXY"] {
+        command.env_remove(var);
+    }
+    command
+}
+
+pub fn rpkg_with(dir: &Path, args: &[&str], script: &str) -> Run {
+    rpkg_env(dir, args, &[], script)
+}
+
+/// Like rpkg_with, with extra environment variables.
+pub fn rpkg_env(dir: &Path, args: &[&str], env: &[(&str, &str)], script: &str) -> Run {
+    let mut command = command(dir, args);
+    command.envs(env.iter().copied());
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    print!("{}", stdout);
+    Run { stdout, code: output.status.code().unwrap_or(-1) }
+}
+
+/// An rpkg process that is fed its script one command at a time, so that a test can change files between commands.
+pub struct Interactive {
+    child : std::process::Child,
+    stdout : BufReader<std::process::ChildStdout>,
+}
+
+impl Interactive {
+    pub fn start(dir: &Path, args: &[&str]) -> Interactive {
+        let mut child = command(dir, args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit()).spawn().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Interactive { child, stdout }
+    }
+
+    /// Sends one command and returns the output lines up to and including the first that contains until.
+    pub fn send(&mut self, command: &str, until: &str) -> Vec<String> {
+        writeln!(self.child.stdin.as_mut().unwrap(), "{}", command).unwrap();
+        self.read_until(until)
+    }
+
+    /// Returns the output lines up to and including the first that contains until.
+    pub fn read_until(&mut self, until: &str) -> Vec<String> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            assert!(self.stdout.read_line(&mut line).unwrap() > 0, "rpkg exited before printing {:?}: {:?}", until, lines);
+            print!("{}", line);
+            let done = line.contains(until);
+            lines.push(line);
+            if done { return lines; }
+        }
+    }
+
+    /// Closes standard input and returns the exit code.
+    pub fn finish(mut self) -> i32 {
+        drop(self.child.stdin.take());
+        let mut rest = String::new();
+        self.stdout.read_to_string(&mut rest).unwrap();
+        print!("{}", rest);
+        self.child.wait().unwrap().code().unwrap_or(-1)
+    }
+
+    /// Stops a process that doesn't exit on its own, such as rpkg serve.
+    pub fn kill(mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+}
+
+pub struct Request {
+    pub method : String,
+    pub path : String,
+    pub headers : Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status : u32,
+    pub headers : Vec<(String, String)>,
+    pub body : Vec<u8>,
+    /// wait this long before answering
+    pub delay : Duration,
+}
+
+impl Response {
+    pub fn new(status: u32, body: impl Into<Vec<u8>>) -> Response {
+        Response { status, headers: vec![], body: body.into(), delay: Duration::ZERO }
+    }
+
+    pub fn delayed(mut self, delay: Duration) -> Response {
+        self.delay = delay;
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// A local HTTP server on an ephemeral port that answers every request with handler, one thread per
+/// connection, and remembers the request paths. It runs until the test process exits.
+pub struct StandIn {
+    pub addr : SocketAddr,
+    requests : Arc<Mutex<Vec<String>>>,
+}
+
+impl StandIn {
+    pub fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> StandIn {
+        Self::start_with(handler, Ok)
+    }
+
+    /// Like start, but each connection goes through wrap first, e.g. for a TLS handshake.
+    pub fn start_with<S: Read + Write + 'static>(handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+                                                 wrap: impl Fn(TcpStream) -> std::io::Result<S> + Send + Sync + 'static) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler : Arc<Handler> = Arc::new(handler);
+        let wrap = Arc::new(wrap);
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                let (handler, wrap, seen) = (Arc::clone(&handler), Arc::clone(&wrap), Arc::clone(&seen));
+                thread::spawn(move || {
+                    // failed handshakes and dropped connections are what some tests are about
+                    let _ = wrap(stream).and_then(|stream| answer(stream, &*handler, &seen));
+                });
+            }
+        });
+        StandIn { addr, requests }
+    }
+
+    /// Serves the files under root; anything else is a 404. Honours If-Modified-Since with the files' mtimes.
+    pub fn serve_dir(root: PathBuf) -> StandIn {
+        Self::start(move |req| file_response(&root, req))
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// host:port, the way set-server takes a plain HTTP server.
+    pub fn host(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// The checksums in the fixture Packages file, by package, version and algorithm (md5, sha256).
+pub fn fixture_checksums() -> HashMap<(String, String, String), String> {
+    let text = fs::read_to_string(fixture("Packages")).unwrap();
+    let mut sums = HashMap::new();
+    for para in rpkg::deb822::paragraphs(&text) {
+        let (package, version) = (para.get("Package").unwrap(), para.get("Version").unwrap());
+        for (field, algorithm) in [("MD5sum", "md5"), ("SHA256", "sha256")] {
+            if let Some(sum) = para.get(field) {
+                sums.insert((package.to_string(), version.to_string(), algorithm.to_string()), sum.to_string());
+            }
+        }
+    }
+    sums
+}
+
+/// Answers the verification server's /rest/v1/checksums/<pkg>/<version> and
+/// /rest/v2/checksums/<pkg>/<version>/<algorithm> from sums, and 404 otherwise.
+pub fn checksum_response(sums: &HashMap<(String, String, String), String>, req: &Request) -> Response {
+    let parts : Vec<String> = req.path.split('/').map(|p| urlencoding::decode(p).unwrap().into_owned()).collect();
+    let key = match &parts.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["", "rest", "v1", "checksums", pkg, version] => (pkg.to_string(), version.to_string(), String::from("md5")),
+        ["", "rest", "v2", "checksums", pkg, version, algorithm] => (pkg.to_string(), version.to_string(), algorithm.to_string()),
+        _ => return Response::new(404, "")
+    };
+    match sums.get(&key) {
+        Some(sum) => Response::new(200, sum.clone()),
+        None => Response::new(404, "")
+    }
+}
+
+/// A verification server that knows the checksums in the fixture Packages file.
+pub fn checksum_server() -> StandIn {
+    let sums = fixture_checksums();
+    StandIn::start(move |req| checksum_response(&sums, req))
+}
+
+pub fn file_response(root: &Path, req: &Request) -> Response {
+    let path = root.join(req.path.trim_start_matches('/'));
+    let modified = match fs::metadata(&path).and_then(|m| m.modified()) {
+        Ok(m) => m,
+        Err(_) => return Response::new(404, "")
+    };
+    let last_modified = httpdate(modified);
+    if req.header("If-Modified-Since") == Some(last_modified.as_str()) {
+        return Response::new(304, "");
+    }
+    let mut response = Response::new(200, fs::read(&path).unwrap());
+    response.headers.push((String::from("Last-Modified"), last_modified));
+    response
+}
+
+fn answer<S: Read + Write>(stream: S, handler: &Handler, seen: &Mutex<Vec<String>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() { break; }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut words = request_line.split_whitespace();
+    let request = Request {
+        method : words.next().unwrap_or("").to_string(),
+        path : words.next().unwrap_or("").to_string(),
+        headers,
+    };
+    seen.lock().unwrap().push(request.path.clone());
+    let response = handler(&request);
+    thread::sleep(response.delay);
+    let mut stream = reader.into_inner();
+    write!(stream, "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len())?;
+    for (name, value) in &response.headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(stream, "\r\n")?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+// an HTTP date, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+fn httpdate(t: std::time::SystemTime) -> String {
+    let secs = t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][(days % 7) as usize];
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let month_name = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"][(month - 1) as usize];
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", weekday, day, month_name, year, rem / 3600, rem % 3600 / 60, rem % 60)
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use common::{rpkg_with, workdir};
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn xz(data: &[u8]) -> Vec<u8> {
+    let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn infos(dir: &Path, load: &str) -> (i32, Vec<serde_json::Value>) {
+    let run = rpkg_with(dir, &["--keep-going", "--allow-unverified"], &format!("{}\ninfo alpha --json\ninfo epsilon --json\n", load));
+    (run.code, run.objects("info"))
+}
+
+#[test]
+fn compressed_indexes_load_like_plain_ones() {
+    let dir = workdir(&["Packages", "status"]);
+    let plain = fs::read(dir.path().join("Packages")).unwrap();
+    // the names don't say what the compression is
+    fs::write(dir.path().join("Packages.a"), gzip(&plain)).unwrap();
+    fs::write(dir.path().join("Packages.b"), xz(&plain)).unwrap();
+    let (code, expected) = infos(dir.path(), "load-packages Packages");
+    assert_eq!(code, 0);
+    assert_eq!(expected.len(), 2);
+    assert_eq!(infos(dir.path(), "load-packages Packages.a"), (0, expected.clone()));
+    assert_eq!(infos(dir.path(), "load-packages Packages.b"), (0, expected));
+}
+
+#[test]
+fn truncated_index_loads_nothing() {
+    let dir = workdir(&["Packages"]);
+    let packed = xz(&fs::read(dir.path().join("Packages")).unwrap());
+    fs::write(dir.path().join("Packages.xz"), &packed[..packed.len() - 20]).unwrap();
+    let run = rpkg_with(dir.path(), &["--keep-going", "--allow-unverified"], "load-packages Packages.xz\ninfo alpha --json\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("could not read Packages.xz"));
+    assert!(run.stdout.contains("Packages available: 0"));
+    assert!(run.objects("info").is_empty());
+}
+
+#[test]
+fn non_utf8_bytes_are_replaced_whether_compressed_or_not() {
+    let dir = workdir(&["Packages"]);
+    let text = fs::read_to_string(dir.path().join("Packages")).unwrap();
+    // a Maintainer of "René" in Latin-1
+    let mut latin1 = text.replacen("Package: alpha\n", "Package: alpha\nMaintainer: Ren#\n", 1).into_bytes();
+    let at = latin1.iter().position(|b| *b == b'#').unwrap();
+    latin1[at] = 0xe9;
+    fs::write(dir.path().join("Latin1"), &latin1).unwrap();
+    fs::write(dir.path().join("Latin1.gz"), gzip(&latin1)).unwrap();
+    let (code, plain) = infos(dir.path(), "load-packages Latin1");
+    assert_eq!(code, 0);
+    assert_eq!(plain.len(), 2);
+    assert_eq!(infos(dir.path(), "load-packages Latin1.gz"), (0, plain));
+}
+
+#[test]
+fn compressed_status_files_are_complete() {
+    let dir = workdir(&["status"]);
+    let run = rpkg_with(dir.path(), &[], "load-installed status\nwrite-installed out.gz\nwrite-installed out.xz\nwrite-installed out\n");
+    assert_eq!(run.code, 0);
+    let plain = fs::read(dir.path().join("out")).unwrap();
+    let mut unpacked = vec![];
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&fs::read(dir.path().join("out.gz")).unwrap()[..]), &mut unpacked).unwrap();
+    assert_eq!(unpacked, plain);
+    unpacked.clear();
+    std::io::Read::read_to_end(&mut xz2::read::XzDecoder::new(&fs::read(dir.path().join("out.xz")).unwrap()[..]), &mut unpacked).unwrap();
+    assert_eq!(unpacked, plain);
+}
+
+#[cfg(unix)]
+#[test]
+fn status_file_write_errors_are_reported() {
+    let dir = workdir(&["status"]);
+    for name in ["full", "full.xz"] {
+        std::os::unix::fs::symlink("/dev/full", dir.path().join(name)).unwrap();
+        let run = rpkg_with(dir.path(), &[], &format!("load-installed status\nwrite-installed {}\n", name));
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains(&format!("could not write {}: No space left on device", name)));
+    }
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::fs;
+
+use common::{fixture, rpkg_with, workdir};
+
+#[test]
+fn missing_built_in_defaults_are_skipped() {
+    let dir = workdir(&[]);
+    fs::create_dir(dir.path().join("data")).unwrap();
+    fs::copy(fixture("status"), dir.path().join("data/installed-packages")).unwrap();
+    let run = rpkg_with(dir.path(), &["-c", "load-defaults"], "");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Warning: skipping default file data/mirror.csclub.uwaterloo.ca_debian_dists_sid_main_binary-amd64_Packages: it does not exist"));
+    assert!(run.stdout.contains("Packages installed: 4"));
+}
+
+#[test]
+fn config_sources_are_loaded() {
+    let dir = workdir(&["Packages", "status"]);
+    fs::write(dir.path().join("rpkg.toml"), "installed = \"status\"\n[sources.main]\npackages = \"Packages\"\n").unwrap();
+    let run = rpkg_with(dir.path(), &["--config", "rpkg.toml", "--allow-unverified", "-c", "load-defaults"], "");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Packages available: 6"));
+    assert!(run.stdout.contains("Packages installed: 4"));
+    assert!(!run.stdout.contains("skipping"));
+}
+
+#[test]
+fn missing_files_named_in_the_config_are_errors() {
+    let dir = workdir(&["status"]);
+    fs::write(dir.path().join("rpkg.toml"), "installed = \"status\"\n[sources.main]\npackages = \"nowhere/Packages\"\n").unwrap();
+    let run = rpkg_with(dir.path(), &["--config", "rpkg.toml", "-c", "load-defaults"], "");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("could not read nowhere/Packages"));
+}
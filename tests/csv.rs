@@ -0,0 +1,34 @@
+mod common;
+
+use std::fs;
+
+use common::{rpkg_with, workdir};
+
+const ARGS : &[&str] = &["--allow-unverified", "--keep-going"];
+
+#[test]
+fn csv_file_loads() {
+    let dir = workdir(&[]);
+    fs::write(dir.path().join("sums.csv"), "name,version,hash\nalpha,1.0-1,298008e8feadfd4536153f17174b897b\nbeta,2.1,\n").unwrap();
+    let run = rpkg_with(dir.path(), ARGS, "load-csv sums.csv\ninfo alpha --json\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Packages available: 2"));
+    assert_eq!(run.objects("info")[0]["checksums"]["md5"], "298008e8feadfd4536153f17174b897b");
+}
+
+#[test]
+fn bad_csv_files_fail_the_command_and_load_nothing() {
+    let dir = workdir(&[]);
+    fs::write(dir.path().join("short.csv"), "name,version,hash\nalpha,1.0-1,298008e8feadfd4536153f17174b897b\nbeta\n").unwrap();
+    fs::write(dir.path().join("ragged.csv"), "name,version,hash\nalpha,1.0-1,298008e8feadfd4536153f17174b897b\nbeta,2.1,x,y\n").unwrap();
+    fs::write(dir.path().join("empty-version.csv"), "name,version\nalpha,\n").unwrap();
+    let run = rpkg_with(dir.path(), ARGS, "load-csv nowhere.csv\nload-csv short.csv\nload-csv ragged.csv\nload-csv empty-version.csv\ninfo alpha --json\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: could not read nowhere.csv: No such file or directory"));
+    assert!(run.stdout.contains("Error: could not read short.csv: "));
+    assert!(run.stdout.contains("Error: could not read ragged.csv: "));
+    assert!(run.stdout.contains("Error: could not read empty-version.csv: missing name or version on line 2"));
+    assert_eq!(run.stdout.matches("Packages available: 0").count(), 4);
+    assert!(run.objects("info").is_empty());
+    assert!(run.stdout.contains("no such package alpha"));
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+// fetches package into dir/cache from a stand-in mirror serving the fixture pool
+fn fetch(dir: &std::path::Path, package: &str) -> (Run, StandIn) {
+    let mirror = StandIn::serve_dir(fixture(""));
+    let run = rpkg_with(dir, TRUST, &format!("load-packages Packages\nset-mirror {}\nset-cache-dir cache\nfetch {}\n", mirror.url(), package));
+    (run, mirror)
+}
+
+// changes the value of field in the stanza of package in dir/Packages
+fn edit_field(dir: &std::path::Path, package: &str, field: &str, value: &str) {
+    let path = dir.join("Packages");
+    let text = fs::read_to_string(&path).unwrap();
+    let stanzas : Vec<String> = text.split("\n\n").map(|stanza| {
+        if !stanza.starts_with(&format!("Package: {}\n", package)) {
+            return stanza.to_string();
+        }
+        stanza.lines().map(|l| if l.starts_with(&format!("{}:", field)) { format!("{}: {}", field, value) } else { l.to_string() })
+            .collect::<Vec<String>>().join("\n")
+    }).collect();
+    fs::write(&path, stanzas.join("\n\n")).unwrap();
+}
+
+#[test]
+fn good_download_is_kept() {
+    let dir = workdir(&["Packages"]);
+    let (run, _mirror) = fetch(dir.path(), "alpha");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("fetched alpha version 1.0-1 to cache/alpha_1.0-1_amd64.deb (1200 bytes, md5, sha256 ok)"));
+    assert_eq!(fs::read(dir.path().join("cache/alpha_1.0-1_amd64.deb")).unwrap(), fs::read(fixture("pool/alpha_1.0-1_amd64.deb")).unwrap());
+}
+
+#[test]
+fn good_copy_is_not_downloaded_again() {
+    let dir = workdir(&["Packages"]);
+    fetch(dir.path(), "beta");
+    let (run, mirror) = fetch(dir.path(), "beta");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("package beta version 2.1 already in cache at cache/beta_2.1_amd64.deb"));
+    assert!(mirror.requests().is_empty());
+}
+
+#[test]
+fn size_mismatch_is_rejected() {
+    let dir = workdir(&["Packages"]);
+    edit_field(dir.path(), "alpha", "Size", "1201");
+    let (run, _mirror) = fetch(dir.path(), "alpha");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("rejected download of package alpha version 1.0-1: size 1200 does not match 1201"));
+    assert!(!dir.path().join("cache/alpha_1.0-1_amd64.deb").exists());
+    assert!(!dir.path().join("cache/alpha_1.0-1_amd64.deb.part").exists());
+}
+
+#[test]
+fn hash_mismatch_is_rejected() {
+    let dir = workdir(&["Packages"]);
+    let wrong = "0".repeat(64);
+    edit_field(dir.path(), "gamma", "SHA256", &wrong);
+    let (run, _mirror) = fetch(dir.path(), "gamma");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains(&format!("rejected download of package gamma version 0.5: SHA256 5da4d918ebcb5227013f82b0f75da5186d802baed9ef7b31ca5d2618360de4bf does not match {}", wrong)));
+    assert!(!dir.path().join("cache/gamma_0.5_all.deb").exists());
+    assert!(!dir.path().join("cache/gamma_0.5_all.deb.part").exists());
+}
+
+#[test]
+fn rejection_is_reported_in_json() {
+    let dir = workdir(&["Packages"]);
+    edit_field(dir.path(), "alpha", "Size", "1201");
+    let mirror = StandIn::serve_dir(fixture(""));
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-output json\nset-mirror {}\nset-cache-dir cache\nfetch alpha\n", mirror.url()));
+    assert_eq!(run.code, 1);
+    assert!(run.objects("error")[0]["message"].as_str().unwrap().starts_with("rejected download of package alpha"));
+}
+
+#[test]
+fn missing_file_is_an_error() {
+    let dir = workdir(&["Packages"]);
+    let (run, _mirror) = fetch(dir.path(), "delta");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("got error 404 on download of package delta version 1.0"));
+    assert!(!dir.path().join("cache/delta_1.0_amd64.deb.part").exists());
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::*;
+
+// every line of the output is a JSON object with a type
+fn all_json(run: &Run) -> Vec<serde_json::Value> {
+    run.stdout.lines().map(|line| {
+        let value : serde_json::Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("not JSON: {:?}: {}", line, e));
+        assert!(value["type"].is_string(), "no type: {}", line);
+        value
+    }).collect()
+}
+
+#[test]
+fn loading_and_writing_print_json() {
+    let dir = workdir(&["Packages", "status", "keyring.asc", "InRelease"]);
+    let server = checksum_server();
+    let script = format!("set-output json\nset-keyring keyring.asc\nload-release InRelease\nload-packages Packages\nload-installed status\n\
+                          save-cache c.bin\nload-cache c.bin\nwrite-installed out-status\ninstall delta\ncommit-to committed-status\n\
+                          set-server {}\nenq-verify alpha\nexecute\nverify-report report.json\n", server.host());
+    let run = rpkg(dir.path(), &script);
+    assert_eq!(run.code, 0);
+    all_json(&run);
+    assert_eq!(run.objects("keyring")[0]["keys"], 1);
+    let release = &run.objects("release")[0];
+    assert_eq!(release["path"], "InRelease");
+    assert_eq!(release["suite"], "sid");
+    assert_eq!(release["signed_by"], "2c7648da4d16adf3");
+    assert!(release["unverified_reason"].is_null());
+    let loaded = run.objects("loaded");
+    assert_eq!(loaded[0]["file"], "Packages");
+    assert_eq!(loaded[0]["available"], 6);
+    assert_eq!(loaded[1]["file"], "status");
+    assert_eq!(loaded[2]["file"], "c.bin");
+    assert_eq!(run.objects("save-cache")[0]["path"], "c.bin");
+    assert_eq!(run.objects("write-installed")[0]["path"], "out-status");
+    assert_eq!(run.objects("commit-to")[0]["transactions"], 1);
+    let report = &run.objects("verify-report")[0];
+    assert_eq!(report["format"], "json");
+    assert_eq!(report["requests"], 1);
+}
+
+#[test]
+fn warnings_are_json_objects() {
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], "load-packages Packages --json\n");
+    assert_eq!(run.code, 0);
+    let warning = &all_json(&run)[0];
+    assert_eq!(warning["type"], "warning");
+    assert_eq!(warning["message"], "loading unauthenticated index Packages: it is not listed in any loaded Release file");
+}
+
+#[test]
+fn json_flag_applies_to_one_command() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], "load-installed status --json\nwrite-installed out\n");
+    assert_eq!(run.code, 0);
+    let lines : Vec<&str> = run.stdout.lines().collect();
+    assert!(serde_json::from_str::<serde_json::Value>(lines[0]).is_ok());
+    assert_eq!(lines[1], "Wrote 4 installed packages to out");
+}
+
+#[test]
+fn update_prints_only_json() {
+    let root = tempfile::tempdir().unwrap();
+    let dists = root.path().join("dists/sid");
+    std::fs::create_dir_all(dists.join("main/binary-amd64")).unwrap();
+    std::fs::copy(fixture("Release"), dists.join("Release")).unwrap();
+    std::fs::copy(fixture("Packages"), dists.join("main/binary-amd64/Packages")).unwrap();
+    let mirror = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&[]);
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], &format!("add-source {} sid main amd64\nupdate --json\n", mirror.url()));
+    assert_eq!(run.code, 0);
+    let kinds : Vec<String> = all_json(&run).iter().map(|v| v["type"].as_str().unwrap().to_string()).collect();
+    assert_eq!(kinds, ["index", "index", "release", "warning", "loaded"]);
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use std::fs;
+
+use common::{rpkg_with, workdir};
+
+const SIGNED : &[&str] = &["Packages", "keyring.asc", "Release", "Release.gpg", "InRelease", "InRelease.tampered", "InRelease.other-key"];
+
+#[test]
+fn inrelease_fields_come_from_the_signed_text() {
+    let dir = workdir(SIGNED);
+    let run = rpkg_with(dir.path(), &[], "set-keyring keyring.asc\nload-release InRelease\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Signature: good, key 2c7648da4d16adf3"));
+    assert!(run.stdout.contains("Release InRelease: Origin Fixture, Suite sid, Codename sid, Components main, Architectures amd64, 1 files"));
+}
+
+#[test]
+fn text_outside_the_signed_message_is_refused() {
+    let dir = workdir(SIGNED);
+    let signed = fs::read_to_string(dir.path().join("InRelease")).unwrap();
+    fs::write(dir.path().join("Appended"), format!("{}\nSuite: injected\n", signed)).unwrap();
+    let headers = signed.replacen("Hash: SHA256\n", "Hash: SHA256\nSuite: injected\n", 1);
+    fs::write(dir.path().join("Headers"), headers).unwrap();
+    for name in ["Appended", "Headers"] {
+        let run = rpkg_with(dir.path(), &[], &format!("set-keyring keyring.asc\nload-release {}\n", name));
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains(&format!("Error: could not parse {}: bad clearsigned message", name)));
+        assert!(!run.stdout.contains("injected"));
+    }
+}
+
+#[test]
+fn tampered_inrelease_parses_but_does_not_verify() {
+    let dir = workdir(SIGNED);
+    let run = rpkg_with(dir.path(), &[], "set-keyring keyring.asc\nload-release InRelease.tampered\n");
+    assert!(run.stdout.contains("Signature: not verified"));
+    assert!(run.stdout.contains("Suite stable"));
+}
+
+const KEYRING : &[&str] = &["--keyring", "keyring.asc"];
+
+fn load(dir: &std::path::Path, args: &[&str], release: &str) -> common::Run {
+    rpkg_with(dir, args, &format!("load-release {}\nload-packages Packages\ninfo alpha --json\n", release))
+}
+
+#[test]
+fn index_from_a_good_inrelease_loads() {
+    let dir = workdir(SIGNED);
+    let run = load(dir.path(), KEYRING, "InRelease");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Keyring keyring.asc: 1 keys"));
+    assert!(!run.stdout.contains("Warning"));
+    assert_eq!(run.objects("info")[0]["suite"], "sid");
+}
+
+#[test]
+fn index_from_a_good_detached_signature_loads() {
+    let dir = workdir(SIGNED);
+    let run = load(dir.path(), KEYRING, "Release");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Signature: good, key 2c7648da4d16adf3"));
+    assert_eq!(run.objects("info")[0]["origin"], "Fixture");
+}
+
+#[test]
+fn index_from_a_bad_signature_is_refused() {
+    let dir = workdir(SIGNED);
+    for release in ["InRelease.tampered", "InRelease.other-key"] {
+        let run = load(dir.path(), KEYRING, release);
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains(&format!("Error: refusing to load unauthenticated index Packages: {} is not signed by a key in keyring.asc", release)));
+    }
+
+    // a good signature over a different Release file
+    fs::copy(dir.path().join("Release.gpg"), dir.path().join("Other.gpg")).unwrap();
+    fs::write(dir.path().join("Other"), fs::read_to_string(dir.path().join("Release")).unwrap().replace("Fixture", "Other")).unwrap();
+    let run = load(dir.path(), KEYRING, "Other");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Signature: not verified (no good signature by a key in keyring.asc)"));
+}
+
+#[test]
+fn index_from_an_unsigned_release_is_refused() {
+    let dir = workdir(SIGNED);
+    fs::copy(dir.path().join("Release"), dir.path().join("Unsigned")).unwrap();
+    let run = load(dir.path(), KEYRING, "Unsigned");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Signature: not verified (not clearsigned and no Unsigned.gpg)"));
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated index Packages"));
+    assert!(run.objects("info").is_empty());
+}
+
+#[test]
+fn index_is_refused_without_a_keyring() {
+    let dir = workdir(SIGNED);
+    let run = load(dir.path(), &[], "InRelease");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated index Packages: InRelease can't be verified without a keyring"));
+
+    let run = rpkg_with(dir.path(), &[], "load-packages Packages\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("it is not listed in any loaded Release file"));
+}
+
+#[test]
+fn index_that_does_not_match_a_good_release_is_refused() {
+    let dir = workdir(SIGNED);
+    let path = dir.path().join("Packages");
+    fs::write(&path, fs::read_to_string(&path).unwrap().replace("Version: 2.1\n", "Version: 2.2\n")).unwrap();
+    let run = load(dir.path(), KEYRING, "InRelease");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated index Packages: it is not listed in any loaded Release file"));
+}
+
+#[test]
+fn override_loads_unverified_index_with_a_warning() {
+    let dir = workdir(SIGNED);
+    let run = load(dir.path(), &["--keyring", "keyring.asc", "--allow-unverified"], "InRelease.tampered");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Warning: loading unauthenticated index Packages"));
+    assert_eq!(run.objects("info").len(), 1);
+
+    let run = rpkg_with(dir.path(), &[], "set-allow-unverified true\nload-packages Packages\n");
+    assert_eq!(run.code, 0);
+}
+
+#[test]
+fn csv_needs_the_override() {
+    let dir = workdir(SIGNED);
+    let run = rpkg_with(dir.path(), KEYRING, "load-release InRelease\nload-packages Packages\noutput-md5s sums.csv\n");
+    assert_eq!(run.code, 0);
+    let run = rpkg_with(dir.path(), KEYRING, "load-csv sums.csv\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated CSV file sums.csv: CSV files are not signed"));
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], "load-csv sums.csv\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Packages available: 6"));
+}
+
+#[test]
+fn cache_of_unverified_packages_needs_the_override() {
+    let dir = workdir(SIGNED);
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], "load-packages Packages\nsave-cache unverified.bin\n");
+    assert_eq!(run.code, 0);
+    let run = rpkg_with(dir.path(), KEYRING, "load-cache unverified.bin\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated cache unverified.bin: Packages was not authenticated"));
+
+    let run = rpkg_with(dir.path(), KEYRING, "load-release InRelease\nload-packages Packages\nsave-cache verified.bin\n");
+    assert_eq!(run.code, 0);
+    let run = rpkg_with(dir.path(), &[], "load-cache verified.bin\ninfo alpha --json\n");
+    assert_eq!(run.code, 0);
+    assert_eq!(run.objects("info").len(), 1);
+}
+
+#[test]
+fn stale_cache_replays_the_release_before_its_index() {
+    let dir = workdir(SIGNED);
+    rpkg_with(dir.path(), KEYRING, "load-release InRelease\nload-packages Packages\nsave-cache verified.bin\n");
+    let run = rpkg_with(dir.path(), KEYRING, "load-cache verified.bin\n");
+    assert!(!run.stdout.contains("stale"));
+    fs::copy(dir.path().join("InRelease.tampered"), dir.path().join("InRelease")).unwrap();
+    let run = rpkg_with(dir.path(), KEYRING, "load-cache verified.bin\n");
+    assert!(run.stdout.contains("is stale: InRelease changed"));
+    assert!(run.stdout.contains("Error: refusing to load unauthenticated index Packages"));
+    assert_eq!(run.code, 1);
+}
+
+#[test]
+fn bad_keyring_on_the_command_line_is_a_usage_error() {
+    let dir = workdir(SIGNED);
+    let run = rpkg_with(dir.path(), &["--keyring", "Packages"], "");
+    assert_eq!(run.code, 2);
+    assert!(run.stdout.contains("Error: "));
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{checksum_response, checksum_server, fixture_checksums, rpkg_with, workdir, StandIn};
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+// a checksum server that takes a while to answer, so that submitted requests are still in flight
+fn slow_server() -> StandIn {
+    let sums = fixture_checksums();
+    StandIn::start(move |req| checksum_response(&sums, req).delayed(Duration::from_millis(300)))
+}
+
+fn queue(server: &StandIn, script: &str) -> String {
+    format!("load-packages Packages\nset-server {}\nenq-verify alpha\nenq-verify beta\n{}", server.host(), script)
+}
+
+#[test]
+fn submit_then_wait_runs_the_requests() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "submit\nwait\n"));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("submitted 2 requests (2 in progress)"));
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    assert!(run.stdout.contains("verifying beta (sha256), matches: true"));
+}
+
+#[test]
+fn poll_reports_what_is_left_without_blocking() {
+    let server = slow_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "submit\npoll --json\nwait\npoll --json\n"));
+    assert_eq!(run.code, 0);
+    let polls = run.objects("poll");
+    assert_eq!(polls[0]["in_progress"], 2);
+    assert_eq!(polls[1]["in_progress"], 0);
+}
+
+#[test]
+fn cancel_drops_enqueued_and_submitted_requests() {
+    let server = slow_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "set-max-connections 1\nsubmit\nenq-verify delta\ncancel\nquit\n"));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("cancelled 3 requests (1 enqueued, 2 submitted)"));
+    assert!(!run.stdout.contains("matches"));
+}
+
+#[test]
+fn quit_refuses_while_requests_are_pending() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "quit\n"));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("2 requests are pending; quit --execute runs them first, quit --discard drops them"));
+    assert!(server.requests().is_empty());
+
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "quit --execute --discard\n"));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("quit takes --execute or --discard, not both"));
+}
+
+#[test]
+fn quit_execute_runs_pending_requests() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "quit --execute\nenq-verify delta\n"));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("verifying beta (sha256), matches: true"));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn quit_discard_drops_pending_requests() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &queue(&server, "quit --discard\n"));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("cancelled 2 requests (2 enqueued, 0 submitted)"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn input_ending_with_pending_requests_fails() {
+    let server = slow_server();
+    let dir = workdir(&["Packages"]);
+    for script in ["", "submit\n"] {
+        let run = rpkg_with(dir.path(), TRUST, &queue(&server, script));
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains("2 requests are still pending at the end of the input; end with quit --execute to run them or quit --discard to drop them"));
+        assert!(!run.stdout.contains("matches"));
+    }
+    let args = ["--allow-unverified", "-c", "load-packages Packages", "-c", "enq-verify alpha"];
+    let run = rpkg_with(dir.path(), &args, "");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("1 requests are still pending at the end of the input"));
+}
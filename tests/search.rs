@@ -0,0 +1,112 @@
+mod common;
+
+use common::{rpkg_with, workdir};
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+#[test]
+fn quoted_terms_reach_the_pattern_parser() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = rpkg_with(dir.path(), TRUST, "load-packages Packages\nload-installed status\nsearch ~d\"a library that\" ~i\nsearch ~d\"alpha's alternatives\"\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("beta 2.1 [installed 2.0]"));
+    assert!(!run.stdout.contains("newlib"));
+    assert!(run.stdout.contains("gamma 0.5 [installed 0.5]"));
+}
+
+#[test]
+fn flags_are_taken_out_of_the_pattern() {
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, "load-packages Packages\nsearch --json ~d\"a  library\"\nsearch ~n\"unclosed\n");
+    assert_eq!(run.code, 1);
+    let results = run.objects("search");
+    assert_eq!(results[0]["pattern"], "~d\"a  library\"");
+    assert_eq!(results[0]["matches"].as_array().unwrap().len(), 0);
+    assert!(run.stdout.contains("unclosed \""));
+}
+
+// the names that each pattern matches, searching the fixture Packages and status files
+fn matches(patterns: &[&str]) -> Vec<Vec<String>> {
+    let dir = workdir(&["Packages", "status"]);
+    let script : String = patterns.iter().map(|p| format!("search --json {}\n", p)).collect();
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nload-installed status\n{}", script));
+    assert_eq!(run.code, 0);
+    run.objects("search").iter()
+        .map(|s| s["matches"].as_array().unwrap().iter().map(|m| m["package"].as_str().unwrap().to_string()).collect())
+        .collect()
+}
+
+fn check(cases: &[(&str, &[&str])]) {
+    let found = matches(&cases.iter().map(|(p, _)| *p).collect::<Vec<&str>>());
+    for ((pattern, expected), found) in cases.iter().zip(found) {
+        assert_eq!(found, *expected, "search {}", pattern);
+    }
+}
+
+#[test]
+fn field_terms_match_case_insensitive_regexes() {
+    check(&[
+        ("alpha", &["alpha"]),
+        ("~nlib", &["newlib"]),
+        ("~n^.e", &["beta", "delta", "newlib", "zeta"]),
+        ("~dALTERNATIVE", &["delta", "gamma"]),
+        ("~d\"that alpha needs\"", &["beta"]),
+        ("~slibs", &["beta", "newlib"]),
+        ("~pextra", &["delta", "gamma"]),
+        ("~nnothing", &[]),
+    ]);
+}
+
+#[test]
+fn state_terms_look_at_what_is_installed() {
+    check(&[
+        ("~i", &["alpha", "beta", "gamma", "zeta"]),
+        ("~U", &["beta"]),
+        ("~Dbeta", &["alpha", "delta"]),
+        ("~Dgamma", &["alpha"]),
+    ]);
+}
+
+#[test]
+fn version_terms_compare_with_each_operator() {
+    check(&[
+        // zeta has no available version, so its installed 1.0 counts
+        ("~V>=1.0", &["alpha", "beta", "delta", "epsilon", "newlib", "zeta"]),
+        ("~V>>1.1", &["beta", "epsilon"]),
+        ("~V<=1.0", &["delta", "gamma", "zeta"]),
+        ("~V<<1.0", &["gamma"]),
+        ("~V=1.0", &["delta", "zeta"]),
+        ("~V1.0", &["delta", "zeta"]),
+    ]);
+}
+
+#[test]
+fn terms_combine_with_and_or_not_and_parentheses() {
+    check(&[
+        ("~slibs | ~pextra", &["beta", "delta", "gamma", "newlib"]),
+        ("~i !~U", &["alpha", "gamma", "zeta"]),
+        ("~i (~slibs | ~sutils)", &["alpha", "beta"]),
+        ("!(~i | ~slibs)", &["delta", "epsilon"]),
+        ("~i ~slibs | ~pextra", &["beta", "delta", "gamma"]),
+    ]);
+}
+
+#[test]
+fn bad_patterns_are_reported() {
+    let dir = workdir(&["Packages"]);
+    let bad = [
+        ("~x", "unknown term ~x"),
+        ("(~i", "missing )"),
+        ("~i )", "unexpected \")\""),
+        ("~n(", "bad regex \"(\""),
+        ("~V>=", "~V needs a version"),
+        ("~D", "~D needs a package name"),
+        ("~", "~ needs a letter"),
+    ];
+    let script : String = bad.iter().map(|(p, _)| format!("search {}\n", p)).collect();
+    let run = rpkg_with(dir.path(), &["--allow-unverified", "--keep-going"], &format!("load-packages Packages\n{}", script));
+    assert_eq!(run.code, 1);
+    for (pattern, message) in bad {
+        assert!(run.stdout.contains(&format!("Error: bad search pattern {:?}: {}", pattern, message)), "search {}", pattern);
+    }
+}
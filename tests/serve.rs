@@ -0,0 +1,73 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::*;
+
+// starts rpkg serve on a free port and returns it with the address it listens on
+fn serve(dir: &std::path::Path, args: &[&str]) -> (Interactive, SocketAddr) {
+    let args : Vec<&str> = ["serve", "--port", "0"].iter().chain(args).copied().collect();
+    let mut server = Interactive::start(dir, &args);
+    let line = server.read_until("Serving checksums").pop().unwrap();
+    let addr = line.trim().rsplit(' ').next().unwrap().parse().unwrap();
+    (server, addr)
+}
+
+#[test]
+fn listens_on_the_loopback_address_by_default() {
+    let dir = workdir(&["Packages"]);
+    let (server, addr) = serve(dir.path(), &["--allow-unverified", "Packages"]);
+    assert!(addr.ip().is_loopback());
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], &format!("load-packages Packages\nset-server {}\nenq-verify alpha\nexecute\n", addr));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    server.kill();
+}
+
+#[test]
+fn bind_chooses_the_address() {
+    let dir = workdir(&["Packages"]);
+    let (server, addr) = serve(dir.path(), &["--bind", "0.0.0.0", "--allow-unverified", "Packages"]);
+    assert!(addr.ip().is_unspecified());
+    server.kill();
+}
+
+#[test]
+fn unverified_files_are_not_served() {
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), &["serve", "--port", "0", "Packages"], "");
+    assert_eq!(run.code, 1);
+    assert!(!run.stdout.contains("Serving checksums"));
+}
+
+#[test]
+fn connections_over_the_limit_wait() {
+    let dir = workdir(&["Packages"]);
+    let (server, addr) = serve(dir.path(), &["--max-connections", "1", "--delay-ms", "300", "--allow-unverified", "Packages"]);
+    let started = Instant::now();
+    let clients : Vec<_> = ["alpha", "beta", "gamma"].iter().map(|package| {
+        let addr = addr.to_string();
+        thread::spawn(move || {
+            let dir = workdir(&["Packages"]);
+            rpkg_with(dir.path(), &["--allow-unverified"], &format!("load-packages Packages\nset-server {}\nenq-verify {}\nexecute\n", addr, package)).code
+        })
+    }).collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), 0);
+    }
+    // one at a time, each delayed
+    assert!(started.elapsed() >= Duration::from_millis(900));
+    server.kill();
+}
+
+#[test]
+fn bad_options_are_refused() {
+    let dir = workdir(&["Packages"]);
+    for args in [&["serve", "--max-connections", "0", "Packages"][..], &["serve", "--bind", "localhost:80", "Packages"][..]] {
+        let run = rpkg_with(dir.path(), args, "");
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains("syntax: rpkg serve"));
+    }
+}
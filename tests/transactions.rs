@@ -0,0 +1,87 @@
+mod common;
+
+use std::fs;
+
+use common::{rpkg_with, workdir, Run};
+
+const TRUST : &[&str] = &["--allow-unverified", "--keep-going"];
+
+fn simulate(dir: &std::path::Path, script: &str) -> Run {
+    rpkg_with(dir, TRUST, &format!("load-packages Packages\nload-installed status\n{}", script))
+}
+
+// the package names in a status file, in order and once each (beta has a stanza for each of two architectures)
+fn packages_in(path: &std::path::Path) -> Vec<String> {
+    let mut names : Vec<String> = fs::read_to_string(path).unwrap().lines().filter_map(|l| l.strip_prefix("Package: ")).map(String::from).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[test]
+fn install_brings_in_what_how_to_install_needs() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = simulate(dir.path(), "install epsilon\ninstall epsilon\ntransactions --json\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("transaction 1: install epsilon\n  epsilon (not installed) -> 3:1.2-4\n  newlib (not installed) -> 1.1\n"));
+    assert!(run.stdout.contains("epsilon is already installed at version 3:1.2-4"));
+    assert_eq!(run.objects("transaction").len(), 1);
+}
+
+#[test]
+fn install_without_an_available_version_changes_nothing() {
+    let dir = workdir(&["Packages", "status"]);
+    fs::write(dir.path().join("Omega"), "Package: omega\nVersion: 1.0\nDepends: zeta (>= 2.0)\nDescription: needs a newer zeta\n").unwrap();
+    let run = simulate(dir.path(), "load-packages Omega\ninstall omega\ntransactions\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("can't install omega: zeta has no available version; nothing was changed"));
+    assert!(run.stdout.contains("no transactions"));
+}
+
+#[test]
+fn remove_takes_out_the_packages_that_it_breaks() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = simulate(dir.path(), "remove beta\nremove delta\n");
+    assert_eq!(run.code, 1);
+    // alpha needs beta (>= 2.0); gamma is left alone
+    assert!(run.stdout.contains("transaction 1: remove beta\n  alpha 1.0-1 -> (removed)\n  beta 2.0 -> (removed)\n"));
+    assert!(run.stdout.contains("delta is not installed"));
+}
+
+#[test]
+fn rollback_undoes_transactions_in_reverse() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = simulate(dir.path(), "install epsilon\nremove beta\nrollback\nwrite-installed one\nrollback\nwrite-installed none\nrollback\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("rolled back 2: remove beta\n"));
+    assert!(run.stdout.contains("rolled back 1: install epsilon\n"));
+    assert!(run.stdout.contains("no transactions to roll back\n"));
+    assert_eq!(packages_in(&dir.path().join("one")), ["alpha", "beta", "epsilon", "gamma", "newlib", "zeta"]);
+    assert_eq!(packages_in(&dir.path().join("none")), packages_in(&dir.path().join("status")));
+}
+
+#[test]
+fn rollback_stops_at_the_last_commit() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = simulate(dir.path(), "install epsilon\ncommit-to committed\nremove beta\ninstall delta\nrollback --all\nrollback --all\nwrite-installed after\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Wrote 6 installed packages to committed"));
+    assert!(run.stdout.contains("rolled back 3: install delta"));
+    assert!(run.stdout.contains("rolled back 2: remove beta"));
+    assert!(!run.stdout.contains("rolled back 1"));
+    assert!(run.stdout.contains("no transactions to roll back since the last commit-to"));
+    assert_eq!(packages_in(&dir.path().join("after")), packages_in(&dir.path().join("committed")));
+}
+
+#[test]
+fn commit_to_marks_the_transactions_it_wrote() {
+    let dir = workdir(&["Packages", "status"]);
+    let run = simulate(dir.path(), "remove beta\ncommit-to --json committed\ntransactions --json\n");
+    assert_eq!(run.code, 0);
+    let commit = &run.objects("commit-to")[0];
+    assert_eq!(commit["packages"], 2);
+    assert_eq!(commit["transactions"], 1);
+    assert_eq!(packages_in(&dir.path().join("committed")), ["gamma", "zeta"]);
+    let transactions = run.objects("transaction");
+    assert_eq!(transactions.last().unwrap()["committed_to"], "committed");
+}
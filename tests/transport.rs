@@ -0,0 +1,205 @@
+mod common;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+
+use common::*;
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+// a key and a certificate for common_name, signed by issuer or else self-signed as a CA
+fn certificate(common_name: &str, issuer: Option<&(PKey<Private>, X509)>) -> (PKey<Private>, X509) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+        None => {
+            cert.set_issuer_name(&name).unwrap();
+            cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            cert.sign(&key, MessageDigest::sha256()).unwrap();
+        }
+        Some((issuer_key, issuer_cert)) => {
+            cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+            let san = SubjectAlternativeName::new().ip("127.0.0.1").build(&cert.x509v3_context(Some(issuer_cert), None)).unwrap();
+            cert.append_extension(san).unwrap();
+            cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        }
+    }
+    (key, cert.build())
+}
+
+fn write_pem(path: &Path, key: Option<&PKey<Private>>, cert: Option<&X509>) {
+    let mut pem = vec![];
+    if let Some(cert) = cert {
+        pem.extend(cert.to_pem().unwrap());
+    }
+    if let Some(key) = key {
+        pem.extend(key.private_key_to_pem_pkcs8().unwrap());
+    }
+    fs::write(path, pem).unwrap();
+}
+
+// A private CA and a server certificate from it, written to dir as ca.pem; and a second CA, other-ca.pem,
+// with a client certificate from it in client.pem and client-key.pem, and both in client-both.pem.
+struct Pki {
+    server : (PKey<Private>, X509),
+    client_ca : X509,
+}
+
+fn pki(dir: &Path) -> Pki {
+    let ca = certificate("Test CA", None);
+    let server = certificate("127.0.0.1", Some(&ca));
+    let client_ca = certificate("Client CA", None);
+    let client = certificate("client", Some(&client_ca));
+    write_pem(&dir.join("ca.pem"), None, Some(&ca.1));
+    write_pem(&dir.join("other-ca.pem"), None, Some(&client_ca.1));
+    write_pem(&dir.join("client.pem"), None, Some(&client.1));
+    write_pem(&dir.join("client-key.pem"), Some(&client.0), None);
+    write_pem(&dir.join("client-both.pem"), Some(&client.0), Some(&client.1));
+    Pki { server, client_ca: client_ca.1 }
+}
+
+// a TLS checksum server with the PKI's server certificate, which requires a client certificate if mutual is set
+fn tls_server(pki: &Pki, mutual: bool) -> StandIn {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&pki.server.0).unwrap();
+    acceptor.set_certificate(&pki.server.1).unwrap();
+    if mutual {
+        acceptor.cert_store_mut().add_cert(pki.client_ca.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = Arc::new(acceptor.build());
+    let sums = fixture_checksums();
+    StandIn::start_with(move |req| checksum_response(&sums, req),
+                        move |stream| acceptor.accept(stream).map_err(|e| io::Error::other(e.to_string())))
+}
+
+fn verify_alpha(dir: &Path, server: &str, settings: &str) -> Run {
+    rpkg_with(dir, TRUST, &format!("load-packages Packages\nset-server {}\n{}enq-verify alpha\nexecute\n", server, settings))
+}
+
+fn https(server: &StandIn) -> String {
+    format!("https://{}", server.host())
+}
+
+#[test]
+fn ca_bundle_trusts_a_private_ca() {
+    let dir = workdir(&["Packages"]);
+    let pki = pki(dir.path());
+    let server = tls_server(&pki, false);
+    let run = verify_alpha(dir.path(), &https(&server), "set-ca-bundle ca.pem\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+}
+
+#[test]
+fn untrusted_certificate_is_a_transport_error() {
+    let dir = workdir(&["Packages"]);
+    let pki = pki(dir.path());
+    let server = tls_server(&pki, false);
+    for settings in ["", "set-ca-bundle other-ca.pem\n"] {
+        let run = verify_alpha(dir.path(), &https(&server), &format!("set-retries 0\n{}", settings));
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains("request for package alpha version 1.0-1 failed"));
+        assert!(!run.stdout.contains("matches: true"));
+    }
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn client_certificate_is_presented() {
+    let dir = workdir(&["Packages"]);
+    let pki = pki(dir.path());
+    let server = tls_server(&pki, true);
+    for settings in ["set-client-cert client.pem client-key.pem\n", "set-client-cert client-both.pem\n"] {
+        let run = verify_alpha(dir.path(), &https(&server), &format!("set-ca-bundle ca.pem\n{}", settings));
+        assert_eq!(run.code, 0);
+        assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    }
+}
+
+#[test]
+fn server_requiring_a_client_certificate_refuses_a_request_without_one() {
+    let dir = workdir(&["Packages"]);
+    let pki = pki(dir.path());
+    let server = tls_server(&pki, true);
+    for settings in ["", "set-client-cert client-both.pem\nset-client-cert none\n"] {
+        let run = verify_alpha(dir.path(), &https(&server), &format!("set-ca-bundle ca.pem\nset-retries 0\n{}", settings));
+        assert_eq!(run.code, 1);
+        assert!(run.stdout.contains("request for package alpha version 1.0-1 failed"));
+    }
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn missing_files_are_refused() {
+    let dir = workdir(&["Packages"]);
+    let run = rpkg(dir.path(), "set-ca-bundle nowhere.pem\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("CA bundle nowhere.pem does not exist"));
+}
+
+// a proxy for plain HTTP: it answers requests for absolute URLs as the checksum server would
+fn proxy() -> StandIn {
+    let sums = fixture_checksums();
+    StandIn::start(move |req| {
+        let path = req.path.strip_prefix("http://").and_then(|rest| rest.find('/').map(|i| &rest[i..])).unwrap_or("");
+        checksum_response(&sums, &Request { method: req.method.clone(), path: path.to_string(), headers: vec![] })
+    })
+}
+
+#[test]
+fn proxy_variables_apply_by_default() {
+    let (server, proxy) = (checksum_server(), proxy());
+    let dir = workdir(&["Packages"]);
+    let proxy_url = proxy.url();
+    let env = [("http_proxy", proxy_url.as_str())];
+    for settings in ["", "set-proxy none\nset-proxy env\n"] {
+        let run = rpkg_env(dir.path(), TRUST, &env, &format!("load-packages Packages\nset-server {}\n{}set-verify-ttl 0\nenq-verify alpha\nexecute\n", server.host(), settings));
+        assert_eq!(run.code, 0);
+    }
+    assert_eq!(proxy.requests().len(), 2);
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn set_proxy_none_ignores_proxy_variables() {
+    let (server, proxy) = (checksum_server(), proxy());
+    let dir = workdir(&["Packages"]);
+    let proxy_url = proxy.url();
+    let env = [("http_proxy", proxy_url.as_str()), ("HTTP_PROXY", proxy_url.as_str()), ("all_proxy", proxy_url.as_str())];
+    let run = rpkg_env(dir.path(), TRUST, &env, &format!("load-packages Packages\nset-server {}\nset-proxy none\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert!(proxy.requests().is_empty());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn set_proxy_sends_requests_through_the_proxy() {
+    let (server, proxy) = (checksum_server(), proxy());
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-proxy {}\nenq-verify alpha\nexecute\n", server.host(), proxy.url()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    assert_eq!(proxy.requests(), vec![format!("http://{}/rest/v2/checksums/alpha/1.0-1/sha256", server.host())]);
+    assert!(server.requests().is_empty());
+}
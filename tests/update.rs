@@ -0,0 +1,112 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use common::*;
+
+// lays out a mirror of suite sid under root, with packages as main/binary-amd64/Packages, and a Release file
+// listing it, both last modified at modified
+fn mirror(root: &Path, packages: &str, modified: SystemTime) {
+    let dists = root.join("dists/sid");
+    fs::create_dir_all(dists.join("main/binary-amd64")).unwrap();
+    fs::write(dists.join("main/binary-amd64/Packages"), packages).unwrap();
+    let release = format!("Origin: Fixture\nLabel: Fixture\nSuite: sid\nCodename: sid\nArchitectures: amd64\nComponents: main\nSHA256:\n {:x} {} main/binary-amd64/Packages\n",
+                          Sha256::digest(packages.as_bytes()), packages.len());
+    fs::write(dists.join("Release"), release).unwrap();
+    for file in ["Release", "main/binary-amd64/Packages"] {
+        fs::File::options().write(true).open(dists.join(file)).unwrap().set_modified(modified).unwrap();
+    }
+}
+
+// the fixture Packages file without the stanza of package
+fn without(package: &str) -> String {
+    fs::read_to_string(fixture("Packages")).unwrap().split("\n\n")
+        .filter(|stanza| !stanza.starts_with(&format!("Package: {}\n", package)))
+        .collect::<Vec<&str>>().join("\n\n")
+}
+
+#[test]
+fn signed_mirror_is_downloaded_and_loaded() {
+    let root = tempfile::tempdir().unwrap();
+    let dists = root.path().join("dists/sid");
+    fs::create_dir_all(dists.join("main/binary-amd64")).unwrap();
+    fs::copy(fixture("Release"), dists.join("Release")).unwrap();
+    fs::copy(fixture("Release.gpg"), dists.join("Release.gpg")).unwrap();
+    fs::copy(fixture("Packages"), dists.join("main/binary-amd64/Packages")).unwrap();
+    let server = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&["keyring.asc"]);
+    let run = rpkg_with(dir.path(), &["--keyring", "keyring.asc"], &format!("add-source {} sid main amd64\nupdate\ninfo alpha\n", server.url()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("Signature: good, key 2c7648da4d16adf3"));
+    assert!(run.stdout.contains("Version: 1.0-1"));
+    for file in ["dists_sid_Release", "dists_sid_Release.gpg", "dists_sid_main_binary-amd64_Packages"] {
+        assert!(dir.path().join("lists").join(format!("{}_{}", server.host(), file)).exists());
+    }
+    let mut requests = server.requests();
+    requests.sort();
+    assert_eq!(requests, ["/dists/sid/Release", "/dists/sid/Release.gpg", "/dists/sid/main/binary-amd64/Packages"]);
+}
+
+#[test]
+fn unsigned_mirror_is_refused() {
+    let root = tempfile::tempdir().unwrap();
+    mirror(root.path(), &fs::read_to_string(fixture("Packages")).unwrap(), SystemTime::now());
+    let server = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&["keyring.asc"]);
+    let run = rpkg_with(dir.path(), &["--keyring", "keyring.asc"], &format!("add-source {} sid main amd64\nupdate\n", server.url()));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("refusing to load unauthenticated"));
+}
+
+#[test]
+fn unchanged_files_are_not_downloaded_again() {
+    let root = tempfile::tempdir().unwrap();
+    mirror(root.path(), &fs::read_to_string(fixture("Packages")).unwrap(), SystemTime::now());
+    let server = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&[]);
+    let run = rpkg_with(dir.path(), &["--allow-unverified"], &format!("add-source {} sid main amd64\nupdate\nupdate\n", server.url()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains(&format!("{}/dists/sid/Release not modified", server.url())));
+    assert!(run.stdout.contains(&format!("{}/dists/sid/main/binary-amd64/Packages not modified", server.url())));
+}
+
+#[test]
+fn update_drops_packages_the_mirror_no_longer_lists() {
+    let root = tempfile::tempdir().unwrap();
+    mirror(root.path(), &fs::read_to_string(fixture("Packages")).unwrap(), SystemTime::now());
+    let server = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&[]);
+    let mut rpkg = Interactive::start(dir.path(), &["--allow-unverified"]);
+    let loaded = rpkg.send(&format!("add-source {} sid main amd64\nupdate", server.url()), "Packages available");
+    assert_eq!(loaded.last().unwrap().trim(), "Packages available: 6");
+    assert!(rpkg.send("info gamma\ninfo alpha", "Package: alpha").iter().any(|l| l.trim() == "Version: 0.5"));
+
+    // a later second than the first copy, so that If-Modified-Since doesn't hide the change
+    mirror(root.path(), &without("gamma"), SystemTime::now() + Duration::from_secs(5));
+    let loaded = rpkg.send("update", "Packages available");
+    assert_eq!(loaded.last().unwrap().trim(), "Packages available: 5");
+    assert!(!rpkg.send("info gamma\ninfo alpha", "Package: alpha").iter().any(|l| l.trim() == "Version: 0.5"));
+    assert_eq!(rpkg.finish(), 0);
+}
+
+#[test]
+fn dropped_packages_can_still_be_asked_about() {
+    let root = tempfile::tempdir().unwrap();
+    mirror(root.path(), &fs::read_to_string(fixture("Packages")).unwrap(), SystemTime::now());
+    let server = StandIn::serve_dir(root.path().to_path_buf());
+    let dir = workdir(&["status"]);
+    let mut rpkg = Interactive::start(dir.path(), &["--allow-unverified", "--keep-going"]);
+    rpkg.send(&format!("load-installed status\nadd-source {} sid main amd64\nupdate", server.url()), "Packages available: 6");
+    mirror(root.path(), &without("alpha"), SystemTime::now() + Duration::from_secs(5));
+    rpkg.send("update", "Packages available: 5");
+    // alpha is still installed, but nothing is known about it any more beyond that
+    assert_eq!(rpkg.send("deps alpha", "alpha"), ["\"alpha\" depends on \"\"\n"]);
+    assert_eq!(rpkg.send("info alpha", "Installed-Version"), ["Package: alpha\n", "Installed-Version: 1.0-1\n"]);
+    let removed = rpkg.send("remove alpha", "alpha 1.0-1 -> (removed)");
+    assert!(removed.iter().any(|l| l.contains("transaction 1: remove alpha")));
+    assert_eq!(rpkg.finish(), 0);
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use common::{checksum_response, checksum_server, fixture_checksums, rpkg_with, workdir, Response, StandIn};
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+#[test]
+fn strongest_local_checksum_is_requested_by_default() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nenq-verify alpha\nenq-verify delta\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    // delta only has an MD5sum
+    assert!(run.stdout.contains("verifying delta, matches: true"));
+    let mut requests = server.requests();
+    requests.sort();
+    assert_eq!(requests, ["/rest/v1/checksums/delta/1.0", "/rest/v2/checksums/alpha/1.0-1/sha256"]);
+}
+
+#[test]
+fn set_hash_picks_the_algorithm() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-hash md5\nenq-verify alpha\nexecute\nset-hash strongest\nenq-verify alpha --force\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert_eq!(server.requests(), ["/rest/v1/checksums/alpha/1.0-1", "/rest/v2/checksums/alpha/1.0-1/sha256"]);
+}
+
+#[test]
+fn total_latency_counts_time_spent_queued() {
+    let sums = fixture_checksums();
+    let server = StandIn::start(move |req| checksum_response(&sums, req).delayed(Duration::from_millis(200)));
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-max-connections 1\nenq-verify alpha\nenq-verify beta\nexecute --json\n", server.host()));
+    assert_eq!(run.code, 0);
+    let results = run.objects("verify");
+    assert_eq!(results.len(), 2);
+    let second = &results[1];
+    let (latency, total) = (second["latency_ms"].as_u64().unwrap(), second["total_ms"].as_u64().unwrap());
+    assert!((200..400).contains(&latency), "latency {}", latency);
+    assert!(total >= latency + 200, "total {} latency {}", total, latency);
+}
+
+#[test]
+fn total_latency_covers_every_attempt() {
+    let sums = fixture_checksums();
+    let calls = AtomicUsize::new(0);
+    let server = StandIn::start(move |req| {
+        if calls.fetch_add(1, Ordering::SeqCst) == 0 { Response::new(503, "") } else { checksum_response(&sums, req) }
+    });
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-backoff 300\nenq-verify alpha\nexecute\nverify-report report.json\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains(" ms since queued after 2 attempts)"));
+    let report : serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join("report.json")).unwrap()).unwrap();
+    let result = &report[0];
+    assert_eq!(result["retries"], 1);
+    assert!(result["total_ms"].as_u64().unwrap() >= 300 + result["latency_ms"].as_u64().unwrap());
+}
+
+// a checksum server that answers the first `failures` requests with 503, and remembers when each request came
+fn flaky_server(failures: usize) -> (StandIn, Arc<Mutex<Vec<Instant>>>) {
+    let sums = fixture_checksums();
+    let times = Arc::new(Mutex::new(vec![]));
+    let seen = Arc::clone(&times);
+    let server = StandIn::start(move |req| {
+        let mut times = seen.lock().unwrap();
+        times.push(Instant::now());
+        if times.len() <= failures { Response::new(503, "") } else { checksum_response(&sums, req) }
+    });
+    (server, times)
+}
+
+#[test]
+fn server_errors_are_retried_with_exponential_backoff() {
+    let (server, times) = flaky_server(2);
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-retries 3\nset-backoff 200\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("retrying request for package alpha version 1.0-1 in 200 ms (got error 503)"));
+    assert!(run.stdout.contains("retrying request for package alpha version 1.0-1 in 400 ms (got error 503)"));
+    assert!(run.stdout.contains("matches: true"));
+    assert!(run.stdout.contains("after 3 attempts)"));
+    let times = times.lock().unwrap();
+    assert_eq!(times.len(), 3);
+    assert!(times[1] - times[0] >= Duration::from_millis(200));
+    assert!(times[2] - times[1] >= Duration::from_millis(400));
+}
+
+#[test]
+fn retries_run_out() {
+    let (server, times) = flaky_server(usize::MAX);
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-retries 2\nset-backoff 10\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("got error 503 on request for package alpha version 1.0-1 after 3 attempts"));
+    assert_eq!(times.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn slow_answers_time_out() {
+    let server = StandIn::start(|_| Response::new(200, "").delayed(Duration::from_secs(3)));
+    let dir = workdir(&["Packages"]);
+    let started = Instant::now();
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-timeout 200\nset-retries 1\nset-backoff 10\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("request for package alpha version 1.0-1 failed after 2 attempts: Timeout was reached"));
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn failed_server_fails_over_to_the_next() {
+    let (bad, _) = flaky_server(usize::MAX);
+    let good = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nadd-server {}\nset-retries 0\nenq-verify alpha\nexecute\n", bad.host(), good.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains(&format!("trying next server for package alpha version 1.0-1 (got error 503 from {})", bad.host())));
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+}
+
+// bind and drop a listener to find a port that nothing listens on
+fn closed_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn refused_connection_is_a_transport_error() {
+    let port = closed_port();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server 127.0.0.1:{}\nset-retries 0\nenq-verify alpha\nexecute --json\n", port));
+    assert_eq!(run.code, 1);
+    assert_eq!(run.objects("verify")[0]["outcome"], "transport-error");
+}
+
+#[test]
+fn down_server_fails_over_to_the_next() {
+    let down = format!("127.0.0.1:{}", closed_port());
+    let good = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nadd-server {}\nset-retries 0\nenq-verify alpha\nexecute\n", down, good.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("trying next server for package alpha version 1.0-1 ("));
+    assert!(run.stdout.contains(&format!(" from {})", down)));
+    assert!(run.stdout.contains("verifying alpha (sha256), matches: true"));
+    assert_eq!(good.requests(), ["/rest/v2/checksums/alpha/1.0-1/sha256"]);
+}
+
+#[test]
+fn quorum_of_agreeing_servers() {
+    let (first, second) = (checksum_server(), checksum_server());
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nadd-server {}\nset-server-mode quorum\nenq-verify alpha\nexecute\n", first.host(), second.host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("quorum for package alpha version 1.0-1: 2/2 servers agree, matches: true\n"));
+}
+
+#[test]
+fn quorum_reports_servers_that_disagree() {
+    let good = checksum_server();
+    let zeros = "0".repeat(64);
+    let answer = zeros.clone();
+    let bad = StandIn::start(move |_| Response::new(200, answer.clone()));
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nadd-server {}\nset-server-mode quorum\nenq-verify alpha\nexecute\n", good.host(), bad.host()));
+    assert_eq!(run.code, 1);
+    let sum = &fixture_checksums()[&(String::from("alpha"), String::from("1.0-1"), String::from("sha256"))];
+    let line = run.stdout.lines().find(|l| l.starts_with("Warning: servers disagree on package alpha version 1.0-1: ")).unwrap();
+    assert!(line.contains(&format!("{} says {}", good.host(), sum)), "{}", line);
+    assert!(line.contains(&format!("{} says {}", bad.host(), zeros)), "{}", line);
+    assert!(!run.stdout.contains("servers agree"));
+}
+
+#[test]
+fn quorum_asks_only_the_first_n_servers() {
+    let servers = [checksum_server(), checksum_server(), checksum_server()];
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nadd-server {}\nadd-server {}\nset-server-mode quorum 2\nenq-verify alpha\nexecute\n",
+                                                    servers[0].host(), servers[1].host(), servers[2].host()));
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("quorum for package alpha version 1.0-1: 2/2 servers agree, matches: true\n"));
+    assert_eq!(servers[0].requests().len(), 1);
+    assert_eq!(servers[1].requests().len(), 1);
+    assert!(servers[2].requests().is_empty());
+}
+
+#[test]
+fn junit_report_maps_outcomes_as_documented() {
+    let sums = fixture_checksums();
+    let server = StandIn::start(move |req| match req.path.split('/').nth(4) {
+        Some("gamma") => Response::new(200, "0".repeat(64)),
+        Some("beta") => Response::new(404, ""),
+        Some("epsilon") => checksum_response(&sums, req).delayed(Duration::from_secs(2)),
+        Some("zeta") => Response::new(200, "0".repeat(32)),
+        _ => checksum_response(&sums, req)
+    });
+    let dir = workdir(&["Packages", "status"]);
+    let run = rpkg_with(dir.path(), &["--allow-unverified", "--keep-going"],
+                        &format!("load-packages Packages\nload-installed status\nset-server {}\nset-retries 0\nset-timeout 300\n\
+                                  enq-verify alpha\nenq-verify gamma\nenq-verify beta\nenq-verify epsilon\nenq-verify zeta 1.0\nexecute\n\
+                                  verify-report report.xml --format=junit\n", server.host()));
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("Report written to report.xml (5 requests)"));
+    let xml = std::fs::read_to_string(dir.path().join("report.xml")).unwrap();
+    assert!(xml.contains("<testsuite name=\"rpkg-verify\" tests=\"5\" failures=\"2\" errors=\"1\" skipped=\"1\" "), "{}", xml);
+    // the first line of each testcase's outcome, by testcase name
+    let outcome = |name: &str| {
+        let case = xml.split("<testcase ").find(|c| c.contains(&format!(" name=\"{}\"", name))).unwrap_or_else(|| panic!("no testcase {}", name));
+        case.lines().nth(1).unwrap().trim().split(' ').next().unwrap().to_string()
+    };
+    assert_eq!(outcome("alpha 1.0-1"), "<system-out>url:");
+    assert_eq!(outcome("gamma 0.5"), "<failure");
+    assert_eq!(outcome("beta 2.1"), "<failure");
+    assert_eq!(outcome("epsilon 3:1.2-4"), "<error");
+    assert_eq!(outcome("zeta 1.0"), "<skipped");
+    assert!(xml.contains("<failure message=\"checksum mismatch\"/>"));
+    assert!(xml.contains("<failure message=\"HTTP status 404\"/>"));
+    assert!(xml.contains("<skipped message=\"no local md5 checksum for version 1.0\"/>"));
+}
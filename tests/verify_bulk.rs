@@ -0,0 +1,97 @@
+mod common;
+
+use common::*;
+
+const TRUST : &[&str] = &["--allow-unverified", "--keep-going"];
+
+// A checksum server that knows the fixture checksums, except that it gives gamma a wrong one, doesn't know
+// zeta, and answers for installed beta 2.0, which has no local checksum, with a made-up one.
+fn server() -> StandIn {
+    let sums = fixture_checksums();
+    StandIn::start(move |req| {
+        let package = req.path.split('/').nth(4).unwrap_or("");
+        let algorithm = if req.path.starts_with("/rest/v2/") { req.path.rsplit('/').next().unwrap() } else { "md5" };
+        let len = if algorithm == "md5" { 32 } else { 64 };
+        match package {
+            "gamma" => Response::new(200, "0".repeat(len)),
+            "beta" if req.path.split('/').nth(5) == Some("2.0") => Response::new(200, "0".repeat(len)),
+            "zeta" => Response::new(404, ""),
+            _ => checksum_response(&sums, req)
+        }
+    })
+}
+
+fn verify(script: &str) -> Run {
+    let server = server();
+    let dir = workdir(&["Packages", "status"]);
+    rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nload-installed status\nset-server {}\nset-retries 0\n{}", server.host(), script))
+}
+
+// The "[i/n]" lines of a bulk verification, which must number 1 to n once each, without the numbers and the
+// timings, in order of the rest of the line, since requests finish in any order.
+fn numbered(stdout: &str) -> Vec<String> {
+    let mut numbers = vec![];
+    let mut lines = vec![];
+    for line in stdout.lines().filter(|l| l.starts_with('[')) {
+        let (number, rest) = line[1..].split_once("] ").unwrap();
+        numbers.push(number.to_string());
+        lines.push(match rest.rsplit_once(" (") {
+            Some((rest, timing)) if timing.ends_with(" ms)") => rest.to_string(),
+            _ => rest.to_string()
+        });
+    }
+    numbers.sort_by_key(|n| n.split('/').next().unwrap().parse::<usize>().unwrap());
+    assert_eq!(numbers, (1..=lines.len()).map(|i| format!("{}/{}", i, lines.len())).collect::<Vec<String>>());
+    lines.sort();
+    lines
+}
+
+#[test]
+fn verify_installed_numbers_its_lines_and_sums_up() {
+    let run = verify("verify-installed\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("queued 4 requests for installed packages (0 answered from the verification cache)\n"));
+    assert_eq!(numbered(&run.stdout), ["got error 404 on request for package zeta version 1.0",
+                                       "verifying alpha (sha256), matches: true",
+                                       "verifying beta, no local md5 checksum for version 2.0",
+                                       "verifying gamma (sha256), matches: false"]);
+    assert!(run.stdout.contains("installed packages: 4 verified, 1 matched, 1 mismatched, 1 without local checksum, 1 failed\n"));
+}
+
+#[test]
+fn verify_plan_covers_the_packages_to_install() {
+    let run = verify("verify-plan epsilon\n");
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("queued 2 requests for plan for epsilon (0 answered from the verification cache)\n"));
+    assert_eq!(numbered(&run.stdout), ["verifying epsilon (sha256), matches: true", "verifying newlib, matches: true"]);
+    assert!(run.stdout.contains("plan for epsilon: 2 verified, 2 matched, 0 mismatched, 0 without local checksum, 0 failed\n"));
+}
+
+#[test]
+fn verify_all_counts_cached_answers_but_does_not_number_them() {
+    let run = verify("verify-plan alpha\nverify-all\n");
+    assert_eq!(run.code, 1);
+    assert!(run.stdout.contains("queued 5 requests for available packages (1 answered from the verification cache)\n"));
+    let all = &run.stdout[run.stdout.find("queued 5").unwrap()..];
+    assert_eq!(numbered(all), ["verifying beta (sha256), matches: true",
+                               "verifying delta, matches: true",
+                               "verifying epsilon (sha256), matches: true",
+                               "verifying gamma (sha256), matches: false",
+                               "verifying newlib, matches: true"]);
+    assert!(run.stdout.contains("available packages: 6 verified, 5 matched, 1 mismatched, 0 without local checksum, 0 failed\n"));
+}
+
+#[test]
+fn summary_is_an_object_in_json_output() {
+    let run = verify("set-output json\nverify-installed\n");
+    assert_eq!(run.code, 1);
+    let mut outcomes : Vec<String> = run.objects("verify").iter().map(|v| v["outcome"].as_str().unwrap().to_string()).collect();
+    outcomes.sort();
+    assert_eq!(outcomes, ["http-error", "match", "mismatch", "no-local-checksum"]);
+    let summary = run.objects("verify-summary");
+    assert_eq!(summary.len(), 1);
+    assert_eq!(summary[0]["what"], "installed packages");
+    for (field, count) in [("verified", 4), ("matched", 1), ("mismatched", 1), ("no_local_checksum", 1), ("failed", 1)] {
+        assert_eq!(summary[0][field], count, "{}", field);
+    }
+}
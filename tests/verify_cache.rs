@@ -0,0 +1,68 @@
+mod common;
+
+use std::fs;
+use std::time::Duration;
+
+use common::*;
+
+const TRUST : &[&str] = &["--allow-unverified"];
+
+fn records(path: &std::path::Path) -> Vec<serde_json::Value> {
+    fs::read_to_string(path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+}
+
+#[test]
+fn cache_file_is_kept_in_the_cache_directory() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nenq-verify alpha\nexecute\nset-cache-dir elsewhere\nenq-verify beta\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert!(!dir.path().join("verify-cache.jsonl").exists());
+    assert_eq!(records(&dir.path().join("cache/verify-cache.jsonl"))[0]["package"], "alpha");
+    assert_eq!(records(&dir.path().join("elsewhere/verify-cache.jsonl"))[0]["package"], "beta");
+}
+
+#[test]
+fn set_verify_cache_chooses_the_file() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-verify-cache answers.jsonl\nset-cache-dir elsewhere\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert_eq!(records(&dir.path().join("answers.jsonl")).len(), 1);
+    assert!(!dir.path().join("elsewhere/verify-cache.jsonl").exists());
+}
+
+#[test]
+fn answers_are_reused_within_the_ttl() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let script = format!("load-packages Packages\nset-server {}\nset-verify-ttl 18446744073709551615\nenq-verify alpha\nexecute\nenq-verify alpha\nexecute\n", server.host());
+    let run = rpkg_with(dir.path(), TRUST, &script);
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains(&format!("matches: true (cached answer from {}", server.host())));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn zero_ttl_always_asks() {
+    let server = checksum_server();
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nset-verify-ttl 0\nenq-verify alpha\nexecute\nenq-verify alpha\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn answers_are_stamped_when_they_arrive() {
+    let sums = fixture_checksums();
+    let server = StandIn::start(move |req| {
+        let response = checksum_response(&sums, req);
+        if req.path.contains("/beta/") { response.delayed(Duration::from_millis(2100)) } else { response }
+    });
+    let dir = workdir(&["Packages"]);
+    let run = rpkg_with(dir.path(), TRUST, &format!("load-packages Packages\nset-server {}\nenq-verify alpha\nenq-verify beta\nexecute\n", server.host()));
+    assert_eq!(run.code, 0);
+    let records = records(&dir.path().join("cache/verify-cache.jsonl"));
+    let stamp = |package: &str| records.iter().find(|r| r["package"] == package).unwrap()["timestamp"].as_u64().unwrap();
+    assert!(stamp("beta") >= stamp("alpha") + 2);
+}